@@ -0,0 +1,121 @@
+// Calls the mocked gRPC server with the authorization metadata set by the AsyncAuthInterceptor.
+// cargo run --example grpc --features grpc
+use async_trait::async_trait;
+use google_cloud_auth::error::Error;
+use google_cloud_auth::grpc::{AsyncAuthInterceptor, RequestParams};
+use google_cloud_auth::token::Token;
+use google_cloud_auth::token_source::TokenSource;
+use hyper::Body;
+use prost_types::value::Kind;
+use prost_types::Value;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::http::{Request, Response};
+use tonic::server::{Grpc, UnaryService};
+use tonic::transport::{Channel, NamedService, Server};
+use tower::{BoxError, Service, ServiceBuilder};
+
+const METHOD: &str = "/example.Echo/Echo";
+
+// Replace with google_cloud_auth::create_credential to use the actual credentials.
+struct FixedTokenSource;
+
+#[async_trait]
+impl TokenSource for FixedTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        Ok(Token {
+            access_token: "example-token".to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: None,
+            id_token: None,
+        })
+    }
+}
+
+// The mocked server returns the metadata it received.
+#[derive(Clone)]
+struct EchoServer;
+
+impl NamedService for EchoServer {
+    const NAME: &'static str = "example.Echo";
+}
+
+impl Service<Request<Body>> for EchoServer {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::<Value, Value>::default());
+            Ok(grpc.unary(Echo, request).await)
+        })
+    }
+}
+
+struct Echo;
+
+fn string_value(value: String) -> Value {
+    Value {
+        kind: Some(Kind::StringValue(value)),
+    }
+}
+
+impl UnaryService<Value> for Echo {
+    type Response = Value;
+    type Future = Pin<Box<dyn Future<Output = Result<tonic::Response<Value>, tonic::Status>> + Send>>;
+
+    fn call(&mut self, request: tonic::Request<Value>) -> Self::Future {
+        let received = request.metadata();
+        let metadata = ["authorization", "x-goog-user-project", "x-goog-request-params"]
+            .iter()
+            .map(|key| format!("{}: {:?}", key, received.get(*key)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Box::pin(async move { Ok(tonic::Response::new(string_value(metadata))) })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), BoxError> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(
+        Server::builder()
+            .add_service(EchoServer)
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let channel = Channel::from_shared(format!("http://{}", addr))?.connect().await?;
+    let interceptor = AsyncAuthInterceptor::new(Arc::new(FixedTokenSource)).with_quota_project_id("my-project")?;
+    let channel = ServiceBuilder::new().layer(interceptor.layer()).service(channel);
+
+    let mut client = tonic::client::Grpc::new(channel);
+    client.ready().await?;
+    let mut request = tonic::Request::new(string_value("hello".to_string()));
+    request
+        .extensions_mut()
+        .insert(RequestParams::new(&[("bucket", "projects/_/buckets/my-bucket")]));
+    let response = client
+        .unary(
+            request,
+            PathAndQuery::from_static(METHOD),
+            ProstCodec::<Value, Value>::default(),
+        )
+        .await?;
+    if let Some(Kind::StringValue(metadata)) = response.into_inner().kind {
+        println!("{}", metadata);
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use crate::error::Error;
+use crate::middleware::BoxError;
+use hyper::http::uri::PathAndQuery;
+use hyper::http::{HeaderValue, Request, Uri};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+pub const API_KEY_ENV: &str = "GOOGLE_API_KEY";
+const API_KEY_HEADER: &str = "x-goog-api-key";
+
+// Where the API key is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiKeyPlacement {
+    // The x-goog-api-key header.
+    Header,
+    // The key query parameter.
+    QueryParameter,
+}
+
+// ApiKeyCredential sends the API key instead of the token for the APIs which accept the API keys.
+// https://cloud.google.com/docs/authentication/api-keys
+#[derive(Clone)]
+pub struct ApiKeyCredential {
+    key: String,
+    placement: ApiKeyPlacement,
+}
+
+// The key must not be written to the logs.
+impl fmt::Debug for ApiKeyCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeyCredential")
+            .field("key", &"<redacted>")
+            .field("placement", &self.placement)
+            .finish()
+    }
+}
+
+impl ApiKeyCredential {
+    pub fn new(key: &str) -> ApiKeyCredential {
+        ApiKeyCredential {
+            key: key.to_string(),
+            placement: ApiKeyPlacement::Header,
+        }
+    }
+
+    // Reads the key from the GOOGLE_API_KEY environment variable.
+    pub fn from_env() -> Result<ApiKeyCredential, Error> {
+        match std::env::var(API_KEY_ENV) {
+            Ok(key) if !key.is_empty() => Ok(ApiKeyCredential::new(&key)),
+            _ => Err(Error::ApiKeyNotFound(API_KEY_ENV.to_string())),
+        }
+    }
+
+    pub fn with_placement(mut self, placement: ApiKeyPlacement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    // Sets the key to the request.
+    pub fn authorize<B>(&self, request: &mut Request<B>) -> Result<(), Error> {
+        match self.placement {
+            ApiKeyPlacement::Header => {
+                let value = HeaderValue::from_str(&self.key).map_err(hyper::http::Error::from)?;
+                request.headers_mut().insert(API_KEY_HEADER, value);
+            }
+            ApiKeyPlacement::QueryParameter => {
+                let uri = request.uri().clone();
+                let key = format!("key={}", urlencoding::encode(&self.key));
+                let path_and_query = match uri.query() {
+                    Some(query) if !query.is_empty() => format!("{}?{}&{}", uri.path(), query, key),
+                    _ => format!("{}?{}", uri.path(), key),
+                };
+                let mut parts = uri.into_parts();
+                parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).map_err(hyper::http::Error::from)?);
+                *request.uri_mut() = Uri::from_parts(parts).map_err(hyper::http::Error::from)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn layer(&self) -> ApiKeyLayer {
+        ApiKeyLayer {
+            credential: self.clone(),
+        }
+    }
+}
+
+// ApiKeyLayer wraps the HTTP service to send the API key with every request.
+#[derive(Clone, Debug)]
+pub struct ApiKeyLayer {
+    credential: ApiKeyCredential,
+}
+
+impl<S> Layer<S> for ApiKeyLayer {
+    type Service = ApiKeyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ApiKeyService {
+            inner,
+            credential: self.credential.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiKeyService<S> {
+    inner: S,
+    credential: ApiKeyCredential,
+}
+
+impl<S, B> Service<Request<B>> for ApiKeyService<S>
+where
+    S: Service<Request<B>>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(|e| e.into())
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        if let Err(e) = self.credential.authorize(&mut request) {
+            return Box::pin(async move { Err(e.into()) });
+        }
+        let response = self.inner.call(request);
+        Box::pin(async move { response.await.map_err(|e| e.into()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api_key::{ApiKeyCredential, ApiKeyPlacement, API_KEY_ENV};
+    use crate::error::Error;
+    use hyper::http::Request;
+    use serial_test::serial;
+
+    fn request(uri: &str) -> Request<()> {
+        Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn test_authorize() {
+        let credential = ApiKeyCredential::new("secret+key");
+        let mut req = request("https://storage.googleapis.com/storage/v1/b/bucket/o");
+        credential.authorize(&mut req).unwrap();
+        assert_eq!("secret+key", req.headers()["x-goog-api-key"]);
+        assert_eq!("https://storage.googleapis.com/storage/v1/b/bucket/o", req.uri());
+
+        let credential = credential.with_placement(ApiKeyPlacement::QueryParameter);
+        let mut req = request("https://storage.googleapis.com/storage/v1/b/bucket/o");
+        credential.authorize(&mut req).unwrap();
+        assert_eq!("/storage/v1/b/bucket/o?key=secret%2Bkey", req.uri().path_and_query().unwrap());
+        assert!(req.headers().get("x-goog-api-key").is_none());
+
+        let mut req = request("https://storage.googleapis.com/storage/v1/b/bucket/o?prefix=a");
+        credential.authorize(&mut req).unwrap();
+        assert_eq!("prefix=a&key=secret%2Bkey", req.uri().query().unwrap());
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let debug = format!("{:?}", ApiKeyCredential::new("secret").layer());
+        assert!(!debug.contains("secret"), "{}", debug);
+        assert!(debug.contains("<redacted>"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_from_env() {
+        std::env::set_var(API_KEY_ENV, "from-env");
+        let mut req = request("https://example.com/");
+        ApiKeyCredential::from_env().unwrap().authorize(&mut req).unwrap();
+        assert_eq!("from-env", req.headers()["x-goog-api-key"]);
+
+        std::env::remove_var(API_KEY_ENV);
+        assert!(matches!(ApiKeyCredential::from_env(), Err(Error::ApiKeyNotFound(_))));
+    }
+}
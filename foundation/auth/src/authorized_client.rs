@@ -0,0 +1,185 @@
+use crate::error::Error;
+use crate::middleware::{Authorizer, BearerToken};
+use crate::token_source::TokenSource;
+use crate::Credential;
+use hyper::client::connect::Connect;
+use hyper::http::{Request, Response};
+use hyper::{Body, Client};
+use std::sync::Arc;
+
+// AuthorizedClient is the hyper client which sends the token of the token source with every request.
+// It is the same as the AuthLayer for the users not on tower.
+// When the server rejects the token with 401, the token is refreshed and the request is sent once more.
+#[derive(Clone)]
+pub struct AuthorizedClient<C> {
+    client: Client<C>,
+    authorizer: Authorizer,
+}
+
+impl<C> AuthorizedClient<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    pub fn new(client: Client<C>, token_source: Arc<dyn TokenSource>) -> AuthorizedClient<C> {
+        AuthorizedClient {
+            client,
+            authorizer: Authorizer::new(token_source),
+        }
+    }
+
+    // Uses the token source and the quota project of the credential.
+    pub fn from_credential(client: Client<C>, credential: &Credential) -> Result<AuthorizedClient<C>, Error> {
+        let client = AuthorizedClient::new(client, credential.token_source.clone());
+        match &credential.quota_project_id {
+            Some(quota_project_id) => client.with_quota_project_id(quota_project_id),
+            None => Ok(client),
+        }
+    }
+
+    // Sends the project as the x-goog-user-project header.
+    pub fn with_quota_project_id(mut self, quota_project_id: &str) -> Result<AuthorizedClient<C>, Error> {
+        self.authorizer = self.authorizer.with_quota_project_id(quota_project_id)?;
+        Ok(self)
+    }
+
+    // Sends the ID token instead of the access token with BearerToken::IdToken.
+    pub fn with_bearer_token(mut self, bearer_token: BearerToken) -> AuthorizedClient<C> {
+        self.authorizer.bearer_token = bearer_token;
+        self
+    }
+
+    // Returns the hyper client to send the requests without the token.
+    pub fn inner(&self) -> &Client<C> {
+        &self.client
+    }
+
+    // The request body is buffered to send it again after the refresh.
+    pub async fn request(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        self.authorizer
+            .send(parts, body, |request| async { Ok(self.client.request(request).await?) })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authorized_client::AuthorizedClient;
+    use crate::mock_server;
+    use crate::testing::CountingTokenSource;
+    use crate::token_source::{TokenSource, TokenSourceExt};
+    use hyper::http::{Method, Request};
+    use hyper::{Body, Client, Response, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // Rejects token-1 and records the authorization headers, the quota projects and the bodies.
+    async fn serve(received: Arc<Mutex<Vec<(String, String, String)>>>) -> String {
+        let host = mock_server::serve(move |parts, body| {
+            let header = |name: &str| {
+                parts
+                    .headers
+                    .get(name)
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            };
+            let authorization = header("authorization");
+            let status = match authorization.as_str() {
+                "Bearer token-1" => StatusCode::UNAUTHORIZED,
+                _ => StatusCode::OK,
+            };
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            received
+                .lock()
+                .unwrap()
+                .push((authorization, header("x-goog-user-project"), body));
+            Response::builder().status(status).body(Body::empty()).unwrap()
+        })
+        .await;
+        format!("http://{}/upload", host)
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let url = serve(received.clone()).await;
+        let ts = CountingTokenSource::new().cached();
+        let client = AuthorizedClient::new(Client::new(), Arc::new(ts))
+            .with_quota_project_id("billing")
+            .unwrap();
+
+        for _ in 0..2 {
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri(url.as_str())
+                .header("content-type", "text/plain")
+                .body(Body::from("payload"))
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+        }
+
+        let received = received.lock().unwrap();
+        let authorizations: Vec<&str> = received.iter().map(|r| r.0.as_str()).collect();
+        // the rejected token is refreshed only once and the refreshed token is reused.
+        assert_eq!(vec!["Bearer token-1", "Bearer token-2", "Bearer token-2"], authorizations);
+        assert!(received.iter().all(|r| r.1 == "billing" && r.2 == "payload"));
+    }
+
+    #[test]
+    fn test_invalid_quota_project() {
+        let client = AuthorizedClient::new(Client::new(), Arc::new(CountingTokenSource::new()));
+        assert!(client.with_quota_project_id("invalid\nproject").is_err());
+    }
+
+    // Rejects every token with the challenge.
+    async fn serve_unauthorized(challenge: Option<&'static str>, requests: Arc<AtomicUsize>) -> String {
+        let host = mock_server::serve(move |_, _| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let mut response = Response::builder().status(StatusCode::UNAUTHORIZED);
+            if let Some(challenge) = challenge {
+                response = response.header("www-authenticate", challenge);
+            }
+            response.body(Body::empty()).unwrap()
+        })
+        .await;
+        format!("http://{}/object", host)
+    }
+
+    #[tokio::test]
+    async fn test_persistent_unauthorized() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = serve_unauthorized(
+            Some(r#"Bearer realm="https://accounts.google.com/", error="invalid_token""#),
+            requests.clone(),
+        )
+        .await;
+        let ts = Arc::new(CountingTokenSource::new().cached());
+        let client = AuthorizedClient::new(Client::new(), ts.clone());
+
+        for _ in 0..3 {
+            let request = Request::get(url.as_str()).body(Body::empty()).unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+        // the token is refreshed once, and the new token rejected again is not refreshed for the next requests.
+        assert_eq!(4, requests.load(Ordering::SeqCst));
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_not_by_token() {
+        for challenge in [r#"Bearer error="invalid_request""#, r#"Basic realm="proxy""#] {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let url = serve_unauthorized(Some(challenge), requests.clone()).await;
+            let ts = Arc::new(CountingTokenSource::new().cached());
+            let client = AuthorizedClient::new(Client::new(), ts.clone());
+
+            let request = Request::get(url.as_str()).body(Body::empty()).unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, client.request(request).await.unwrap().status());
+            assert_eq!(1, requests.load(Ordering::SeqCst));
+            assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::error::Error;
+use crate::token::Token;
+use crate::token_source::TokenSource;
+use crate::Config;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+// BlockingTokenSource gets the token without the async runtime, for the synchronous programs.
+pub trait BlockingTokenSource: Send + Sync {
+    fn token(&self) -> Result<Token, Error>;
+
+    fn refresh(&self) -> Result<Token, Error>;
+}
+
+// Drives the async token source on the internal single threaded runtime.
+// The runtime is owned by the token source, so that the pooled connections are reused between the calls.
+pub struct BlockingAdapter {
+    inner: Arc<dyn TokenSource>,
+    runtime: Runtime,
+}
+
+impl BlockingAdapter {
+    pub fn new(inner: Arc<dyn TokenSource>) -> Result<BlockingAdapter, Error> {
+        Ok(BlockingAdapter {
+            inner,
+            runtime: new_runtime()?,
+        })
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> Result<F::Output, Error> {
+        ensure_not_async()?;
+        Ok(self.runtime.block_on(future))
+    }
+}
+
+impl BlockingTokenSource for BlockingAdapter {
+    fn token(&self) -> Result<Token, Error> {
+        self.block_on(self.inner.token())?
+    }
+
+    fn refresh(&self) -> Result<Token, Error> {
+        self.block_on(self.inner.refresh())?
+    }
+}
+
+// Creates the token source in the same way as crate::create_token_source.
+// Returns Error::BlockingInAsyncContext when it is called in the async runtime, which would deadlock.
+pub fn create_token_source(config: Config) -> Result<Box<dyn BlockingTokenSource>, Error> {
+    ensure_not_async()?;
+    let runtime = new_runtime()?;
+    let inner = runtime.block_on(crate::create_token_source(config))?;
+    Ok(Box::new(BlockingAdapter { inner, runtime }))
+}
+
+fn new_runtime() -> Result<Runtime, Error> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+fn ensure_not_async() -> Result<(), Error> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => Err(Error::BlockingInAsyncContext),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocking::{create_token_source, BlockingAdapter, BlockingTokenSource};
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::token_source::static_token_source::StaticTokenSource;
+    use crate::Config;
+    use std::sync::Arc;
+
+    fn service_account() -> CredentialsFile {
+        CredentialsFile::new_from_file_blocking("testdata/service_account.json").unwrap()
+    }
+
+    #[test]
+    fn test_blocking_token_source() {
+        let config = Config::default()
+            .with_credentials(service_account())
+            .with_scopes(&["https://www.googleapis.com/auth/cloud-platform"])
+            .with_use_self_signed_jwt(true);
+        let ts = create_token_source(config).unwrap();
+        let token = ts.token().unwrap();
+        assert_eq!("Bearer", token.token_type);
+        assert!(token.expiry.unwrap() > chrono::Utc::now());
+        assert!(ts.refresh().is_ok());
+    }
+
+    #[test]
+    fn test_adapter() {
+        let ts = BlockingAdapter::new(Arc::new(StaticTokenSource::new("static"))).unwrap();
+        assert_eq!("static", ts.token().unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_async_context() {
+        let config = Config::default().with_credentials(service_account());
+        assert!(matches!(create_token_source(config), Err(Error::BlockingInAsyncContext)));
+    }
+
+    #[test]
+    fn test_async_context_adapter() {
+        let ts = BlockingAdapter::new(Arc::new(StaticTokenSource::new("static"))).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let result = runtime.block_on(async { ts.token() });
+        assert!(matches!(result, Err(Error::BlockingInAsyncContext)));
+    }
+}
@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+// Clock returns the current time used for the expiry of the tokens and the JWT claims.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub(crate) fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// FakeClock returns the time set by the test instead of the system time.
+#[cfg(any(test, feature = "testing"))]
+pub struct FakeClock {
+    now: std::sync::Mutex<DateTime<Utc>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl FakeClock {
+    pub fn new(now: DateTime<Utc>) -> FakeClock {
+        FakeClock {
+            now: std::sync::Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, FakeClock, SystemClock};
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_fake_clock() {
+        let start = chrono::Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = FakeClock::new(start);
+        assert_eq!(start, clock.now());
+        clock.advance(chrono::Duration::minutes(5));
+        assert_eq!(start + chrono::Duration::minutes(5), clock.now());
+        clock.set(start);
+        assert_eq!(start, clock.now());
+
+        assert!(SystemClock.now() > start);
+    }
+}
@@ -8,9 +8,77 @@ pub enum Error {
     #[error("unsupported account {0}")]
     UnsupportedAccountType(String),
 
+    #[error("invalid credentials file: {0}")]
+    InvalidCredentialsFile(String),
+
+    #[error("this looks like an OAuth client ID file, not application default credentials — run `gcloud auth application-default login`")]
+    OAuthClientIdFile,
+
+    #[error("unknown credentials type {0}, the supported types are {}", crate::credentials::SUPPORTED_TYPES.join(", "))]
+    UnknownCredentialsType(String),
+
+    #[error("credentials of type {expected} is required, but the type is {actual}")]
+    UnexpectedCredentialsType { expected: &'static str, actual: String },
+
+    #[error("{field} is required for {credential_type} credentials{}", .path.as_ref().map(|p| format!(" in {}", p)).unwrap_or_default())]
+    MissingCredentialField {
+        field: &'static str,
+        credential_type: String,
+        // The file which the credentials are read from, if any.
+        path: Option<String>,
+    },
+
+    #[error("unsupported credential source")]
+    UnsupportedCredentialSource,
+
+    #[error("workforce_pool_user_project is required for the workforce pool credentials without the impersonation")]
+    WorkforcePoolUserProjectRequired,
+
+    #[error("workforce_pool_user_project is set for the audience {0}, which is not the workforce pool")]
+    UnexpectedWorkforcePoolUserProject(String),
+
+    #[error("unsupported subject token format {0}")]
+    UnsupportedSubjectTokenFormat(String),
+
+    #[error("subject_token_field_name is required for the json format")]
+    SubjectTokenFieldNameRequired,
+
+    #[error("subject token field {0} is not found")]
+    SubjectTokenFieldNotFound(String),
+
+    #[error("timed out fetching the subject token from {0}")]
+    SubjectTokenTimeout(String),
+
+    #[error("unsupported aws environment {0}")]
+    UnsupportedAwsEnvironment(String),
+
+    #[error("subject token url {url} responded with status {status}")]
+    SubjectTokenResponseError { url: String, status: hyper::StatusCode },
+
+    #[error("executables need to be explicitly allowed by setting GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES to 1")]
+    ExecutablesNotAllowed,
+
+    #[error("invalid executable config: {0}")]
+    InvalidExecutableConfig(String),
+
+    #[error("timed out running the executable {0}")]
+    ExecutableTimeout(String),
+
+    #[error("executable {command} exited with {code:?}")]
+    ExecutableFailed { command: String, code: Option<i32> },
+
+    #[error("executable returned the error {code}: {message}")]
+    ExecutableResponseError { code: String, message: String },
+
+    #[error("invalid executable response: {0}")]
+    InvalidExecutableResponse(String),
+
     #[error("refresh token is required for user account credentials")]
     RefreshTokenIsRequired,
 
+    #[error("refresh token was rejected: {0}. re-run `gcloud auth application-default login`")]
+    RefreshTokenRejected(String),
+
     #[error(transparent)]
     JsonError(#[from] json::Error),
 
@@ -26,15 +94,125 @@ pub enum Error {
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
+    #[error(transparent)]
+    TimeParseError(#[from] chrono::ParseError),
+
     #[error(transparent)]
     VarError(#[from] VarError),
 
+    #[error("credentials are not found, checked {}. run `gcloud auth application-default login` or set GOOGLE_APPLICATION_CREDENTIALS", .0.join(", "))]
+    CredentialsNotFound(Vec<String>),
+
     #[error("user home directory not found")]
     NoHomeDirectoryFound,
 
     #[error("Server responded with error status is {0}")]
     DeserializeError(String),
 
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+
+    #[error("token endpoint responded with {status}: {error} {}", .description.as_deref().unwrap_or_default())]
+    TokenEndpoint {
+        status: hyper::StatusCode,
+        error: String,
+        description: Option<String>,
+        uri: Option<String>,
+    },
+
+    #[error("request timed out after {0:?}")]
+    HttpTimeout(std::time::Duration),
+
+    #[error("invalid proxy url {0}")]
+    InvalidProxy(String),
+
+    #[error("proxy refused the tunnel: {0}")]
+    ProxyConnectFailed(String),
+
+    // The error of the refresh shared by the callers which waited for it.
+    #[error(transparent)]
+    Shared(std::sync::Arc<Error>),
+
+    #[error("failed after {attempts} attempts: {source}")]
+    RetryExhausted { attempts: u32, source: Box<Error> },
+
+    #[error("the token has no ID token, use the token source of the ID token such as create_id_token_source")]
+    IdTokenNotFound,
+
+    #[error("invalid id token: {0}")]
+    InvalidIdToken(String),
+
+    #[error("invalid JWT lifetime {0:?}, it must be whole seconds between 1s and 1h")]
+    InvalidJwtLifetime(std::time::Duration),
+
+    #[error("clock skew {skew:?} must be shorter than the JWT lifetime {lifetime:?}")]
+    InvalidJwtClockSkew {
+        skew: std::time::Duration,
+        lifetime: std::time::Duration,
+    },
+
+    #[error("invalid impersonation config: {0}")]
+    InvalidImpersonationConfig(String),
+
+    #[error("lifetime {lifetime:?} is not allowed, add the service account to constraints/iam.allowServiceAccountCredentialLifetimeExtension: {message}")]
+    LifetimeExtensionNotAllowed {
+        lifetime: std::time::Duration,
+        message: String,
+    },
+
+    #[error("permission to sign as {service_account} is denied, grant roles/iam.serviceAccountTokenCreator on it: {message}")]
+    SigningPermissionDenied { service_account: String, message: String },
+
+    #[error("invalid credential access boundary: {0}")]
+    InvalidAccessBoundary(String),
+
+    #[error("authorization failed: {0}")]
+    AuthorizationFailed(String),
+
+    #[error("the token has been revoked")]
+    TokenRevoked,
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("api key is not found in {0}")]
+    ApiKeyNotFound(String),
+
+    #[error("all token sources in the chain failed: {}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "))]
+    ChainExhausted(Vec<Error>),
+
+    #[error("project id is not found: {}", .0.join(", "))]
+    ProjectIdNotFound(Vec<String>),
+
     #[error("Private Key is requred")]
     NoPrivateKeyFound,
+
+    #[error("invalid private key: {0}")]
+    InvalidPrivateKey(String),
+
+    #[error("PKCS#12 keys are not supported: {0}")]
+    UnsupportedP12Key(&'static str),
+
+    #[error("metadata server is unavailable: {0}")]
+    MetadataServerUnavailable(hyper::Error),
+
+    #[error("metadata {0} is not defined")]
+    MetadataNotFound(String),
+
+    #[error("response of {0} doesn't have the Metadata-Flavor: Google header, it is not the metadata server")]
+    NotMetadataServer(String),
+
+    #[error("blocking API is called in the async context, use the async API instead")]
+    BlockingInAsyncContext,
+}
+
+impl Error {
+    // Returns the error shared by the callers of the failed refresh, or the error itself, so that the errors of
+    // the cached token sources can be matched in the same way as the others.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Shared(e) => e.root(),
+            e => e,
+        }
+    }
 }
@@ -0,0 +1,252 @@
+use crate::error::Error;
+use crate::flow::{UserToken, UserTokenResponse};
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token::TOKEN_URL;
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{error_from_response, form_body, ResponseExtension};
+use hyper::http::{Method, Request, Response};
+use hyper::Body;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEVICE_CODE_URL: &str = "https://oauth2.googleapis.com/device/code";
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// The polling interval is increased by this much when the server responds with slow_down.
+// https://datatracker.ietf.org/doc/html/rfc8628#section-3.5
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+const DEFAULT_INTERVAL_SECONDS: u64 = 5;
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL_SECONDS
+}
+
+// DeviceCode is the code pair issued for the device.
+// Show the user_code and the verification_url to the user, who enters the code on another device.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    #[serde(alias = "verification_uri")]
+    pub verification_url: String,
+    // Seconds until the device code expires.
+    pub expires_in: i64,
+    // The minimum seconds between the polling requests.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+// DeviceFlow authorizes the end user on the devices without the browser such as the headless appliances.
+// https://developers.google.com/identity/protocols/oauth2/limited-input-device
+pub struct DeviceFlow {
+    client_id: String,
+    client_secret: String,
+    device_code_url: String,
+    token_url: String,
+    scopes: Vec<String>,
+    slow_down_increment: Duration,
+    retry_policy: RetryPolicy,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl DeviceFlow {
+    pub fn new(client_id: &str, client_secret: &str) -> DeviceFlow {
+        DeviceFlow {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            device_code_url: DEVICE_CODE_URL.to_string(),
+            token_url: TOKEN_URL.to_string(),
+            scopes: vec![],
+            slow_down_increment: SLOW_DOWN_INCREMENT,
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn with_device_code_url(mut self, device_code_url: &str) -> Self {
+        self.device_code_url = device_code_url.to_string();
+        self
+    }
+
+    pub fn with_token_url(mut self, token_url: &str) -> Self {
+        self.token_url = token_url.to_string();
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Requests the device code and passes it to on_code to show it to the user,
+    // and then waits for the user to complete the authorization.
+    pub async fn run<F: FnOnce(&DeviceCode)>(&self, on_code: F) -> Result<UserToken, Error> {
+        let code = self.request_code().await?;
+        on_code(&code);
+        self.poll_token(&code).await
+    }
+
+    pub async fn request_code(&self) -> Result<DeviceCode, Error> {
+        let scope = self.scopes.join(" ");
+        let body = form_body(&[("client_id", &self.client_id), ("scope", &scope)]);
+        let response = self.post(&self.device_code_url, &body).await?;
+        response.deserialize().await
+    }
+
+    // Polls the token endpoint at the interval until the user completes the authorization or the code expires.
+    pub async fn poll_token(&self, code: &DeviceCode) -> Result<UserToken, Error> {
+        let body = form_body(&[
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("device_code", &code.device_code),
+            ("grant_type", DEVICE_CODE_GRANT_TYPE),
+        ]);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(code.expires_in.max(0) as u64);
+        let mut interval = Duration::from_secs(code.interval);
+        loop {
+            if tokio::time::Instant::now() + interval >= deadline {
+                return Err(Error::AuthorizationFailed("expired_token".to_string()));
+            }
+            tokio::time::sleep(interval).await;
+
+            let now = chrono::Utc::now();
+            let response = self.post(&self.token_url, &body).await?;
+            if response.status().is_success() {
+                let response: UserTokenResponse = response.deserialize().await?;
+                return Ok(response.into_user_token(now, &self.client_id, &self.client_secret, &self.token_url));
+            }
+            match error_from_response(response).await {
+                Error::TokenEndpoint { error, .. } if error == "authorization_pending" => {}
+                Error::TokenEndpoint { error, .. } if error == "slow_down" => interval += self.slow_down_increment,
+                // expired_token, access_denied and so on.
+                Error::TokenEndpoint { error, .. } => return Err(Error::AuthorizationFailed(error)),
+                e => return Err(e),
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, body: &str) -> Result<Response<Body>, Error> {
+        request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(body.to_string()))?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::flow::device::{DeviceCode, DeviceFlow};
+    use crate::mock_server;
+    use crate::token_source::authorized_user_token_source::UserAccountTokenSource;
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn error(status: StatusCode, error: &str) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .body(Body::from(format!(r#"{{"error":"{}"}}"#, error)))
+            .unwrap()
+    }
+
+    // Responds to the polling requests with the errors in order, and then the token.
+    async fn serve(errors: Vec<&'static str>, polls: Arc<Mutex<Vec<Instant>>>) -> String {
+        let host = mock_server::serve(move |req, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            if req.uri.path() == "/device/code" {
+                assert_eq!("client_id=id&scope=email%20profile", body);
+                return Response::new(Body::from(
+                    r#"{"device_code":"device","user_code":"GQVQ-JKEC","verification_url":"https://www.google.com/device","expires_in":1800,"interval":0}"#,
+                ));
+            }
+            assert!(body.contains("device_code=device"));
+            assert!(body.contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code"));
+            let mut polls = polls.lock().unwrap();
+            polls.push(Instant::now());
+            match errors.get(polls.len() - 1) {
+                Some(e) => error(StatusCode::PRECONDITION_REQUIRED, e),
+                None => Response::new(Body::from(
+                    r#"{"access_token":"access","expires_in":3599,"refresh_token":"refresh","token_type":"Bearer"}"#,
+                )),
+            }
+        })
+        .await;
+        format!("http://{}", host)
+    }
+
+    fn flow(host: &str) -> DeviceFlow {
+        let mut flow = DeviceFlow::new("id", "secret")
+            .with_scopes(&["email", "profile"])
+            .with_device_code_url(&format!("{}/device/code", host))
+            .with_token_url(&format!("{}/token", host));
+        flow.slow_down_increment = Duration::from_millis(200);
+        flow
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let polls = Arc::new(Mutex::new(vec![]));
+        let host = serve(vec!["authorization_pending", "authorization_pending"], polls.clone()).await;
+        let mut shown = None;
+        let token = flow(&host)
+            .run(|code| shown = Some(code.user_code.clone()))
+            .await
+            .unwrap();
+        assert_eq!(Some("GQVQ-JKEC".to_string()), shown);
+        assert_eq!("access", token.token.access_token);
+        assert_eq!(3, polls.lock().unwrap().len());
+        assert!(UserAccountTokenSource::new(&token.to_credentials_file().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_slow_down() {
+        let polls = Arc::new(Mutex::new(vec![]));
+        let host = serve(vec!["authorization_pending", "slow_down", "slow_down"], polls.clone()).await;
+        flow(&host).run(|_| {}).await.unwrap();
+
+        let polls = polls.lock().unwrap();
+        assert_eq!(4, polls.len());
+        // the interval is increased by every slow_down.
+        assert!(polls[1] - polls[0] < Duration::from_millis(200));
+        assert!(polls[2] - polls[1] >= Duration::from_millis(200));
+        assert!(polls[3] - polls[2] >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_denied() {
+        let polls = Arc::new(Mutex::new(vec![]));
+        let host = serve(vec!["authorization_pending", "access_denied"], polls.clone()).await;
+        let result = flow(&host).run(|_| {}).await;
+        assert!(matches!(result, Err(Error::AuthorizationFailed(e)) if e == "access_denied"));
+    }
+
+    #[tokio::test]
+    async fn test_expired() {
+        let code = DeviceCode {
+            device_code: "device".to_string(),
+            user_code: "GQVQ-JKEC".to_string(),
+            verification_url: "https://www.google.com/device".to_string(),
+            expires_in: 0,
+            interval: 0,
+        };
+        let result = DeviceFlow::new("id", "secret").poll_token(&code).await;
+        assert!(matches!(result, Err(Error::AuthorizationFailed(e)) if e == "expired_token"));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::error::Error;
+use crate::flow::{UserToken, UserTokenResponse};
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token::{AUTH_URL, TOKEN_URL};
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{form_body, ResponseExtension};
+use hyper::http::{Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+const COMPLETED_PAGE: &str =
+    "<html><body>The authorization flow has completed. You may close this window.</body></html>";
+const STATE_MISMATCH_PAGE: &str = "<html><body>The state doesn't match the authorization request.</body></html>";
+
+#[derive(Deserialize)]
+struct ClientSecrets {
+    installed: InstalledClient,
+}
+
+#[derive(Deserialize)]
+struct InstalledClient {
+    client_id: String,
+    client_secret: String,
+    auth_uri: Option<String>,
+    token_uri: Option<String>,
+}
+
+// Pkce is the proof key for the code exchange.
+// https://datatracker.ietf.org/doc/html/rfc7636
+struct Pkce {
+    verifier: String,
+    challenge: String,
+}
+
+impl Pkce {
+    fn new() -> Pkce {
+        Pkce::from_verifier(random_string(64))
+    }
+
+    fn from_verifier(verifier: String) -> Pkce {
+        let digest = ring::digest::digest(&ring::digest::SHA256, verifier.as_bytes());
+        Pkce {
+            challenge: base64::encode_config(digest.as_ref(), base64::URL_SAFE_NO_PAD),
+            verifier,
+        }
+    }
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = urlencoding::decode(&value.replace('+', " ")).ok()?.to_string();
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+// InstalledAppFlow authorizes the end user with the browser for the CLI tools.
+// The authorization code is received by the redirect to the local server and exchanged for the tokens with PKCE.
+// https://developers.google.com/identity/protocols/oauth2/native-app
+pub struct InstalledAppFlow {
+    client_id: String,
+    client_secret: String,
+    auth_url: String,
+    token_url: String,
+    scopes: Vec<String>,
+    login_hint: Option<String>,
+    timeout: Duration,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl InstalledAppFlow {
+    pub fn new(client_id: &str, client_secret: &str) -> InstalledAppFlow {
+        InstalledAppFlow {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_url: AUTH_URL.to_string(),
+            token_url: TOKEN_URL.to_string(),
+            scopes: vec![],
+            login_hint: None,
+            timeout: DEFAULT_TIMEOUT,
+            client: default_http_client(),
+        }
+    }
+
+    // Reads the client_secret.json of the desktop app downloaded from the Google Cloud Console.
+    pub fn from_client_secrets(client_secrets_json: &[u8]) -> Result<InstalledAppFlow, Error> {
+        let secrets: ClientSecrets = json::from_slice(client_secrets_json)?;
+        let client = secrets.installed;
+        let mut flow = InstalledAppFlow::new(&client.client_id, &client.client_secret);
+        if let Some(auth_uri) = client.auth_uri {
+            flow.auth_url = auth_uri;
+        }
+        if let Some(token_uri) = client.token_uri {
+            flow.token_url = token_uri;
+        }
+        Ok(flow)
+    }
+
+    pub fn with_scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    // Preselects the account of the email address in the consent screen.
+    pub fn with_login_hint(mut self, login_hint: &str) -> Self {
+        self.login_hint = Some(login_hint.to_string());
+        self
+    }
+
+    pub fn with_auth_url(mut self, auth_url: &str) -> Self {
+        self.auth_url = auth_url.to_string();
+        self
+    }
+
+    pub fn with_token_url(mut self, token_url: &str) -> Self {
+        self.token_url = token_url.to_string();
+        self
+    }
+
+    // The flow fails unless the user completes the authorization within the timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    fn authorization_url(&self, redirect_uri: &str, code_challenge: &str, state: &str) -> String {
+        let scope = self.scopes.join(" ");
+        let mut params = vec![
+            ("access_type", "offline"),
+            ("client_id", self.client_id.as_str()),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", scope.as_str()),
+            ("state", state),
+        ];
+        if let Some(login_hint) = &self.login_hint {
+            params.push(("login_hint", login_hint));
+        }
+        format!("{}?{}", self.auth_url, form_body(&params))
+    }
+
+    // Starts the local server for the redirect and passes the authorization url to open_url,
+    // which opens the url with the browser or prints it for the user.
+    // The returned token has the refresh token to be persisted.
+    pub async fn run<F: FnOnce(&str)>(&self, open_url: F) -> Result<UserToken, Error> {
+        let pkce = Pkce::new();
+        let state = random_string(32);
+        let (tx, mut rx) = mpsc::channel::<HashMap<String, String>>(1);
+        let expected_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            let expected_state = expected_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let params = parse_query(req.uri().query().unwrap_or_default());
+                    let mut response = Response::new(Body::from(COMPLETED_PAGE));
+                    // ignores the requests other than the redirect such as the favicon, and the redirects with
+                    // another state, which any local process can send, to keep waiting for the one of this flow.
+                    if params.contains_key("code") || params.contains_key("error") {
+                        if params.get("state") == Some(&expected_state) {
+                            let _ = tx.try_send(params);
+                        } else {
+                            response = Response::new(Body::from(STATE_MISMATCH_PAGE));
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                        }
+                    }
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())?.serve(make_service);
+        let redirect_uri = format!("http://127.0.0.1:{}", server.local_addr().port());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = shutdown_rx.await;
+        }));
+
+        open_url(&self.authorization_url(&redirect_uri, &pkce.challenge, &state));
+        let received = tokio::time::timeout(self.timeout, rx.recv()).await;
+        let _ = shutdown_tx.send(());
+
+        let params = match received {
+            Ok(Some(params)) => params,
+            _ => return Err(Error::AuthorizationFailed("timed out waiting for the redirect".to_string())),
+        };
+        if let Some(error) = params.get("error") {
+            return Err(Error::AuthorizationFailed(error.to_string()));
+        }
+        let code = params.get("code").map(|s| s.as_str()).unwrap_or_default();
+        self.exchange_code(code, &pkce.verifier, &redirect_uri).await
+    }
+
+    async fn exchange_code(&self, code: &str, code_verifier: &str, redirect_uri: &str) -> Result<UserToken, Error> {
+        let body = form_body(&[
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("code", code),
+            ("code_verifier", code_verifier),
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", redirect_uri),
+        ]);
+        let now = chrono::Utc::now();
+        // the code is single-use, so the exchange is never retried.
+        let response = request_with_retry(self.client.as_ref(), &RetryPolicy::no_retry(), || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.token_url.as_str())
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(body.clone()))?)
+        })
+        .await?;
+        let response: UserTokenResponse = response.deserialize().await?;
+        Ok(response.into_user_token(now, &self.client_id, &self.client_secret, &self.token_url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::flow::installed::{parse_query, InstalledAppFlow, Pkce};
+    use crate::mock_server;
+    use crate::token_source::authorized_user_token_source::UserAccountTokenSource;
+    use hyper::{Body, Client, Response, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_pkce() {
+        // https://datatracker.ietf.org/doc/html/rfc7636#appendix-B
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        assert_eq!("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM", pkce.challenge);
+        assert_eq!(64, Pkce::new().verifier.len());
+    }
+
+    #[test]
+    fn test_from_client_secrets() {
+        let json = r#"{"installed":{"client_id":"id.apps.googleusercontent.com","client_secret":"secret","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","redirect_uris":["http://localhost"]}}"#;
+        let flow = InstalledAppFlow::from_client_secrets(json.as_bytes()).unwrap();
+        assert_eq!("id.apps.googleusercontent.com", flow.client_id);
+        assert_eq!("secret", flow.client_secret);
+        assert!(InstalledAppFlow::from_client_secrets(br#"{"web":{}}"#).is_err());
+    }
+
+    // Follows the redirect to the local server as the browser does after the user consents.
+    fn redirect(url: &str, code: &'static str, state: Option<&'static str>) {
+        let params = parse_query(url.split_once('?').unwrap().1);
+        let state = state.unwrap_or(&params["state"]).to_string();
+        let redirect_uri = format!("{}/?code={}&state={}", params["redirect_uri"], code, state);
+        tokio::spawn(async move {
+            Client::new().get(redirect_uri.parse().unwrap()).await.unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let challenge = Arc::new(Mutex::new(String::new()));
+        let expected_challenge = challenge.clone();
+        let host = mock_server::serve(move |_, body| {
+            let params = parse_query(std::str::from_utf8(&body).unwrap());
+            assert_eq!("authorization_code", params["grant_type"]);
+            assert_eq!("auth-code", params["code"]);
+            let pkce = Pkce::from_verifier(params["code_verifier"].to_string());
+            assert_eq!(*expected_challenge.lock().unwrap(), pkce.challenge);
+            Response::new(Body::from(
+                r#"{"access_token":"access","expires_in":3599,"refresh_token":"refresh","scope":"https://www.googleapis.com/auth/drive","token_type":"Bearer"}"#,
+            ))
+        })
+        .await;
+
+        let flow = InstalledAppFlow::new("id", "secret")
+            .with_scopes(&["https://www.googleapis.com/auth/drive"])
+            .with_login_hint("user@example.com")
+            .with_token_url(&format!("http://{}/token", host));
+        let token = flow
+            .run(|url| {
+                let params = parse_query(url.split_once('?').unwrap().1);
+                assert!(url.starts_with("https://accounts.google.com/o/oauth2/auth?"));
+                assert_eq!("S256", params["code_challenge_method"]);
+                assert_eq!("offline", params["access_type"]);
+                assert_eq!("user@example.com", params["login_hint"]);
+                assert_eq!("https://www.googleapis.com/auth/drive", params["scope"]);
+                assert!(params["redirect_uri"].starts_with("http://127.0.0.1:"));
+                *challenge.lock().unwrap() = params["code_challenge"].to_string();
+                redirect(url, "auth-code", None);
+            })
+            .await
+            .unwrap();
+        assert_eq!("access", token.token.access_token);
+        assert_eq!(Some("refresh"), token.refresh_token.as_deref());
+        assert!(token.token.expiry.is_some());
+
+        // the refresh token is usable by the token source of the authorized user.
+        let cred = token.to_credentials_file().unwrap();
+        assert!(UserAccountTokenSource::new(&cred).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_state_mismatch_is_ignored() {
+        let host = mock_server::serve(|_, body| {
+            assert_eq!("auth-code", parse_query(std::str::from_utf8(&body).unwrap())["code"]);
+            Response::new(Body::from(
+                r#"{"access_token":"access","expires_in":3599,"token_type":"Bearer"}"#,
+            ))
+        })
+        .await;
+        let flow = InstalledAppFlow::new("id", "secret").with_token_url(&format!("http://{}/token", host));
+        let token = flow
+            .run(|url| {
+                let params = parse_query(url.split_once('?').unwrap().1);
+                let forged = format!("{}/?code=forged&state=forged", params["redirect_uri"]);
+                let redirect_uri = format!("{}/?code=auth-code&state={}", params["redirect_uri"], params["state"]);
+                tokio::spawn(async move {
+                    // the redirect with another state doesn't end the flow.
+                    let response = Client::new().get(forged.parse().unwrap()).await.unwrap();
+                    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+                    Client::new().get(redirect_uri.parse().unwrap()).await.unwrap();
+                });
+            })
+            .await
+            .unwrap();
+        assert_eq!("access", token.token.access_token);
+
+        let flow = InstalledAppFlow::new("id", "secret").with_timeout(std::time::Duration::from_millis(500));
+        let result = flow.run(|url| redirect(url, "auth-code", Some("forged"))).await;
+        assert!(matches!(result, Err(Error::AuthorizationFailed(e)) if e.starts_with("timed out")));
+    }
+
+    #[tokio::test]
+    async fn test_code_exchange_is_not_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            response
+        })
+        .await;
+        let flow = InstalledAppFlow::new("id", "secret").with_token_url(&format!("http://{}/token", host));
+        assert!(flow.run(|url| redirect(url, "auth-code", None)).await.is_err());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let flow = InstalledAppFlow::new("id", "secret").with_timeout(std::time::Duration::from_millis(10));
+        assert!(matches!(flow.run(|_| {}).await, Err(Error::AuthorizationFailed(_))));
+    }
+}
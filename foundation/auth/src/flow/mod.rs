@@ -0,0 +1,134 @@
+pub mod device;
+pub mod installed;
+
+use crate::credentials::{CredentialsFile, USER_CREDENTIALS_KEY};
+use crate::error::Error;
+use crate::secret::mask_option;
+use crate::token::{Token, TOKEN_URL};
+use crate::token_source::token_expiry;
+use serde::Deserialize;
+
+// UserToken is the token of the end user authorized by the interactive flows.
+// Persist it with to_credentials_json, which is read by the UserAccountTokenSource.
+#[derive(Clone)]
+pub struct UserToken {
+    pub token: Token,
+    pub refresh_token: Option<String>,
+    pub id_token: Option<String>,
+    // The space separated scopes granted by the user.
+    pub scope: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+    pub token_uri: String,
+}
+
+impl std::fmt::Debug for UserToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserToken")
+            .field("token", &self.token)
+            .field("refresh_token", &mask_option(&self.refresh_token))
+            .field("id_token", &mask_option(&self.id_token))
+            .field("scope", &self.scope)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("token_uri", &self.token_uri)
+            .finish()
+    }
+}
+
+impl UserToken {
+    // Returns the authorized_user credentials JSON, which is the same format as the file created by gcloud.
+    pub fn to_credentials_json(&self) -> Result<String, Error> {
+        let refresh_token = self.refresh_token.as_ref().ok_or(Error::RefreshTokenIsRequired)?;
+        let mut credentials = json::json!({
+            "type": USER_CREDENTIALS_KEY,
+            "client_id": self.client_id,
+            "client_secret": self.client_secret,
+            "refresh_token": refresh_token,
+        });
+        if self.token_uri != TOKEN_URL {
+            credentials["token_uri"] = json::Value::String(self.token_uri.to_string());
+        }
+        Ok(json::to_string_pretty(&credentials)?)
+    }
+
+    pub fn to_credentials_file(&self) -> Result<CredentialsFile, Error> {
+        CredentialsFile::new_from_str(&self.to_credentials_json()?)
+    }
+}
+
+// Token response of the authorization code and the device code grants.
+#[derive(Deserialize)]
+struct UserTokenResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    id_token: Option<String>,
+    scope: Option<String>,
+}
+
+impl UserTokenResponse {
+    fn into_user_token(
+        self,
+        now: chrono::DateTime<chrono::Utc>,
+        client_id: &str,
+        client_secret: &str,
+        token_uri: &str,
+    ) -> UserToken {
+        UserToken {
+            token: Token {
+                access_token: self.access_token,
+                token_type: self.token_type,
+                expiry: token_expiry(now, self.expires_in, self.id_token.as_deref()),
+                id_token: self.id_token.clone(),
+            },
+            refresh_token: self.refresh_token,
+            id_token: self.id_token,
+            scope: self.scope,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            token_uri: token_uri.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::flow::UserToken;
+    use crate::token::{Token, TOKEN_URL};
+    use crate::token_source::authorized_user_token_source::UserAccountTokenSource;
+
+    fn user_token(refresh_token: Option<&str>) -> UserToken {
+        UserToken {
+            token: Token {
+                access_token: "access".to_string(),
+                token_type: "Bearer".to_string(),
+                expiry: None,
+                id_token: None,
+            },
+            refresh_token: refresh_token.map(|s| s.to_string()),
+            id_token: None,
+            scope: None,
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            token_uri: TOKEN_URL.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_to_credentials_file() {
+        let cred = user_token(Some("refresh")).to_credentials_file().unwrap();
+        assert_eq!("authorized_user", cred.tp);
+        assert_eq!(Some("refresh"), cred.refresh_token.as_ref().map(|s| s.expose()));
+        assert_eq!(Some("id"), cred.client_id.as_deref());
+        assert_eq!(None, cred.token_uri);
+        assert!(UserAccountTokenSource::new(&cred).is_ok());
+
+        assert!(matches!(
+            user_token(None).to_credentials_json(),
+            Err(Error::RefreshTokenIsRequired)
+        ));
+    }
+}
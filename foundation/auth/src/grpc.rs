@@ -0,0 +1,141 @@
+use crate::error::Error;
+use crate::token_source::TokenSource;
+use crate::Credential;
+use hyper::http::header::AUTHORIZATION;
+use hyper::http::{HeaderValue, Request};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tonic::{Code, Status};
+use tower::filter::{AsyncFilterLayer, AsyncPredicate};
+use tower::BoxError;
+
+const USER_PROJECT_HEADER: &str = "x-goog-user-project";
+const REQUEST_PARAMS_HEADER: &str = "x-goog-request-params";
+
+// RequestParams is sent as the x-goog-request-params header, which routes the request to the resource.
+// Insert it into the extensions of the tonic::Request.
+#[derive(Clone, Debug)]
+pub struct RequestParams(String);
+
+impl RequestParams {
+    pub fn new(params: &[(&str, &str)]) -> RequestParams {
+        RequestParams(
+            params
+                .iter()
+                .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+                .collect::<Vec<_>>()
+                .join("&"),
+        )
+    }
+}
+
+// AsyncAuthInterceptor sets the token of the token source as the authorization metadata of every gRPC call.
+// tonic::Interceptor can't wait for the token source, so this is the tower AsyncPredicate used with the AsyncFilter.
+#[derive(Clone)]
+pub struct AsyncAuthInterceptor {
+    token_source: Arc<dyn TokenSource>,
+    quota_project_id: Option<HeaderValue>,
+}
+
+impl AsyncAuthInterceptor {
+    pub fn new(token_source: Arc<dyn TokenSource>) -> AsyncAuthInterceptor {
+        AsyncAuthInterceptor {
+            token_source,
+            quota_project_id: None,
+        }
+    }
+
+    // Uses the token source and the quota project of the credential.
+    pub fn from_credential(credential: &Credential) -> Result<AsyncAuthInterceptor, Error> {
+        let interceptor = AsyncAuthInterceptor::new(credential.token_source.clone());
+        match &credential.quota_project_id {
+            Some(quota_project_id) => interceptor.with_quota_project_id(quota_project_id),
+            None => Ok(interceptor),
+        }
+    }
+
+    // Sends the project as the x-goog-user-project metadata.
+    pub fn with_quota_project_id(mut self, quota_project_id: &str) -> Result<AsyncAuthInterceptor, Error> {
+        self.quota_project_id = Some(HeaderValue::from_str(quota_project_id).map_err(hyper::http::Error::from)?);
+        Ok(self)
+    }
+
+    // Returns the layer that wraps the tonic channel.
+    pub fn layer(self) -> AsyncFilterLayer<AsyncAuthInterceptor> {
+        AsyncFilterLayer::new(self)
+    }
+}
+
+impl<B: Send + 'static> AsyncPredicate<Request<B>> for AsyncAuthInterceptor {
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Request, BoxError>> + Send>>;
+    type Request = Request<B>;
+
+    fn check(&mut self, request: Request<B>) -> Self::Future {
+        let ts = self.token_source.clone();
+        let quota_project_id = self.quota_project_id.clone();
+        Box::pin(async move {
+            let token = ts
+                .token()
+                .await
+                .map_err(|e| Status::new(Code::Unauthenticated, format!("token error: {:?}", e)))?;
+            let (mut parts, body) = request.into_parts();
+            // the empty token of the AnonymousTokenSource is not sent.
+            if !token.access_token.is_empty() {
+                let token_header = HeaderValue::from_str(token.header_value().as_ref())
+                    .map_err(|e| Status::new(Code::Unauthenticated, format!("token error: {:?}", e)))?;
+                parts.headers.insert(AUTHORIZATION, token_header);
+            }
+            if let Some(quota_project_id) = quota_project_id {
+                parts.headers.insert(USER_PROJECT_HEADER, quota_project_id);
+            }
+            if let Some(RequestParams(params)) = parts.extensions.remove::<RequestParams>() {
+                let params = HeaderValue::from_str(&params)
+                    .map_err(|e| Status::new(Code::InvalidArgument, format!("request params: {:?}", e)))?;
+                parts.headers.insert(REQUEST_PARAMS_HEADER, params);
+            }
+            Ok(Request::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::grpc::{AsyncAuthInterceptor, RequestParams};
+    use crate::testing::{FailingTokenSource, StaticTokenSource};
+    use hyper::http::Request;
+    use std::sync::Arc;
+    use tower::filter::AsyncPredicate;
+
+    #[test]
+    fn test_request_params() {
+        let params = RequestParams::new(&[("bucket", "projects/_/buckets/my-bucket"), ("name", "a b")]);
+        assert_eq!("bucket=projects%2F_%2Fbuckets%2Fmy-bucket&name=a%20b", params.0);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let mut interceptor = AsyncAuthInterceptor::new(Arc::new(StaticTokenSource::new("token")))
+            .with_quota_project_id("billing")
+            .unwrap();
+        let mut request = Request::new(());
+        request.extensions_mut().insert(RequestParams::new(&[("bucket", "b")]));
+        let request = interceptor.check(request).await.unwrap();
+        assert_eq!("Bearer token", request.headers()["authorization"]);
+        assert_eq!("billing", request.headers()["x-goog-user-project"]);
+        assert_eq!("bucket=b", request.headers()["x-goog-request-params"]);
+
+        let request = interceptor.check(Request::new(())).await.unwrap();
+        assert!(request.headers().get("x-goog-request-params").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_check_token_error() {
+        let mut interceptor =
+            AsyncAuthInterceptor::new(Arc::new(FailingTokenSource::new(|| Error::ScopeOrAudienceRequired)));
+        let error = interceptor.check(Request::new(())).await.unwrap_err();
+        let status = error.downcast::<tonic::Status>().unwrap();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::impersonate_token_source::validate_delegates;
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{ResponseExtension, TokenSource};
+use hyper::http::{Method, Request, StatusCode};
+use hyper::Body;
+use serde::{de, Deserialize, Serialize};
+use std::sync::Arc;
+
+pub const IAM_CREDENTIALS_URL: &str = "https://iamcredentials.googleapis.com";
+
+#[derive(Serialize)]
+struct SignRequest<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    delegates: &'a [String],
+    payload: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignBlobResponse {
+    key_id: String,
+    signed_blob: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignJwtResponse {
+    key_id: String,
+    signed_jwt: String,
+}
+
+// The signature and the id of the system-managed key of the service account which made it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedBlob {
+    pub key_id: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedJwt {
+    pub key_id: String,
+    pub jwt: String,
+}
+
+// IamCredentialsClient signs the data with the system-managed key of the service account on the server side,
+// for example to create the signed URLs without the private key.
+// The token of the token source needs the Service Account Token Creator role on the service account.
+// https://cloud.google.com/iam/docs/reference/credentials/rest
+#[derive(Clone)]
+pub struct IamCredentialsClient {
+    token_source: Arc<dyn TokenSource>,
+    endpoint: String,
+    delegates: Vec<String>,
+    retry_policy: RetryPolicy,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl IamCredentialsClient {
+    pub fn new(token_source: Arc<dyn TokenSource>) -> IamCredentialsClient {
+        IamCredentialsClient {
+            token_source,
+            endpoint: IAM_CREDENTIALS_URL.to_string(),
+            delegates: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    // The chain of the service accounts in the form of projects/-/serviceAccounts/{email},
+    // same as ImpersonateTokenSource::with_delegates.
+    pub fn with_delegates(mut self, delegates: Vec<String>) -> Result<Self, Error> {
+        self.delegates = validate_delegates(delegates)?;
+        Ok(self)
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Signs the payload with the RSA SHA-256 key of the service account.
+    pub async fn sign_blob(&self, service_account: &str, payload: &[u8]) -> Result<SignedBlob, Error> {
+        let response: SignBlobResponse = self.sign(service_account, "signBlob", base64::encode(payload)).await?;
+        let signature = base64::decode(&response.signed_blob)
+            .map_err(|e| Error::InvalidResponse(format!("signedBlob is not base64: {}", e)))?;
+        Ok(SignedBlob {
+            key_id: response.key_id,
+            signature,
+        })
+    }
+
+    // Signs the JWT with the claims. The header with the key id is added by the server.
+    pub async fn sign_jwt(&self, service_account: &str, claims: &json::Value) -> Result<SignedJwt, Error> {
+        let response: SignJwtResponse = self.sign(service_account, "signJwt", json::to_string(claims)?).await?;
+        Ok(SignedJwt {
+            key_id: response.key_id,
+            jwt: response.signed_jwt,
+        })
+    }
+
+    async fn sign<T>(&self, service_account: &str, method: &str, payload: String) -> Result<T, Error>
+    where
+        T: de::DeserializeOwned,
+    {
+        let token = self.token_source.token().await?;
+        let url = format!("{}/v1/projects/-/serviceAccounts/{}:{}", self.endpoint, service_account, method);
+        let body = json::to_string(&SignRequest {
+            delegates: &self.delegates,
+            payload,
+        })?;
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(url.as_str())
+                .header("Content-Type", "application/json")
+                .header("Authorization", token.header_value())
+                .body(Body::from(body.clone()))?)
+        })
+        .await?;
+        response.deserialize().await.map_err(|e| match e {
+            Error::TokenEndpoint {
+                status: StatusCode::FORBIDDEN,
+                error,
+                description,
+                ..
+            } => Error::SigningPermissionDenied {
+                service_account: service_account.to_string(),
+                message: description.unwrap_or(error),
+            },
+            e => e,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::iam_credentials::{IamCredentialsClient, SignedBlob};
+    use crate::mock_server;
+    use crate::StaticTokenSource;
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::Arc;
+
+    fn client(host: &str) -> IamCredentialsClient {
+        IamCredentialsClient::new(Arc::new(StaticTokenSource::new("source"))).with_endpoint(&format!("http://{}", host))
+    }
+
+    #[tokio::test]
+    async fn test_sign_blob() {
+        let host = mock_server::serve(|req, body| {
+            assert_eq!(
+                "/v1/projects/-/serviceAccounts/signer@example.iam.gserviceaccount.com:signBlob",
+                req.uri.path()
+            );
+            assert_eq!("Bearer source", req.headers["Authorization"]);
+            assert_eq!(
+                r#"{"delegates":["projects/-/serviceAccounts/delegate@example.iam.gserviceaccount.com"],"payload":"aGVsbG8="}"#,
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(r#"{"keyId":"key-1","signedBlob":"c2lnbmF0dXJl"}"#))
+        })
+        .await;
+
+        let client = client(&host)
+            .with_delegates(vec![
+                "projects/-/serviceAccounts/delegate@example.iam.gserviceaccount.com".to_string()
+            ])
+            .unwrap();
+        let signed = client
+            .sign_blob("signer@example.iam.gserviceaccount.com", b"hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            SignedBlob {
+                key_id: "key-1".to_string(),
+                signature: b"signature".to_vec(),
+            },
+            signed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sign_jwt() {
+        let host = mock_server::serve(|req, body| {
+            assert_eq!(
+                "/v1/projects/-/serviceAccounts/signer@example.iam.gserviceaccount.com:signJwt",
+                req.uri.path()
+            );
+            assert_eq!(
+                r#"{"payload":"{\"aud\":\"https://example.com\",\"sub\":\"user\"}"}"#,
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(r#"{"keyId":"key-1","signedJwt":"header.payload.signature"}"#))
+        })
+        .await;
+
+        let signed = client(&host)
+            .sign_jwt(
+                "signer@example.iam.gserviceaccount.com",
+                &json::json!({"aud": "https://example.com", "sub": "user"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!("key-1", signed.key_id);
+        assert_eq!("header.payload.signature", signed.jwt);
+    }
+
+    #[tokio::test]
+    async fn test_permission_denied() {
+        let host = mock_server::serve(|_, _| {
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from(
+                    r#"{"error":{"code":403,"message":"Permission 'iam.serviceAccounts.signBlob' denied on resource (or it may not exist).","status":"PERMISSION_DENIED"}}"#,
+                ))
+                .unwrap()
+        })
+        .await;
+
+        match client(&host)
+            .sign_blob("signer@example.iam.gserviceaccount.com", b"hello")
+            .await
+        {
+            Err(Error::SigningPermissionDenied {
+                service_account,
+                message,
+            }) => {
+                assert_eq!("signer@example.iam.gserviceaccount.com", service_account);
+                assert!(message.contains("iam.serviceAccounts.signBlob"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::ResponseExtension;
+use hyper::http::{Method, Request};
+use hyper::Body;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{OnceCell, RwLock};
+
+pub const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const GOOGLE_ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+const DEFAULT_CLOCK_SKEW_SECONDS: i64 = 300;
+// The keys are fetched for the unknown kid at most once in the interval, so that the tokens with the random kid
+// don't make the validator flood the certs endpoint.
+const DEFAULT_MIN_REFRESH_INTERVAL_SECONDS: i64 = 30;
+// The keys are cached for this long when the response has no max-age.
+const DEFAULT_KEYS_MAX_AGE_SECONDS: i64 = 300;
+
+static VALIDATOR: OnceCell<Validator> = OnceCell::const_new();
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub sub: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub azp: Option<String>,
+    pub hd: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+}
+
+// The key of the certs endpoint, which may also have the keys of the other types such as EC without n and e.
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    alg: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+impl Jwk {
+    // Returns the kid and the RS256 public key, or None for the other keys, which are skipped.
+    fn into_rsa_key(self) -> Option<(String, RsaKey)> {
+        match (self.kty.as_str(), self.alg.as_deref(), self.n, self.e) {
+            ("RSA", None | Some("RS256"), Some(n), Some(e)) => Some((self.kid, RsaKey { n, e })),
+            _ => None,
+        }
+    }
+}
+
+struct RsaKey {
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+struct CachedKeys {
+    keys: HashMap<String, RsaKey>,
+    expiry: chrono::DateTime<chrono::Utc>,
+    fetched_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Validator verifies the ID tokens signed by Google.
+// The public keys are cached as long as the Cache-Control header of the certs endpoint allows.
+pub struct Validator {
+    certs_url: String,
+    clock_skew: chrono::Duration,
+    min_refresh_interval: chrono::Duration,
+    cache: RwLock<Option<CachedKeys>>,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new(GOOGLE_CERTS_URL)
+    }
+}
+
+impl Validator {
+    pub fn new(certs_url: &str) -> Validator {
+        Validator {
+            certs_url: certs_url.to_string(),
+            clock_skew: chrono::Duration::seconds(DEFAULT_CLOCK_SKEW_SECONDS),
+            min_refresh_interval: chrono::Duration::seconds(DEFAULT_MIN_REFRESH_INTERVAL_SECONDS),
+            cache: RwLock::new(None),
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Validator {
+        self.client = client;
+        self
+    }
+
+    pub fn with_clock_skew(mut self, clock_skew: chrono::Duration) -> Validator {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    // The minimum interval between the fetches of the keys for the unknown kid.
+    pub fn with_min_refresh_interval(mut self, interval: chrono::Duration) -> Validator {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    pub async fn validate(&self, token: &str, audience: &str) -> Result<IdTokenClaims, Error> {
+        let header = jwt::decode_header(token)?;
+        if header.alg != jwt::Algorithm::RS256 {
+            return Err(Error::InvalidIdToken(format!("unsupported algorithm {:?}", header.alg)));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| Error::InvalidIdToken("kid is not found".to_string()))?;
+
+        let claims = match self.verify_with_cached_keys(token, audience, &kid, false).await? {
+            Some(claims) => claims,
+            // the keys might have been rotated.
+            None => self
+                .verify_with_cached_keys(token, audience, &kid, true)
+                .await?
+                .ok_or(Error::InvalidIdToken(format!("no public key for kid {}", kid)))?,
+        };
+
+        if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+            return Err(Error::InvalidIdToken(format!("invalid issuer {}", claims.iss)));
+        }
+        if claims.iat > (chrono::Utc::now() + self.clock_skew).timestamp() {
+            return Err(Error::InvalidIdToken("token is issued in the future".to_string()));
+        }
+        Ok(claims)
+    }
+
+    async fn verify_with_cached_keys(
+        &self,
+        token: &str,
+        audience: &str,
+        kid: &str,
+        force_refresh: bool,
+    ) -> Result<Option<IdTokenClaims>, Error> {
+        {
+            let cache = self.cache.read().await;
+            if let Some(cached) = cache.as_ref().filter(|cached| self.usable(cached, force_refresh)) {
+                return self.verify_with(cached, token, audience, kid);
+            }
+        }
+        let mut cache = self.cache.write().await;
+        // Another task might have fetched the keys while waiting for the lock.
+        if let Some(cached) = cache.as_ref().filter(|cached| self.usable(cached, force_refresh)) {
+            return self.verify_with(cached, token, audience, kid);
+        }
+        // The keys are cached before verifying, so that the invalid token doesn't make the next one fetch them again.
+        let cached = cache.insert(self.fetch_keys().await?);
+        self.verify_with(cached, token, audience, kid)
+    }
+
+    // The forced refresh uses the keys fetched recently instead, unless they have expired.
+    fn usable(&self, cached: &CachedKeys, force_refresh: bool) -> bool {
+        let now = chrono::Utc::now();
+        cached.expiry > now && (!force_refresh || cached.fetched_at + self.min_refresh_interval > now)
+    }
+
+    fn verify_with(
+        &self,
+        cached: &CachedKeys,
+        token: &str,
+        audience: &str,
+        kid: &str,
+    ) -> Result<Option<IdTokenClaims>, Error> {
+        match cached.keys.get(kid) {
+            Some(key) => Ok(Some(self.verify(token, audience, key)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn verify(&self, token: &str, audience: &str, key: &RsaKey) -> Result<IdTokenClaims, Error> {
+        let mut validation = jwt::Validation::new(jwt::Algorithm::RS256);
+        validation.leeway = self.clock_skew.num_seconds() as u64;
+        validation.set_audience(&[audience]);
+        let key = jwt::DecodingKey::from_rsa_components(&key.n, &key.e);
+        Ok(jwt::decode::<IdTokenClaims>(token, &key, &validation)?.claims)
+    }
+
+    async fn fetch_keys(&self) -> Result<CachedKeys, Error> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(self.certs_url.as_str())
+            .body(Body::empty())?;
+        let response = self.client.request(request).await?;
+        let max_age = response
+            .headers()
+            .get(hyper::header::CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .and_then(max_age)
+            .map(chrono::Duration::seconds)
+            .unwrap_or_else(|| chrono::Duration::seconds(DEFAULT_KEYS_MAX_AGE_SECONDS))
+            // the keys are kept at least for the interval, in which they are not fetched again.
+            .max(self.min_refresh_interval);
+        let jwks: Jwks = response.deserialize().await?;
+        Ok(CachedKeys {
+            keys: jwks.keys.into_iter().filter_map(Jwk::into_rsa_key).collect(),
+            expiry: chrono::Utc::now() + max_age,
+            fetched_at: chrono::Utc::now(),
+        })
+    }
+}
+
+fn max_age(cache_control: &str) -> Option<i64> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|v| v.parse().ok())
+}
+
+// Verifies the ID token signed by Google and returns its claims.
+pub async fn verify_id_token(token: &str, audience: &str) -> Result<IdTokenClaims, Error> {
+    VALIDATOR
+        .get_or_init(|| async { Validator::default() })
+        .await
+        .validate(token, audience)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::idtoken::{max_age, Validator};
+    use crate::mock_server;
+    use hyper::{Body, Response};
+    use serde::Serialize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const KEY_ID: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[derive(Serialize)]
+    struct Claims<'a> {
+        iss: &'a str,
+        aud: &'a str,
+        sub: &'a str,
+        exp: i64,
+        iat: i64,
+        email: &'a str,
+        email_verified: bool,
+    }
+
+    fn sign(kid: &str, iss: &str, aud: &str, exp: i64) -> String {
+        let cred: json::Value = json::from_str(include_str!("../testdata/service_account.json")).unwrap();
+        let key = jwt::EncodingKey::from_rsa_pem(cred["private_key"].as_str().unwrap().as_bytes()).unwrap();
+        let mut header = jwt::Header::new(jwt::Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        let claims = Claims {
+            iss,
+            aud,
+            sub: "1234567890",
+            exp,
+            iat: chrono::Utc::now().timestamp(),
+            email: "user@example.com",
+            email_verified: true,
+        };
+        jwt::encode(&header, &claims, &key).unwrap()
+    }
+
+    async fn certs_server(calls: Arc<AtomicUsize>, cache_control: Option<&'static str>) -> String {
+        let host = mock_server::serve(move |_, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let mut builder = Response::builder();
+            if let Some(cache_control) = cache_control {
+                builder = builder.header("Cache-Control", cache_control);
+            }
+            builder.body(Body::from(include_str!("../testdata/jwks.json"))).unwrap()
+        })
+        .await;
+        format!("http://{}/oauth2/v3/certs", host)
+    }
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(Some(19137), max_age("public, max-age=19137, must-revalidate, no-transform"));
+        assert_eq!(None, max_age("no-cache"));
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let validator =
+            Validator::new(&certs_server(calls.clone(), Some("public, max-age=3600, must-revalidate")).await);
+        let exp = chrono::Utc::now().timestamp() + 3600;
+
+        let token = sign(KEY_ID, "https://accounts.google.com", "https://example.run.app", exp);
+        let claims = validator.validate(&token, "https://example.run.app").await.unwrap();
+        assert_eq!(Some("user@example.com"), claims.email.as_deref());
+        assert_eq!(Some(true), claims.email_verified);
+
+        // the keys are cached.
+        validator.validate(&token, "https://example.run.app").await.unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        assert!(matches!(
+            validator.validate(&token, "https://other.run.app").await,
+            Err(Error::JwtError(_))
+        ));
+        let token = sign(KEY_ID, "https://evil.example.com", "https://example.run.app", exp);
+        assert!(matches!(
+            validator.validate(&token, "https://example.run.app").await,
+            Err(Error::InvalidIdToken(_))
+        ));
+        let token = sign(KEY_ID, "accounts.google.com", "https://example.run.app", exp - 7200);
+        assert!(matches!(
+            validator.validate(&token, "https://example.run.app").await,
+            Err(Error::JwtError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_non_rsa_key_is_skipped() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let validator = Validator::new(&certs_server(calls.clone(), None).await);
+        let exp = chrono::Utc::now().timestamp() + 3600;
+
+        // the EC key in the keys doesn't prevent the RSA key from being used.
+        let token = sign(KEY_ID, "accounts.google.com", "aud", exp);
+        validator.validate(&token, "aud").await.unwrap();
+        let token = sign("fedcba9876543210fedcba9876543210fedcba98", "accounts.google.com", "aud", exp);
+        assert!(matches!(validator.validate(&token, "aud").await, Err(Error::InvalidIdToken(_))));
+    }
+
+    #[tokio::test]
+    async fn test_without_max_age() {
+        let exp = chrono::Utc::now().timestamp() + 3600;
+        let token = sign(KEY_ID, "accounts.google.com", "aud", exp);
+        for cache_control in [None, Some("no-cache"), Some("max-age=0")] {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let validator = Validator::new(&certs_server(calls.clone(), cache_control).await);
+            validator.validate(&token, "aud").await.unwrap();
+            validator.validate(&token, "aud").await.unwrap();
+            assert_eq!(1, calls.load(Ordering::SeqCst), "{:?}", cache_control);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_caches_keys() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let validator =
+            Validator::new(&certs_server(calls.clone(), Some("public, max-age=3600, must-revalidate")).await);
+
+        let token = sign(KEY_ID, "accounts.google.com", "aud", chrono::Utc::now().timestamp() - 3600);
+        for _ in 0..2 {
+            assert!(matches!(validator.validate(&token, "aud").await, Err(Error::JwtError(_))));
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_refetches_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let validator =
+            Validator::new(&certs_server(calls.clone(), Some("public, max-age=3600, must-revalidate")).await)
+                .with_min_refresh_interval(chrono::Duration::zero());
+        let exp = chrono::Utc::now().timestamp() + 3600;
+
+        let token = sign(KEY_ID, "accounts.google.com", "aud", exp);
+        validator.validate(&token, "aud").await.unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        let token = sign("rotated", "accounts.google.com", "aud", exp);
+        assert!(matches!(validator.validate(&token, "aud").await, Err(Error::InvalidIdToken(_))));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_refetch_is_rate_limited() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let validator = Arc::new(Validator::new(
+            &certs_server(calls.clone(), Some("public, max-age=3600, must-revalidate")).await,
+        ));
+        let exp = chrono::Utc::now().timestamp() + 3600;
+
+        // The cold cache is fetched once, though the forced refreshes race for the lock.
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let validator = validator.clone();
+                let token = sign(&format!("random-{}", i), "accounts.google.com", "aud", exp);
+                tokio::spawn(async move { validator.validate(&token, "aud").await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(matches!(task.await.unwrap(), Err(Error::InvalidIdToken(_))));
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        let token = sign(KEY_ID, "accounts.google.com", "aud", exp);
+        validator.validate(&token, "aud").await.unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
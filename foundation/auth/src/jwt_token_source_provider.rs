@@ -0,0 +1,110 @@
+use crate::credentials::{CredentialsFile, SERVICE_ACCOUNT_KEY};
+use crate::error::Error;
+use crate::lru::Lru;
+use crate::token::Token;
+use crate::token_source::reuse_token_source::ReuseTokenSource;
+use crate::token_source::service_account_token_source::ServiceAccountTokenSource;
+use crate::token_source::TokenSource;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CAPACITY: usize = 16;
+
+// JwtTokenSourceProvider hands out the cached self-signed JWT source per audience of the service account,
+// such as https://storage.googleapis.com/ and https://pubsub.googleapis.com/, for the process calling many services.
+// The sources share the private key parsed once, and each caches its JWT until it is about to expire.
+// The least recently used source is evicted when the number of the audiences exceeds the capacity.
+pub struct JwtTokenSourceProvider {
+    credentials: CredentialsFile,
+    sources: Mutex<Lru<Arc<dyn TokenSource>>>,
+}
+
+impl JwtTokenSourceProvider {
+    // The credentials must be the service account with the private key.
+    pub fn new(credentials: &CredentialsFile) -> Result<JwtTokenSourceProvider, Error> {
+        credentials.ensure_type(SERVICE_ACCOUNT_KEY)?;
+        credentials.try_to_private_key()?;
+        Ok(JwtTokenSourceProvider {
+            credentials: credentials.clone(),
+            sources: Mutex::new(Lru::new(DEFAULT_CAPACITY)),
+        })
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.sources = Mutex::new(Lru::new(capacity));
+        self
+    }
+
+    // Returns the token source of the JWT for the audience.
+    pub fn for_audience(&self, audience: &str) -> Result<Arc<dyn TokenSource>, Error> {
+        let mut sources = self.sources.lock().unwrap();
+        if let Some(source) = sources.get(audience) {
+            return Ok(source);
+        }
+        let source = ServiceAccountTokenSource::new(&self.credentials, audience)?;
+        let source: Arc<dyn TokenSource> = Arc::new(ReuseTokenSource::new(Box::new(source), None));
+        sources.insert(audience, source.clone());
+        Ok(source)
+    }
+
+    pub async fn token(&self, audience: &str) -> Result<Token, Error> {
+        self.for_audience(audience)?.token().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::jwt_token_source_provider::JwtTokenSourceProvider;
+    use crate::mock_server::claims;
+    use std::sync::Arc;
+
+    const STORAGE: &str = "https://storage.googleapis.com/";
+    const PUBSUB: &str = "https://pubsub.googleapis.com/";
+    const SPANNER: &str = "https://spanner.googleapis.com/";
+
+    fn provider() -> JwtTokenSourceProvider {
+        let credentials = CredentialsFile::new_from_str(include_str!("../testdata/service_account.json")).unwrap();
+        JwtTokenSourceProvider::new(&credentials).unwrap().with_capacity(2)
+    }
+
+    #[tokio::test]
+    async fn test_token_per_audience() {
+        let provider = provider();
+        let storage = provider.token(STORAGE).await.unwrap();
+        assert_eq!(STORAGE, claims(&storage.access_token)["aud"]);
+        let pubsub = provider.token(PUBSUB).await.unwrap();
+        assert_eq!(PUBSUB, claims(&pubsub.access_token)["aud"]);
+
+        // the JWT is cached by the source.
+        assert_eq!(storage.access_token, provider.token(STORAGE).await.unwrap().access_token);
+        assert!(Arc::ptr_eq(
+            &provider.for_audience(STORAGE).unwrap(),
+            &provider.for_audience(STORAGE).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let provider = provider();
+        let storage = provider.for_audience(STORAGE).unwrap();
+        let pubsub = provider.for_audience(PUBSUB).unwrap();
+
+        // storage is used recently, so pubsub is evicted.
+        assert!(Arc::ptr_eq(&storage, &provider.for_audience(STORAGE).unwrap()));
+        provider.for_audience(SPANNER).unwrap();
+        assert_eq!(2, provider.sources.lock().unwrap().len());
+
+        assert!(Arc::ptr_eq(&storage, &provider.for_audience(STORAGE).unwrap()));
+        assert!(!Arc::ptr_eq(&pubsub, &provider.for_audience(PUBSUB).unwrap()));
+    }
+
+    #[test]
+    fn test_service_account_required() {
+        let credentials = CredentialsFile::new_from_str(include_str!("../testdata/authorized_user.json")).unwrap();
+        assert!(matches!(
+            JwtTokenSourceProvider::new(&credentials),
+            Err(Error::UnexpectedCredentialsType { .. })
+        ));
+    }
+}
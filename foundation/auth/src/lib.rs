@@ -1,90 +1,1054 @@
+#[cfg(all(
+    feature = "default-tls",
+    any(feature = "rustls-tls-webpki-roots", feature = "rustls-tls-native-roots")
+))]
+compile_error!(
+    "\"default-tls\" and \"rustls-tls\" are mutually exclusive. Set default-features = false to use rustls."
+);
+
+#[cfg(not(any(
+    feature = "default-tls",
+    feature = "rustls-tls-webpki-roots",
+    feature = "rustls-tls-native-roots"
+)))]
+compile_error!("either \"default-tls\" or \"rustls-tls\" feature must be enabled.");
+
+pub mod api_key;
+pub mod authorized_client;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod clock;
 pub mod credentials;
 pub mod error;
+pub mod flow;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http_client;
+pub mod iam_credentials;
+pub mod idtoken;
+pub mod jwt_token_source_provider;
+mod lru;
+pub mod metadata;
+pub mod metrics;
+pub mod middleware;
 mod misc;
+#[cfg(test)]
+mod mock_server;
+pub mod project;
+pub mod revoke;
+pub mod scoped_token_provider;
+pub mod secret;
+pub mod sts;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod token;
+pub mod token_info;
 pub mod token_source;
 
-use crate::credentials::CredentialsFile;
+use crate::credentials::{
+    CredentialsFile, EXTERNAL_ACCOUNT_AUTHORIZED_USER_KEY, EXTERNAL_ACCOUNT_KEY, IMPERSONATED_SERVICE_ACCOUNT_KEY,
+    SERVICE_ACCOUNT_KEY, USER_CREDENTIALS_KEY,
+};
+use crate::http_client::{DefaultHttpClient, HttpClient};
+use crate::metadata::on_gce;
+use crate::metrics::TokenMetricsSink;
 use crate::misc::EMPTY;
 use crate::token_source::authorized_user_token_source::UserAccountTokenSource;
-use crate::token_source::compute_token_source::ComputeTokenSource;
-use crate::token_source::reuse_token_source::ReuseTokenSource;
-use crate::token_source::service_account_token_source::OAuth2ServiceAccountTokenSource;
-use crate::token_source::service_account_token_source::ServiceAccountTokenSource;
+use crate::token_source::compute_token_source::{ComputeIdTokenSource, ComputeTokenSource};
+use crate::token_source::external_account_authorized_user_token_source::ExternalAccountAuthorizedUserTokenSource;
+use crate::token_source::external_account_token_source::ExternalAccountTokenSource;
+use crate::token_source::impersonate_token_source::{ImpersonateIdTokenSource, ImpersonateTokenSource};
+use crate::token_source::retry::RetryPolicy;
+use crate::token_source::reuse_token_source::{
+    RefreshObserver, ReuseTokenSource, DEFAULT_REFRESH_JITTER_SECONDS, DEFAULT_REFRESH_MARGIN_SECONDS,
+};
+use crate::token_source::service_account_token_source::{
+    jwt_refresh_margin, OAuth2ServiceAccountTokenSource, MAX_JWT_LIFETIME,
+};
+use crate::token_source::service_account_token_source::{ServiceAccountIdTokenSource, ServiceAccountTokenSource};
+use crate::token_source::subject_token_provider::SubjectTokenProvider;
 use crate::token_source::TokenSource;
-use google_cloud_metadata::on_gce;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use crate::api_key::ApiKeyCredential;
+pub use crate::authorized_client::AuthorizedClient;
+pub use crate::idtoken::verify_id_token;
+pub use crate::jwt_token_source_provider::JwtTokenSourceProvider;
+pub use crate::project::project_id;
+pub use crate::revoke::{revoke_token, revoke_token_with_client};
+pub use crate::scoped_token_provider::ScopedTokenProvider;
+pub use crate::token_info::{token_info, token_info_with_client};
+pub use crate::token_source::chain_token_source::ChainTokenSource;
+pub use crate::token_source::static_token_source::{AnonymousTokenSource, StaticTokenSource};
 
-const SERVICE_ACCOUNT_KEY: &str = "service_account";
-const USER_CREDENTIALS_KEY: &str = "authorized_user";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const QUOTA_PROJECT_ENV: &str = "GOOGLE_CLOUD_QUOTA_PROJECT";
 
-pub struct Config<'a> {
-    pub audience: Option<&'a str>,
-    pub scopes: Option<&'a [&'a str]>,
-    pub delegation_email: Option<&'a str>
+// Config specifies how create_token_source creates the token source.
+//
+// For the service account, the audience takes precedence over the scopes and the self-signed JWT is used,
+// unless sub requires the domain-wide delegation. In that case the scopes are used for the OAuth 2.0 flow.
+#[derive(Default, Clone)]
+pub struct Config {
+    pub scopes: Option<Vec<String>>,
+    pub audience: Option<String>,
+    // The email address of the user for the domain-wide delegation.
+    pub sub: Option<String>,
+    // Returns the ID token for the audience instead of the access token.
+    pub use_id_token: bool,
+    // Uses the self-signed JWT with the scope claim for the service account instead of the OAuth 2.0 flow.
+    pub use_self_signed_jwt: bool,
+    // Uses these credentials instead of finding them.
+    pub credentials: Option<CredentialsFile>,
+    // Overrides the token endpoint of the credentials.
+    pub token_url_override: Option<String>,
+    // Retries the requests to the token endpoints.
+    pub retry_policy: RetryPolicy,
+    // Sends the requests of the token sources instead of the default client.
+    pub http_client: Option<Arc<dyn HttpClient>>,
+    // Supplies the subject token of the external_account credentials instead of their credential_source.
+    pub subject_token_provider: Option<Arc<dyn SubjectTokenProvider>>,
+    // Notified of the refreshes of the cached token.
+    pub refresh_observer: Option<Arc<dyn RefreshObserver>>,
+    // Receives the metrics of the cached token.
+    pub metrics_sink: Option<Arc<dyn TokenMetricsSink>>,
+    // The lifetime of the JWT signed with the service account key, up to 1 hour which is the default.
+    pub jwt_lifetime: Option<Duration>,
+    // Subtracted from the iat of the JWT signed with the service account key, for the verifiers whose clock is behind.
+    pub jwt_clock_skew: Option<Duration>,
+    // Finds the legacy credentials of the gcloud account instead of the account of the active gcloud configuration.
+    pub gcloud_account: Option<String>,
 }
 
-impl Config<'_> {
-    pub fn scopes_to_string(&self, sep: &str) -> String {
-        match self.scopes {
-            Some(s) => s.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(sep),
+impl Config {
+    pub fn with_scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = Some(scopes.iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    pub fn with_sub(mut self, sub: &str) -> Self {
+        self.sub = Some(sub.to_string());
+        self
+    }
+
+    pub fn with_use_id_token(mut self, use_id_token: bool) -> Self {
+        self.use_id_token = use_id_token;
+        self
+    }
+
+    pub fn with_use_self_signed_jwt(mut self, use_self_signed_jwt: bool) -> Self {
+        self.use_self_signed_jwt = use_self_signed_jwt;
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: CredentialsFile) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn with_token_url_override(mut self, token_url: &str) -> Self {
+        self.token_url_override = Some(token_url.to_string());
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, http_client: Arc<dyn HttpClient>) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    pub fn with_subject_token_provider(mut self, provider: Arc<dyn SubjectTokenProvider>) -> Self {
+        self.subject_token_provider = Some(provider);
+        self
+    }
+
+    pub fn with_refresh_observer(mut self, observer: Arc<dyn RefreshObserver>) -> Self {
+        self.refresh_observer = Some(observer);
+        self
+    }
+
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn TokenMetricsSink>) -> Self {
+        self.metrics_sink = Some(sink);
+        self
+    }
+
+    pub fn with_gcloud_account(mut self, account: &str) -> Self {
+        self.gcloud_account = Some(account.to_string());
+        self
+    }
+
+    pub fn with_jwt_lifetime(mut self, lifetime: Duration) -> Self {
+        self.jwt_lifetime = Some(lifetime);
+        self
+    }
+
+    pub fn with_jwt_clock_skew(mut self, skew: Duration) -> Self {
+        self.jwt_clock_skew = Some(skew);
+        self
+    }
+
+    // The malformed HTTPS_PROXY is the error here, instead of the failures of the requests later.
+    fn http_client(&self) -> Result<Arc<dyn HttpClient>, error::Error> {
+        match &self.http_client {
+            Some(client) => Ok(client.clone()),
+            None => Ok(Arc::new(DefaultHttpClient::from_env()?)),
+        }
+    }
+
+    fn scopes_to_string(&self, sep: &str) -> String {
+        match &self.scopes {
+            Some(s) => s.join(sep),
             None => EMPTY.to_string(),
         }
     }
 }
 
-pub async fn create_token_source(config: Config<'_>) -> Result<Box<dyn TokenSource>, error::Error> {
-    let credentials = credentials::CredentialsFile::new().await;
+// Credential is the token source and the projects resolved from the credentials.
+#[derive(Clone)]
+pub struct Credential {
+    pub token_source: Arc<dyn TokenSource>,
+    pub project_id: Option<String>,
+    // The project billed for the quota, which should be sent as the x-goog-user-project header.
+    // GOOGLE_CLOUD_QUOTA_PROJECT overrides the quota_project_id of the credentials.
+    pub quota_project_id: Option<String>,
+}
+
+// Creates the token source from the config.
+// Unless the credentials are specified in the config, they are found in the order described in the README.
+pub async fn create_token_source(config: Config) -> Result<Arc<dyn TokenSource>, error::Error> {
+    Ok(create_credential(config).await?.token_source)
+}
 
-    return match credentials {
+// Creates the credential in the same way as create_token_source.
+pub async fn create_credential(config: Config) -> Result<Credential, error::Error> {
+    let credentials = match &config.credentials {
+        Some(credentials) => Ok(credentials.clone()),
+        None => credentials::CredentialsFile::new_with_gcloud_account(config.gcloud_account.as_deref()).await,
+    };
+
+    let (ts, token, project_id, quota_project_id, jwt_lifetime): (Box<dyn TokenSource>, _, _, _, _) = match credentials
+    {
         Ok(s) => {
-            let ts = credentials_from_json_with_params(s, &config)?;
+            let ts = credentials_from_json_with_params(&s, &config)?;
             let token = ts.token().await?;
-            Ok(Box::new(ReuseTokenSource::new(ts, token)))
+            let jwt_lifetime = match uses_self_signed_jwt(&s, &config) {
+                true => Some(config.jwt_lifetime.unwrap_or(MAX_JWT_LIFETIME)),
+                false => None,
+            };
+            (ts, token, s.project_id, s.quota_project_id, jwt_lifetime)
         }
         Err(e) => {
-            // use metadata server on gce
-            if on_gce().await {
-                let ts = ComputeTokenSource::new(&config.scopes_to_string(","))?;
-                let token = ts.token().await?;
-                Ok(Box::new(ReuseTokenSource::new(Box::new(ts), token)))
-            } else {
-                Err(e)
+            // use metadata server on gce, only if no credentials are configured, so that the errors of the configured
+            // ones are reported instead of running as the service account of the instance.
+            let not_configured = matches!(e, error::Error::CredentialsNotFound(_)) && !credentials::configured_by_env();
+            if config.credentials.is_some() || !not_configured || !on_gce().await {
+                return Err(e);
+            }
+            // the metadata server is accessed without the proxy unless the client is specified.
+            let ts: Box<dyn TokenSource> = match &config.audience {
+                Some(audience) if config.use_id_token => match &config.http_client {
+                    Some(client) => Box::new(ComputeIdTokenSource::new(audience)?.with_http_client(client.clone())),
+                    None => Box::new(ComputeIdTokenSource::new(audience)?),
+                },
+                _ => {
+                    let source = ComputeTokenSource::new(&config.scopes_to_string(","))?;
+                    match &config.http_client {
+                        Some(client) => Box::new(source.with_http_client(client.clone())),
+                        None => Box::new(source),
+                    }
+                }
+            };
+            // the first token is kept, so that the metadata server is asked only once.
+            match ts.token().await {
+                Ok(token) => (ts, token, None, None, None),
+                Err(error::Error::MetadataServerUnavailable(_) | error::Error::NotMetadataServer(_)) => return Err(e),
+                Err(other) => return Err(other),
             }
         }
     };
+    let token_source = ReuseTokenSource::new(ts, Some(token));
+    // the refresh margin is shortened for the short-lived self-signed JWT, in the same way as its cached.
+    let token_source = match jwt_lifetime {
+        Some(lifetime) => {
+            let (margin, jitter) = jwt_refresh_margin(
+                lifetime,
+                config.jwt_clock_skew.unwrap_or_default(),
+                chrono::Duration::seconds(DEFAULT_REFRESH_MARGIN_SECONDS),
+                chrono::Duration::seconds(DEFAULT_REFRESH_JITTER_SECONDS),
+            );
+            token_source.with_refresh_margin(margin).with_refresh_jitter(jitter)
+        }
+        None => token_source,
+    };
+    let token_source = match config.refresh_observer {
+        Some(observer) => token_source.with_refresh_observer(observer),
+        None => token_source,
+    };
+    let token_source = match config.metrics_sink {
+        Some(sink) => token_source.with_metrics_sink(sink),
+        None => token_source,
+    };
+    Ok(Credential {
+        token_source: Arc::new(token_source),
+        project_id,
+        quota_project_id: std::env::var(QUOTA_PROJECT_ENV).ok().or(quota_project_id),
+    })
+}
+
+// Creates the token source from the explicitly specified credentials instead of finding them.
+pub async fn create_token_source_from_credentials(
+    credentials: &CredentialsFile,
+    config: Config,
+) -> Result<Arc<dyn TokenSource>, error::Error> {
+    create_token_source(config.with_credentials(credentials.clone())).await
+}
+
+// The service account key signs the JWT itself unless the domain-wide delegation is required,
+// as chosen by credentials_from_json_with_params.
+fn uses_self_signed_jwt(credentials: &CredentialsFile, config: &Config) -> bool {
+    credentials.tp == SERVICE_ACCOUNT_KEY
+        && config.sub.is_none()
+        && (config.audience.is_some() || (config.scopes.is_some() && config.use_self_signed_jwt))
 }
 
 fn credentials_from_json_with_params(
-    credentials: CredentialsFile,
+    credentials: &CredentialsFile,
     config: &Config,
 ) -> Result<Box<dyn TokenSource>, error::Error> {
+    let overridden;
+    let credentials = match &config.token_url_override {
+        Some(token_url) => {
+            let mut c = credentials.clone();
+            c.token_uri = Some(token_url.to_string());
+            c.token_url_external = Some(token_url.to_string());
+            overridden = c;
+            &overridden
+        }
+        None => credentials,
+    };
+
+    if config.use_id_token {
+        return match &config.audience {
+            Some(audience) => id_token_source_from_credentials(credentials, audience, config),
+            None => Err(error::Error::ScopeOrAudienceRequired),
+        };
+    }
+
+    let client = config.http_client()?;
     match credentials.tp.as_str() {
         SERVICE_ACCOUNT_KEY => {
-            match config.audience {
-                None => {
-                    if config.scopes.is_none() {
-                        return Err(error::Error::ScopeOrAudienceRequired);
-                    }
-
-                    // use Standard OAuth 2.0 Flow
+            match (&config.audience, &config.scopes) {
+                // use self-signed JWT unless the domain-wide delegation is required.
+                (Some(audience), _) if config.sub.is_none() => {
+                    let source = ServiceAccountTokenSource::new(credentials, audience)?
+                        .with_jwt_lifetime(config.jwt_lifetime.unwrap_or(MAX_JWT_LIFETIME))?
+                        .with_clock_skew(config.jwt_clock_skew.unwrap_or_default())?;
+                    Ok(Box::new(source))
+                }
+                (_, Some(_)) if config.use_self_signed_jwt && config.sub.is_none() => {
                     let source =
-                        OAuth2ServiceAccountTokenSource::new(
-                            &credentials,
-                            config.scopes_to_string(" ").as_str(),
-                            config.delegation_email
-                        )?;
+                        ServiceAccountTokenSource::new_with_scopes(credentials, &config.scopes_to_string(" "))?
+                            .with_jwt_lifetime(config.jwt_lifetime.unwrap_or(MAX_JWT_LIFETIME))?
+                            .with_clock_skew(config.jwt_clock_skew.unwrap_or_default())?;
                     Ok(Box::new(source))
                 }
-                Some(audience) => {
-                    // use self-signed JWT.
-                    let source = ServiceAccountTokenSource::new(&credentials, audience)?;
+                (_, Some(_)) => {
+                    // use Standard OAuth 2.0 Flow
+                    let source = OAuth2ServiceAccountTokenSource::new(
+                        credentials,
+                        config.scopes_to_string(" ").as_str(),
+                        config.sub.as_deref(),
+                    )?
+                    .with_jwt_lifetime(config.jwt_lifetime.unwrap_or(MAX_JWT_LIFETIME))?
+                    .with_clock_skew(config.jwt_clock_skew.unwrap_or_default())?
+                    .with_retry_policy(config.retry_policy.clone())
+                    .with_http_client(client);
+                    Ok(Box::new(source))
+                }
+                (_, None) => Err(error::Error::ScopeOrAudienceRequired),
+            }
+        }
+        USER_CREDENTIALS_KEY => {
+            let source = UserAccountTokenSource::new(credentials)?
+                .with_retry_policy(config.retry_policy.clone())
+                .with_http_client(client);
+            Ok(Box::new(source))
+        }
+        EXTERNAL_ACCOUNT_KEY => {
+            let scopes = match config.scopes {
+                Some(_) => config.scopes_to_string(" "),
+                None => CLOUD_PLATFORM_SCOPE.to_string(),
+            };
+            let retry_policy = &config.retry_policy;
+            match &credentials.service_account_impersonation_url {
+                None => Ok(Box::new(external_account_token_source(credentials, &scopes, config)?)),
+                Some(url) => {
+                    // the federated token only needs to call the IAM Credentials API.
+                    let source = external_account_token_source(credentials, CLOUD_PLATFORM_SCOPE, config)?;
+                    let scopes = scopes.split(' ').map(|s| s.to_string()).collect();
+                    let mut source = ImpersonateTokenSource::new(Box::new(source), url, scopes)
+                        .with_retry_policy(retry_policy.clone())
+                        .with_http_client(client);
+                    let lifetime = credentials
+                        .service_account_impersonation
+                        .as_ref()
+                        .and_then(|impersonation| impersonation.token_lifetime_seconds);
+                    if let Some(lifetime) = lifetime {
+                        source = source.with_lifetime(std::time::Duration::from_secs(lifetime))?;
+                    }
                     Ok(Box::new(source))
                 }
             }
         }
-        USER_CREDENTIALS_KEY => Ok(Box::new(UserAccountTokenSource::new(&credentials)?)),
+        EXTERNAL_ACCOUNT_AUTHORIZED_USER_KEY => {
+            let source = ExternalAccountAuthorizedUserTokenSource::new(credentials)?
+                .with_retry_policy(config.retry_policy.clone())
+                .with_http_client(client);
+            Ok(Box::new(source))
+        }
+        IMPERSONATED_SERVICE_ACCOUNT_KEY => {
+            let (source, url) = impersonation_source(credentials, config)?;
+            let scopes = match &config.scopes {
+                Some(scopes) => scopes.clone(),
+                None => vec![CLOUD_PLATFORM_SCOPE.to_string()],
+            };
+            let source = ImpersonateTokenSource::new(source, url, scopes)
+                .with_delegates(impersonation_delegates(credentials))?
+                .with_retry_policy(config.retry_policy.clone())
+                .with_http_client(client);
+            Ok(Box::new(source))
+        }
         //TODO support GDC https://console.developers.google.com,
-        //TODO support external account
-        _ => Err(error::Error::UnsupportedAccountType(credentials.tp)),
+        _ => Err(error::Error::UnsupportedAccountType(credentials.tp.to_string())),
+    }
+}
+
+// Creates the token source of the source_credentials, which only needs to call the IAM Credentials API,
+// and returns it with the url of the impersonation.
+fn impersonation_source<'a>(
+    credentials: &'a CredentialsFile,
+    config: &Config,
+) -> Result<(Box<dyn TokenSource>, &'a str), error::Error> {
+    let missing = |field| credentials.missing(field);
+    let source_credentials = credentials
+        .source_credentials
+        .as_deref()
+        .ok_or_else(|| missing("source_credentials"))?;
+    let url = credentials
+        .service_account_impersonation_url
+        .as_deref()
+        .ok_or_else(|| missing("service_account_impersonation_url"))?;
+    let mut source_config = config.clone();
+    source_config.scopes = Some(vec![CLOUD_PLATFORM_SCOPE.to_string()]);
+    source_config.audience = None;
+    source_config.sub = None;
+    source_config.use_id_token = false;
+    let source = credentials_from_json_with_params(source_credentials, &source_config)?;
+    Ok((source, url))
+}
+
+// gcloud writes the delegates as the emails of the service accounts.
+fn impersonation_delegates(credentials: &CredentialsFile) -> Vec<String> {
+    credentials
+        .delegates
+        .iter()
+        .flatten()
+        .map(|delegate| match delegate.starts_with("projects/") {
+            true => delegate.to_string(),
+            false => format!("projects/-/serviceAccounts/{}", delegate),
+        })
+        .collect()
+}
+
+fn external_account_token_source(
+    credentials: &CredentialsFile,
+    scopes: &str,
+    config: &Config,
+) -> Result<ExternalAccountTokenSource, error::Error> {
+    let source = match &config.subject_token_provider {
+        Some(provider) => {
+            ExternalAccountTokenSource::new_with_subject_token_provider(credentials, scopes, provider.clone())?
+        }
+        None => ExternalAccountTokenSource::new(credentials, scopes)?,
+    };
+    Ok(source
+        .with_retry_policy(config.retry_policy.clone())
+        .with_http_client(config.http_client()?))
+}
+
+// Creates the token source whose token is the ID token for the audience.
+// The credentials are found in the same order as create_token_source.
+pub async fn create_id_token_source(audience: &str) -> Result<Arc<dyn TokenSource>, error::Error> {
+    create_token_source(Config::default().with_audience(audience).with_use_id_token(true)).await
+}
+
+fn id_token_source_from_credentials(
+    credentials: &CredentialsFile,
+    audience: &str,
+    config: &Config,
+) -> Result<Box<dyn TokenSource>, error::Error> {
+    let retry_policy = &config.retry_policy;
+    let client = config.http_client()?;
+    match credentials.tp.as_str() {
+        SERVICE_ACCOUNT_KEY => {
+            let source = ServiceAccountIdTokenSource::new(credentials, audience)?
+                .with_jwt_lifetime(config.jwt_lifetime.unwrap_or(MAX_JWT_LIFETIME))?
+                .with_clock_skew(config.jwt_clock_skew.unwrap_or_default())?
+                .with_retry_policy(retry_policy.clone())
+                .with_http_client(client);
+            Ok(Box::new(source))
+        }
+        EXTERNAL_ACCOUNT_KEY => match &credentials.service_account_impersonation_url {
+            Some(url) => {
+                let source = external_account_token_source(credentials, CLOUD_PLATFORM_SCOPE, config)?;
+                let url = url.replace(":generateAccessToken", ":generateIdToken");
+                let source = ImpersonateIdTokenSource::new(Box::new(source), &url, audience)
+                    .with_retry_policy(retry_policy.clone())
+                    .with_http_client(client);
+                Ok(Box::new(source))
+            }
+            None => Err(credentials.missing("service_account_impersonation_url")),
+        },
+        IMPERSONATED_SERVICE_ACCOUNT_KEY => {
+            let (source, url) = impersonation_source(credentials, config)?;
+            let url = url.replace(":generateAccessToken", ":generateIdToken");
+            let source = ImpersonateIdTokenSource::new(source, &url, audience)
+                .with_delegates(impersonation_delegates(credentials))?
+                .with_retry_policy(retry_policy.clone())
+                .with_http_client(client);
+            Ok(Box::new(source))
+        }
+        _ => Err(error::Error::UnsupportedAccountType(credentials.tp.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::http_client::HttpClient;
+    use crate::metrics::AtomicMetricsSink;
+    use crate::mock_server::{self, claims};
+    use crate::token_source::retry::RetryPolicy;
+    use crate::token_source::reuse_token_source::RefreshObserver;
+    use crate::{
+        create_credential, create_token_source, credentials_from_json_with_params, revoke_token_with_client,
+        token_info_with_client, Config,
+    };
+    use async_trait::async_trait;
+    use hyper::{Body, Request, Response, StatusCode};
+    use serial_test::serial;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const SCOPES: [&str; 2] = [
+        "https://www.googleapis.com/auth/cloud-platform",
+        "https://www.googleapis.com/auth/spanner.data",
+    ];
+
+    fn service_account() -> CredentialsFile {
+        CredentialsFile::new_from_str(include_str!("../testdata/service_account.json")).unwrap()
+    }
+
+    async fn token_claims(config: &Config) -> json::Value {
+        let token = credentials_from_json_with_params(&service_account(), config)
+            .unwrap()
+            .token()
+            .await
+            .unwrap();
+        claims(&token.access_token)
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = Config::default()
+            .with_scopes(&SCOPES)
+            .with_audience("https://spanner.googleapis.com/")
+            .with_sub("user@example.com")
+            .with_token_url_override("http://localhost/token");
+        assert_eq!(SCOPES.join(" "), config.scopes_to_string(" "));
+        assert_eq!(Some("https://spanner.googleapis.com/"), config.audience.as_deref());
+        assert_eq!(Some("user@example.com"), config.sub.as_deref());
+        assert_eq!(Some("http://localhost/token"), config.token_url_override.as_deref());
+        assert!(!config.use_id_token);
+        assert!(config.credentials.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_jwt_selection() {
+        // the audience takes precedence over the scopes.
+        let config = Config::default()
+            .with_audience("https://spanner.googleapis.com/")
+            .with_scopes(&SCOPES);
+        let claims_with_audience = token_claims(&config).await;
+        assert_eq!("https://spanner.googleapis.com/", claims_with_audience["aud"]);
+        assert!(claims_with_audience.get("scope").unwrap().is_null());
+
+        let config = Config::default().with_scopes(&SCOPES).with_use_self_signed_jwt(true);
+        let claims_with_scopes = token_claims(&config).await;
+        assert_eq!(SCOPES.join(" "), claims_with_scopes["scope"]);
+        assert!(claims_with_scopes.get("aud").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sub_uses_oauth2_flow() {
+        let host = mock_server::serve(|_, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let assertion = body.rsplit('=').next().unwrap();
+            let claims = claims(assertion);
+            assert_eq!("user@example.com", claims["sub"]);
+            assert_eq!(SCOPES.join(" "), claims["scope"]);
+            Response::new(Body::from(
+                r#"{"access_token":"delegated","expires_in":3599,"token_type":"Bearer"}"#,
+            ))
+        })
+        .await;
+
+        let config = Config::default()
+            .with_audience("https://spanner.googleapis.com/")
+            .with_scopes(&SCOPES)
+            .with_sub("user@example.com")
+            .with_credentials(service_account())
+            .with_token_url_override(&format!("http://{}/token", host));
+        let ts = create_token_source(config).await.unwrap();
+        assert_eq!("delegated", ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let host = mock_server::serve(move |_, _| {
+            if server_calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            Response::new(Body::from(r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#))
+        })
+        .await;
+
+        let config = Config::default()
+            .with_scopes(&SCOPES)
+            .with_credentials(service_account())
+            .with_token_url_override(&format!("http://{}/token", host));
+        let retry_policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let ts = create_token_source(config.clone().with_retry_policy(retry_policy))
+            .await
+            .unwrap();
+        assert_eq!("abc", ts.token().await.unwrap().access_token);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        calls.store(0, Ordering::SeqCst);
+        let result = create_token_source(config.with_retry_policy(RetryPolicy::no_retry())).await;
+        assert!(matches!(
+            result,
+            Err(Error::TokenEndpoint {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                ..
+            })
+        ));
+    }
+
+    struct FakeHttpClient {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl HttpClient for FakeHttpClient {
+        async fn request(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            assert_eq!("https://oauth2.googleapis.com/token", request.uri().to_string());
+            Ok(Response::new(Body::from(
+                r#"{"access_token":"from-fake","expires_in":3599,"token_type":"Bearer"}"#,
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_client() {
+        let client = Arc::new(FakeHttpClient {
+            calls: AtomicUsize::new(0),
+        });
+        let config = Config::default()
+            .with_scopes(&SCOPES)
+            .with_credentials(service_account())
+            .with_http_client(client.clone());
+        let ts = create_token_source(config).await.unwrap();
+        assert_eq!("from-fake", ts.token().await.unwrap().access_token);
+        assert_eq!(1, client.calls.load(Ordering::SeqCst));
+    }
+
+    #[derive(Default)]
+    struct RecordingHttpClient {
+        urls: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl HttpClient for RecordingHttpClient {
+        async fn request(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+            self.urls.lock().unwrap().push(request.uri().to_string());
+            Ok(Response::new(Body::from(r#"{"scope":"openid"}"#)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_client_of_token_info_and_revoke() {
+        let client = RecordingHttpClient::default();
+        let info = token_info_with_client(&client, "ya29.token").await.unwrap();
+        assert_eq!(vec!["openid"], info.scopes);
+        revoke_token_with_client(&client, "ya29.token").await.unwrap();
+        assert_eq!(
+            vec![
+                "https://www.googleapis.com/oauth2/v3/tokeninfo?access_token=ya29.token",
+                "https://oauth2.googleapis.com/revoke",
+            ],
+            *client.urls.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_use_id_token() {
+        let host = mock_server::serve(|_, _| {
+            let id_token = format!(
+                "header.{}.signature",
+                base64::encode_config(r#"{"exp":1893456000}"#, base64::URL_SAFE_NO_PAD)
+            );
+            Response::new(Body::from(json::json!({ "id_token": id_token }).to_string()))
+        })
+        .await;
+
+        let config = Config::default()
+            .with_audience("https://example.run.app")
+            .with_use_id_token(true)
+            .with_credentials(service_account())
+            .with_token_url_override(&format!("http://{}/token", host));
+        let ts = create_token_source(config).await.unwrap();
+        assert!(ts.token().await.unwrap().access_token.starts_with("header."));
+    }
+
+    #[test]
+    fn test_audience_or_scopes_required() {
+        let config = Config::default().with_use_self_signed_jwt(true);
+        assert!(matches!(
+            credentials_from_json_with_params(&service_account(), &config),
+            Err(Error::ScopeOrAudienceRequired)
+        ));
+        let config = Config::default().with_scopes(&SCOPES).with_use_id_token(true);
+        assert!(matches!(
+            credentials_from_json_with_params(&service_account(), &config),
+            Err(Error::ScopeOrAudienceRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_external_account_authorized_user() {
+        let host = mock_server::serve(|req, _| {
+            assert_eq!("/v1/oauthtoken", req.uri.path());
+            Response::new(Body::from(r#"{"access_token":"workforce","expires_in":3600}"#))
+        })
+        .await;
+
+        let cred =
+            CredentialsFile::new_from_str(include_str!("../testdata/external_account_authorized_user.json")).unwrap();
+        let config = Config::default().with_token_url_override(&format!("http://{}/v1/oauthtoken", host));
+        let token = credentials_from_json_with_params(&cred, &config)
+            .unwrap()
+            .token()
+            .await
+            .unwrap();
+        assert_eq!("workforce", token.access_token);
+    }
+
+    #[tokio::test]
+    async fn test_impersonated_service_account() {
+        let host = mock_server::serve(|req, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let response = match req.uri.path() {
+                "/token" => {
+                    assert!(body.contains("grant_type=refresh_token"));
+                    r#"{"access_token":"user","token_type":"Bearer","expires_in":3600}"#.to_string()
+                }
+                "/v1/projects/-/serviceAccounts/target-sa@test-project.iam.gserviceaccount.com:generateAccessToken" => {
+                    assert_eq!("Bearer user", req.headers["Authorization"]);
+                    assert_eq!(
+                        r#"{"delegates":["projects/-/serviceAccounts/delegate@test-project.iam.gserviceaccount.com"],"scope":["https://www.googleapis.com/auth/cloud-platform","https://www.googleapis.com/auth/spanner.data"],"lifetime":"3600s"}"#,
+                        body
+                    );
+                    r#"{"accessToken":"impersonated","expireTime":"2099-10-01T10:00:00Z"}"#.to_string()
+                }
+                "/v1/projects/-/serviceAccounts/target-sa@test-project.iam.gserviceaccount.com:generateIdToken" => {
+                    assert_eq!("Bearer user", req.headers["Authorization"]);
+                    assert_eq!(
+                        r#"{"delegates":["projects/-/serviceAccounts/delegate@test-project.iam.gserviceaccount.com"],"audience":"https://example.run.app","includeEmail":true}"#,
+                        body
+                    );
+                    r#"{"token":"id-token"}"#.to_string()
+                }
+                path => panic!("unexpected path {}", path),
+            };
+            Response::new(Body::from(response))
+        })
+        .await;
+
+        let mut cred =
+            CredentialsFile::new_from_str(include_str!("../testdata/impersonated_service_account.json")).unwrap();
+        assert_eq!("authorized_user", cred.source_credentials.as_ref().unwrap().tp);
+        cred.service_account_impersonation_url = cred
+            .service_account_impersonation_url
+            .map(|url| url.replace("https://iamcredentials.googleapis.com", &format!("http://{}", host)));
+        cred.delegates = Some(vec!["delegate@test-project.iam.gserviceaccount.com".to_string()]);
+
+        let config = Config::default()
+            .with_scopes(&SCOPES)
+            .with_token_url_override(&format!("http://{}/token", host));
+        let token = credentials_from_json_with_params(&cred, &config)
+            .unwrap()
+            .token()
+            .await
+            .unwrap();
+        assert_eq!("impersonated", token.access_token);
+
+        let config = config.with_audience("https://example.run.app").with_use_id_token(true);
+        let token = credentials_from_json_with_params(&cred, &config)
+            .unwrap()
+            .token()
+            .await
+            .unwrap();
+        assert_eq!("id-token", token.access_token);
+
+        cred.source_credentials = None;
+        assert!(matches!(
+            credentials_from_json_with_params(&cred, &config),
+            Err(Error::MissingCredentialField {
+                field: "source_credentials",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_external_account_impersonation() {
+        let dir = std::env::temp_dir().join("google-cloud-auth-test-external-account-impersonation");
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("token");
+        std::fs::write(&token_file, "subject-token").unwrap();
+
+        let bodies = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = bodies.clone();
+        let host = mock_server::serve(move |req, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            recorded.lock().unwrap().push((req.uri.path().to_string(), body));
+            match req.uri.path() {
+                "/v1/token" => Response::new(Body::from(
+                    r#"{"access_token":"federated","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+                )),
+                _ => Response::new(Body::from(
+                    r#"{"accessToken":"impersonated","expireTime":"2099-10-01T10:00:00Z"}"#,
+                )),
+            }
+        })
+        .await;
+        let credentials = |fixture: &str| -> CredentialsFile {
+            let mut cred: json::Value = json::from_str(fixture).unwrap();
+            cred["token_url"] = json::json!(format!("http://{}/v1/token", host));
+            cred["credential_source"]["file"] = json::json!(token_file.to_str().unwrap());
+            if let Some(url) = cred["service_account_impersonation_url"].as_str() {
+                let url = url.replace("https://iamcredentials.googleapis.com", &format!("http://{}", host));
+                cred["service_account_impersonation_url"] = json::json!(url);
+            }
+            json::from_value(cred).unwrap()
+        };
+        let config = Config::default().with_scopes(&["https://www.googleapis.com/auth/devstorage.read_only"]);
+
+        // the scopes are applied at the impersonation with the lifetime of the credentials.
+        let cred = credentials(include_str!("../testdata/external_account/impersonation_lifetime.json"));
+        let ts = credentials_from_json_with_params(&cred, &config).unwrap();
+        assert_eq!("impersonated", ts.token().await.unwrap().access_token);
+        // the federated token is used directly with the scopes applied at the exchange.
+        let cred = credentials(include_str!("../testdata/external_account/direct.json"));
+        let ts = credentials_from_json_with_params(&cred, &config).unwrap();
+        assert_eq!("federated", ts.token().await.unwrap().access_token);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(3, bodies.len());
+        assert_eq!("/v1/token", bodies[0].0);
+        assert!(bodies[0]
+            .1
+            .contains("&scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform&"));
+        assert_eq!(
+            "/v1/projects/-/serviceAccounts/sa@my-project.iam.gserviceaccount.com:generateAccessToken",
+            bodies[1].0
+        );
+        assert_eq!(
+            r#"{"scope":["https://www.googleapis.com/auth/devstorage.read_only"],"lifetime":"2800s"}"#,
+            bodies[1].1
+        );
+        assert_eq!("/v1/token", bodies[2].0);
+        assert!(bodies[2]
+            .1
+            .contains("&scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fdevstorage.read_only&"));
+
+        // the lifetime is validated.
+        let mut cred = credentials(include_str!("../testdata/external_account/impersonation_lifetime.json"));
+        cred.service_account_impersonation
+            .as_mut()
+            .unwrap()
+            .token_lifetime_seconds = Some(50000);
+        assert!(matches!(
+            credentials_from_json_with_params(&cred, &config),
+            Err(Error::InvalidImpersonationConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_observer() {
+        #[derive(Default)]
+        struct CountingObserver(AtomicUsize);
+
+        impl RefreshObserver for CountingObserver {
+            fn on_refresh_success(&self, _: Option<chrono::DateTime<chrono::Utc>>, _: Duration) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let observer = Arc::new(CountingObserver::default());
+        let config = Config::default()
+            .with_audience("https://spanner.googleapis.com/")
+            .with_credentials(service_account())
+            .with_refresh_observer(observer.clone());
+        let credential = create_credential(config).await.unwrap();
+        credential.token_source.refresh().await.unwrap();
+        assert_eq!(1, observer.0.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_short_jwt_lifetime_is_cached() {
+        // the refresh margin is shortened for the JWT living shorter than the default margin.
+        let sink = Arc::new(AtomicMetricsSink::new());
+        let config = Config::default()
+            .with_audience("https://spanner.googleapis.com/")
+            .with_credentials(service_account())
+            .with_jwt_lifetime(Duration::from_secs(60))
+            .with_metrics_sink(sink.clone());
+        let credential = create_credential(config).await.unwrap();
+        credential.token_source.token().await.unwrap();
+        credential.token_source.token().await.unwrap();
+        let snapshot = sink.snapshot();
+        assert_eq!((2, 0), (snapshot.cache_hits, snapshot.cache_misses));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_quota_project_id() {
+        let mut cred: json::Value = json::from_str(include_str!("../testdata/service_account.json")).unwrap();
+        cred["quota_project_id"] = json::json!("billing-project");
+        let cred: CredentialsFile = json::from_value(cred).unwrap();
+        let config = Config::default()
+            .with_audience("https://spanner.googleapis.com/")
+            .with_credentials(cred);
+
+        let credential = create_credential(config.clone()).await.unwrap();
+        assert_eq!(Some("test-project"), credential.project_id.as_deref());
+        assert_eq!(Some("billing-project"), credential.quota_project_id.as_deref());
+
+        std::env::set_var("GOOGLE_CLOUD_QUOTA_PROJECT", "overridden-project");
+        let credential = create_credential(config).await.unwrap();
+        std::env::remove_var("GOOGLE_CLOUD_QUOTA_PROJECT");
+        assert_eq!(Some("overridden-project"), credential.quota_project_id.as_deref());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_metadata_fallback_fetches_token_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve_metadata(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Response::new(Body::from(
+                r#"{"access_token":"from-metadata","token_type":"Bearer","expires_in":3599}"#,
+            ))
+        })
+        .await;
+        let dir = std::env::temp_dir().join("google-cloud-auth-test-metadata-fallback");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS_JSON");
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS");
+        std::env::set_var("CLOUDSDK_CONFIG", &dir);
+        // GCE_METADATA_HOST makes on_gce trust that it runs on GCE.
+        std::env::set_var("GCE_METADATA_HOST", &host);
+
+        let credential = create_credential(Config::default().with_scopes(&SCOPES)).await;
+        std::env::remove_var("GCE_METADATA_HOST");
+        std::env::remove_var("CLOUDSDK_CONFIG");
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let ts = credential.unwrap().token_source;
+        assert_eq!("from-metadata", ts.token().await.unwrap().access_token);
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_metadata_fallback_only_without_credentials() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve_metadata(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Response::new(Body::from(
+                r#"{"access_token":"from-metadata","token_type":"Bearer","expires_in":3599}"#,
+            ))
+        })
+        .await;
+        let dir = std::env::temp_dir().join("google-cloud-auth-test-metadata-no-fallback");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS_JSON");
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS");
+        std::env::set_var("CLOUDSDK_CONFIG", &dir);
+        std::env::set_var("GCE_METADATA_HOST", &host);
+        assert!(crate::metadata::on_gce().await);
+
+        let create = || create_credential(Config::default().with_scopes(&SCOPES));
+        // the OAuth client ID file in the well known location.
+        std::fs::write(
+            dir.join("application_default_credentials.json"),
+            r#"{"installed":{"client_id":"id","client_secret":"secret"}}"#,
+        )
+        .unwrap();
+        let client_id_file = create().await;
+        std::fs::remove_file(dir.join("application_default_credentials.json")).unwrap();
+
+        std::env::set_var("GOOGLE_APPLICATION_CREDENTIALS_JSON", "{");
+        let malformed_json = create().await;
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS_JSON");
+
+        std::env::set_var("GOOGLE_APPLICATION_CREDENTIALS", dir.join("missing.json"));
+        let missing_path = create().await;
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS");
+
+        std::env::remove_var("GCE_METADATA_HOST");
+        std::env::remove_var("CLOUDSDK_CONFIG");
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(matches!(client_id_file, Err(Error::OAuthClientIdFile)));
+        assert!(matches!(malformed_json, Err(Error::JsonError(_))));
+        assert!(matches!(missing_path, Err(Error::CredentialsNotFound(_))));
+        assert_eq!(0, calls.load(Ordering::SeqCst));
     }
 }
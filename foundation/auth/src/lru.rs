@@ -0,0 +1,69 @@
+use std::collections::VecDeque;
+
+// Lru keeps up to the capacity of the values by the key, evicting the least recently used one.
+// The providers keep a few sources, so the linear search is faster than hashing the keys.
+pub(crate) struct Lru<V> {
+    capacity: usize,
+    entries: VecDeque<(String, V)>,
+}
+
+impl<V: Clone> Lru<V> {
+    pub(crate) fn new(capacity: usize) -> Lru<V> {
+        Lru {
+            capacity: capacity.max(1),
+            entries: VecDeque::new(),
+        }
+    }
+
+    // Returns the value of the key and marks it as the most recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<V> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(index)?;
+        let value = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(value)
+    }
+
+    // Inserts the value as the most recently used, replacing the value of the same key.
+    pub(crate) fn insert(&mut self, key: &str, value: V) {
+        if let Some(index) = self.entries.iter().position(|(k, _)| k == key) {
+            self.entries.remove(index);
+        } else if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key.to_string(), value));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lru::Lru;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        // a is used recently, so b is evicted.
+        assert_eq!(Some(1), lru.get("a"));
+        lru.insert("c", 3);
+        assert_eq!(2, lru.len());
+        assert_eq!(None, lru.get("b"));
+        assert_eq!(Some(1), lru.get("a"));
+
+        lru.insert("a", 4);
+        assert_eq!(2, lru.len());
+        assert_eq!(Some(4), lru.get("a"));
+        assert_eq!(Some(3), lru.get("c"));
+
+        // the capacity is at least 1.
+        let mut lru = Lru::new(0);
+        lru.insert("a", 1);
+        assert_eq!(Some(1), lru.get("a"));
+    }
+}
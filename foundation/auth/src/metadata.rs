@@ -0,0 +1,281 @@
+use crate::error::Error;
+use crate::http_client::{DefaultHttpClient, HttpClient};
+use crate::token_source::error_from_response;
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use google_cloud_metadata::{metadata_ip, METADATA_FLAVOR_KEY, METADATA_GOOGLE, METADATA_HOST_ENV};
+// The detection is shared with google-cloud-metadata, so that both crates give the same answer with the same cache.
+pub use google_cloud_metadata::{on_gce, METADATA_IP_ENV};
+use hyper::http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use urlencoding::encode;
+
+// The metadata server is on the link-local address, so the connection should be established almost instantly.
+const METADATA_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const METADATA_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The metadata server is never accessed through the proxy.
+pub(crate) fn metadata_http_client() -> Arc<dyn HttpClient> {
+    Arc::new(DefaultHttpClient::new(METADATA_CONNECT_TIMEOUT, METADATA_REQUEST_TIMEOUT, None))
+}
+
+// The metadata server may return 503 or reset the connections while the node is starting,
+// so the requests are retried a few times after the short backoff.
+pub fn metadata_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(500),
+        multiplier: 2.0,
+    }
+}
+
+fn is_metadata_server<B>(response: &Response<B>) -> bool {
+    response
+        .headers()
+        .get(METADATA_FLAVOR_KEY)
+        .map(|v| v == METADATA_GOOGLE)
+        == Some(true)
+}
+
+// MetadataClient gets the values of the metadata server on GCE, Cloud Run, GKE and so on.
+// https://cloud.google.com/compute/docs/metadata/default-metadata-values
+// The values which never change while the instance is running, such as the project and the zone, are cached.
+// The responses without the Metadata-Flavor: Google header, such as those of the captive portal, are rejected.
+#[derive(Clone)]
+pub struct MetadataClient {
+    host: String,
+    client: Arc<dyn HttpClient>,
+    retry_policy: RetryPolicy,
+    cache: Arc<RwLock<HashMap<&'static str, String>>>,
+}
+
+impl Default for MetadataClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetadataClient {
+    // The host is GCE_METADATA_HOST if it is set, otherwise GCE_METADATA_IP or the IP address of the metadata server.
+    pub fn new() -> MetadataClient {
+        let host = std::env::var(METADATA_HOST_ENV).unwrap_or_else(|_| metadata_ip());
+        Self::new_with_host(&host)
+    }
+
+    pub fn new_with_host(host: &str) -> MetadataClient {
+        MetadataClient {
+            host: host.to_string(),
+            client: metadata_http_client(),
+            retry_policy: metadata_retry_policy(),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // Sends the request to the path under /computeMetadata/v1/, which may have the query.
+    pub(crate) async fn request(&self, path: &str) -> Result<Response<Body>, Error> {
+        let url = format!("http://{}/computeMetadata/v1/{}", self.host, path);
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::GET)
+                .uri(url.as_str())
+                .header(METADATA_FLAVOR_KEY, METADATA_GOOGLE)
+                .body(Body::empty())?)
+        })
+        .await
+        .map_err(|e| match e {
+            Error::HyperError(e) => Error::MetadataServerUnavailable(e),
+            Error::RetryExhausted { attempts, source } => match *source {
+                Error::HyperError(e) => Error::MetadataServerUnavailable(e),
+                source => Error::RetryExhausted {
+                    attempts,
+                    source: Box::new(source),
+                },
+            },
+            e => e,
+        })?;
+        if !is_metadata_server(&response) {
+            return Err(Error::NotMetadataServer(url));
+        }
+        Ok(response)
+    }
+
+    // Returns the value of the path under /computeMetadata/v1/, for example instance/hostname.
+    pub async fn get(&self, path: &str) -> Result<String, Error> {
+        let response = self.request(path).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Err(Error::MetadataNotFound(path.to_string())),
+            status if !status.is_success() => return Err(error_from_response(response).await),
+            _ => {}
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(String::from_utf8_lossy(&body).trim().to_string())
+    }
+
+    async fn get_cached(&self, path: &'static str) -> Result<String, Error> {
+        if let Some(value) = self.cache.read().unwrap().get(path) {
+            return Ok(value.clone());
+        }
+        let value = self.get(path).await?;
+        self.cache.write().unwrap().insert(path, value.clone());
+        Ok(value)
+    }
+
+    pub async fn project_id(&self) -> Result<String, Error> {
+        self.get_cached("project/project-id").await
+    }
+
+    pub async fn numeric_project_id(&self) -> Result<String, Error> {
+        self.get_cached("project/numeric-project-id").await
+    }
+
+    // Returns the zone such as us-central1-a.
+    pub async fn zone(&self) -> Result<String, Error> {
+        // the metadata server returns projects/<numeric project id>/zones/<zone>.
+        let zone = self.get_cached("instance/zone").await?;
+        Ok(zone.rsplit('/').next().unwrap_or_default().to_string())
+    }
+
+    pub async fn instance_id(&self) -> Result<String, Error> {
+        self.get_cached("instance/id").await
+    }
+
+    // Returns the email of the default service account attached to the instance.
+    pub async fn service_account_email(&self) -> Result<String, Error> {
+        self.get_cached("instance/service-accounts/default/email").await
+    }
+
+    // Returns the custom metadata of the instance, or None if the key is not set.
+    // The attributes can be changed while the instance is running, so they are not cached.
+    pub async fn instance_attributes(&self, key: &str) -> Result<Option<String>, Error> {
+        match self.get(&format!("instance/attributes/{}", encode(key))).await {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::MetadataNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::metadata::{MetadataClient, METADATA_IP_ENV};
+    use crate::mock_server;
+    use hyper::{Body, Response, StatusCode};
+    use serial_test::serial;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_get() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve_metadata(move |req, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert_eq!("Google", req.headers["Metadata-Flavor"]);
+            let body = match req.uri.path() {
+                "/computeMetadata/v1/project/project-id" => "my-project",
+                "/computeMetadata/v1/project/numeric-project-id" => "1234",
+                "/computeMetadata/v1/instance/zone" => "projects/1234/zones/us-central1-a",
+                "/computeMetadata/v1/instance/id" => "5678",
+                "/computeMetadata/v1/instance/service-accounts/default/email" => {
+                    "sa@my-project.iam.gserviceaccount.com"
+                }
+                "/computeMetadata/v1/instance/attributes/enable-oslogin" => "TRUE\n",
+                _ => {
+                    return Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("not found"))
+                        .unwrap()
+                }
+            };
+            Response::new(Body::from(body))
+        })
+        .await;
+
+        let client = MetadataClient::new_with_host(&host);
+        assert_eq!("my-project", client.project_id().await.unwrap());
+        assert_eq!("1234", client.numeric_project_id().await.unwrap());
+        assert_eq!("us-central1-a", client.zone().await.unwrap());
+        assert_eq!("5678", client.instance_id().await.unwrap());
+        assert_eq!(
+            "sa@my-project.iam.gserviceaccount.com",
+            client.service_account_email().await.unwrap()
+        );
+        assert_eq!(
+            Some("TRUE".to_string()),
+            client.instance_attributes("enable-oslogin").await.unwrap()
+        );
+        assert_eq!(None, client.instance_attributes("startup-script").await.unwrap());
+        assert!(matches!(
+            client.get("instance/unknown").await,
+            Err(Error::MetadataNotFound(path)) if path == "instance/unknown"
+        ));
+        assert_eq!(8, calls.load(Ordering::SeqCst));
+
+        // the immutable values are cached, also in the clones.
+        let clone = client.clone();
+        assert_eq!("my-project", clone.project_id().await.unwrap());
+        assert_eq!("us-central1-a", clone.zone().await.unwrap());
+        assert_eq!(8, calls.load(Ordering::SeqCst));
+        client.instance_attributes("enable-oslogin").await.unwrap();
+        assert_eq!(9, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve_metadata(move |_, _| {
+            let status = match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::OK,
+            };
+            Response::builder()
+                .status(status)
+                .body(Body::from("my-project"))
+                .unwrap()
+        })
+        .await;
+        let client = MetadataClient::new_with_host(&host);
+        assert_eq!("my-project", client.project_id().await.unwrap());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_not_metadata_server() {
+        // the captive portal answers any request without the header.
+        let host = mock_server::serve(|_, _| Response::new(Body::from("<html>sign in</html>"))).await;
+        let client = MetadataClient::new_with_host(&host);
+        assert!(matches!(client.project_id().await, Err(Error::NotMetadataServer(_))));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_metadata_ip_env() {
+        let host = mock_server::serve_metadata(|_, _| Response::new(Body::from("my-project"))).await;
+        std::env::set_var(METADATA_IP_ENV, &host);
+        let client = MetadataClient::new();
+        std::env::remove_var(METADATA_IP_ENV);
+        assert_eq!("my-project", client.project_id().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unavailable() {
+        // nothing listens on the discard port.
+        let client = MetadataClient::new_with_host("127.0.0.1:9");
+        assert!(matches!(client.project_id().await, Err(Error::MetadataServerUnavailable(_))));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::error::Error;
+use crate::token::Token;
+use crate::token_source::retry::is_transient;
+use crate::token_source::TokenSource;
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// ErrorKind classifies the errors of the refreshes for the metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    // The errors which may go away later, such as the timeouts and the 5xx responses.
+    Transient,
+    // The token endpoint rejected the credentials, such as the invalid_grant and the revoked tokens.
+    Rejected,
+    // The other errors, such as the invalid configurations and the unexpected responses.
+    Other,
+}
+
+impl ErrorKind {
+    pub fn of(error: &Error) -> ErrorKind {
+        match error {
+            Error::Shared(e) => ErrorKind::of(e),
+            e if is_transient(e) => ErrorKind::Transient,
+            Error::RefreshTokenRejected(_) | Error::TokenRevoked | Error::InvalidToken(_) => ErrorKind::Rejected,
+            Error::TokenEndpoint { status, .. } if status.is_client_error() => ErrorKind::Rejected,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+// TokenMetricsSink receives the metrics of the token sources.
+// The methods are called on the path of every token request, so they must not block. They do nothing by default.
+pub trait TokenMetricsSink: Send + Sync {
+    // The cached token was returned.
+    fn record_cache_hit(&self) {}
+
+    // The cached token was missing or stale, so the token was refreshed or the caller waited for the refresh.
+    fn record_cache_miss(&self) {}
+
+    // The request for the new token took the latency, whether it succeeded or not.
+    fn record_refresh_latency(&self, _latency: Duration) {}
+
+    fn record_refresh_error(&self, _kind: ErrorKind) {}
+}
+
+// NoopMetricsSink discards the metrics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetricsSink;
+
+impl TokenMetricsSink for NoopMetricsSink {}
+
+// AtomicMetricsSink counts the metrics with the atomic counters, which snapshot returns to be scraped.
+#[derive(Debug, Default)]
+pub struct AtomicMetricsSink {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    refreshes: AtomicU64,
+    refresh_latency_micros: AtomicU64,
+    transient_errors: AtomicU64,
+    rejected_errors: AtomicU64,
+    other_errors: AtomicU64,
+}
+
+// The counters of AtomicMetricsSink since it is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub refreshes: u64,
+    // The sum of the latencies of the refreshes.
+    pub refresh_latency: Duration,
+    pub transient_errors: u64,
+    pub rejected_errors: u64,
+    pub other_errors: u64,
+}
+
+impl MetricsSnapshot {
+    // Returns the ratio of the cache hits, or None before the first request.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        match self.cache_hits + self.cache_misses {
+            0 => None,
+            total => Some(self.cache_hits as f64 / total as f64),
+        }
+    }
+
+    pub fn mean_refresh_latency(&self) -> Option<Duration> {
+        match self.refreshes {
+            0 => None,
+            n => Some(self.refresh_latency / n as u32),
+        }
+    }
+}
+
+impl AtomicMetricsSink {
+    pub fn new() -> AtomicMetricsSink {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            refreshes: self.refreshes.load(Ordering::Relaxed),
+            refresh_latency: Duration::from_micros(self.refresh_latency_micros.load(Ordering::Relaxed)),
+            transient_errors: self.transient_errors.load(Ordering::Relaxed),
+            rejected_errors: self.rejected_errors.load(Ordering::Relaxed),
+            other_errors: self.other_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl TokenMetricsSink for AtomicMetricsSink {
+    fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_refresh_latency(&self, latency: Duration) {
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+        self.refresh_latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn record_refresh_error(&self, kind: ErrorKind) {
+        let counter = match kind {
+            ErrorKind::Transient => &self.transient_errors,
+            ErrorKind::Rejected => &self.rejected_errors,
+            ErrorKind::Other => &self.other_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Records the latency and the error of the request.
+pub(crate) async fn measure<F>(sink: &dyn TokenMetricsSink, request: F) -> Result<Token, Error>
+where
+    F: std::future::Future<Output = Result<Token, Error>>,
+{
+    let start = Instant::now();
+    let result = request.await;
+    sink.record_refresh_latency(start.elapsed());
+    if let Err(e) = &result {
+        sink.record_refresh_error(ErrorKind::of(e));
+    }
+    result
+}
+
+// MeteredTokenSource records the latency and the errors of every token request of the token source without the cache,
+// such as the HTTP-based sources. Use ReuseTokenSource::with_metrics_sink for the cached sources instead.
+pub struct MeteredTokenSource<T> {
+    inner: T,
+    sink: Arc<dyn TokenMetricsSink>,
+}
+
+impl<T: TokenSource> MeteredTokenSource<T> {
+    pub fn new(inner: T, sink: Arc<dyn TokenMetricsSink>) -> MeteredTokenSource<T> {
+        MeteredTokenSource { inner, sink }
+    }
+}
+
+#[async_trait]
+impl<T: TokenSource> TokenSource for MeteredTokenSource<T> {
+    async fn token(&self) -> Result<Token, Error> {
+        measure(self.sink.as_ref(), self.inner.token()).await
+    }
+
+    async fn refresh(&self) -> Result<Token, Error> {
+        measure(self.sink.as_ref(), self.inner.refresh()).await
+    }
+
+    fn invalidate(&self, token: &Token) {
+        self.inner.invalidate(token)
+    }
+
+    fn revoked(&self) -> bool {
+        self.inner.revoked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::metrics::{AtomicMetricsSink, ErrorKind};
+    use crate::testing::{FailingTokenSource, StaticTokenSource};
+    use crate::token_source::{TokenSource, TokenSourceExt};
+    use hyper::StatusCode;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn endpoint_error(status: StatusCode) -> Error {
+        Error::TokenEndpoint {
+            status,
+            error: "error".to_string(),
+            description: None,
+            uri: None,
+        }
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(
+            ErrorKind::Transient,
+            ErrorKind::of(&endpoint_error(StatusCode::SERVICE_UNAVAILABLE))
+        );
+        assert_eq!(ErrorKind::Rejected, ErrorKind::of(&endpoint_error(StatusCode::BAD_REQUEST)));
+        assert_eq!(ErrorKind::Rejected, ErrorKind::of(&Error::TokenRevoked));
+        assert_eq!(ErrorKind::Other, ErrorKind::of(&Error::ScopeOrAudienceRequired));
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_ratio() {
+        let sink = Arc::new(AtomicMetricsSink::new());
+        let expiry = chrono::Utc::now() + chrono::Duration::hours(1);
+        let ts = StaticTokenSource::new("static")
+            .with_expiry(expiry)
+            .cached()
+            .with_metrics_sink(sink.clone());
+        assert_eq!(None, sink.snapshot().cache_hit_ratio());
+        for _ in 0..4 {
+            ts.token().await.unwrap();
+        }
+        let snapshot = sink.snapshot();
+        assert_eq!(3, snapshot.cache_hits);
+        assert_eq!(1, snapshot.cache_misses);
+        assert_eq!(1, snapshot.refreshes);
+        assert_eq!(Some(0.75), snapshot.cache_hit_ratio());
+    }
+
+    #[tokio::test]
+    async fn test_metered() {
+        let sink = Arc::new(AtomicMetricsSink::new());
+        let ts = StaticTokenSource::new("static").metered(sink.clone());
+        ts.token().await.unwrap();
+        ts.refresh().await.unwrap();
+        assert!(FailingTokenSource::unavailable()
+            .metered(sink.clone())
+            .token()
+            .await
+            .is_err());
+
+        let snapshot = sink.snapshot();
+        assert_eq!(3, snapshot.refreshes);
+        assert_eq!(1, snapshot.transient_errors);
+        assert_eq!(0, snapshot.cache_hits + snapshot.cache_misses);
+        assert!(snapshot.mean_refresh_latency().unwrap() < Duration::from_secs(1));
+    }
+}
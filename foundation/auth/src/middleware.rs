@@ -0,0 +1,254 @@
+use crate::error::Error;
+use crate::token::Token;
+use crate::token_source::TokenSource;
+use crate::Credential;
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::http::request::Parts;
+use hyper::http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+const USER_PROJECT_HEADER: &str = "x-goog-user-project";
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// BearerToken selects which token of the Token is sent as the bearer token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BearerToken {
+    #[default]
+    AccessToken,
+    // The ID token, which the service-to-service requests to Cloud Run and Cloud Functions require.
+    // The token must have the id_token, such as the token of create_id_token_source.
+    IdToken,
+}
+
+impl BearerToken {
+    fn value<'a>(&self, token: &'a Token) -> Result<&'a str, Error> {
+        match self {
+            BearerToken::AccessToken => Ok(&token.access_token),
+            BearerToken::IdToken => token.id_token.as_deref().ok_or(Error::IdTokenNotFound),
+        }
+    }
+}
+
+// Returns true if the 401 response rejects the token, so that a new token may be accepted.
+// The Bearer challenge of WWW-Authenticate has no error or invalid_token for the expired or revoked token.
+// The other errors and the challenges of the other schemes, such as that of the proxy, are not fixed by the refresh.
+pub(crate) fn token_rejected<B>(response: &Response<B>) -> bool {
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return false;
+    }
+    let challenge = match response.headers().get(WWW_AUTHENTICATE).map(|v| v.to_str()) {
+        None => return true,
+        Some(Ok(challenge)) => challenge,
+        Some(Err(_)) => return false,
+    };
+    let (scheme, params) = challenge.trim().split_once(' ').unwrap_or((challenge.trim(), ""));
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return false;
+    }
+    params
+        .split(',')
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("error"))
+        .is_none_or(|(_, value)| value.trim().trim_matches('"') == "invalid_token")
+}
+
+// RejectedToken remembers the token rejected again after the refresh, such as when the service account has lost
+// the permission. The requests with that token are not retried, so that the persistent 401 doesn't refresh
+// the token for every request. The token is retried once more after it is replaced, for example when it expires.
+#[derive(Clone, Default)]
+pub(crate) struct RejectedToken(Arc<Mutex<Option<String>>>);
+
+impl RejectedToken {
+    pub(crate) fn contains(&self, token: &Token) -> bool {
+        self.0.lock().unwrap().as_deref() == Some(token.access_token.as_str())
+    }
+
+    pub(crate) fn set(&self, token: &Token) {
+        *self.0.lock().unwrap() = Some(token.access_token.clone());
+    }
+}
+
+// Authorizer sends the token with the requests of AuthLayer and AuthorizedClient, and the request once more with
+// a new token when the token is rejected with 401.
+#[derive(Clone)]
+pub(crate) struct Authorizer {
+    pub(crate) token_source: Arc<dyn TokenSource>,
+    pub(crate) quota_project_id: Option<HeaderValue>,
+    pub(crate) bearer_token: BearerToken,
+    rejected: RejectedToken,
+}
+
+impl Authorizer {
+    pub(crate) fn new(token_source: Arc<dyn TokenSource>) -> Authorizer {
+        Authorizer {
+            token_source,
+            quota_project_id: None,
+            bearer_token: BearerToken::default(),
+            rejected: RejectedToken::default(),
+        }
+    }
+
+    pub(crate) fn with_quota_project_id(mut self, quota_project_id: &str) -> Result<Authorizer, Error> {
+        self.quota_project_id = Some(HeaderValue::from_str(quota_project_id).map_err(hyper::http::Error::from)?);
+        Ok(self)
+    }
+
+    fn authorize(&self, headers: &mut HeaderMap, token: &Token) -> Result<(), Error> {
+        // the empty token of the AnonymousTokenSource is not sent.
+        if !token.access_token.is_empty() {
+            let value = format!("Bearer {}", self.bearer_token.value(token)?);
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(&value).map_err(hyper::http::Error::from)?);
+        }
+        if let Some(quota_project_id) = &self.quota_project_id {
+            headers.insert(USER_PROJECT_HEADER, quota_project_id.clone());
+        }
+        Ok(())
+    }
+
+    // Sends the request with the token by send, and once more with a new token if the token is rejected.
+    // The first attempt is the original request with its extensions. The retry is rebuilt from the method, the uri,
+    // the version and the headers, without the extensions, which can't be cloned.
+    pub(crate) async fn send<B, R, E, F, Fut>(&self, parts: Parts, body: Bytes, mut send: F) -> Result<Response<R>, E>
+    where
+        B: From<Bytes>,
+        F: FnMut(Request<B>) -> Fut,
+        Fut: Future<Output = Result<Response<R>, E>>,
+        E: From<Error>,
+    {
+        let (method, uri, version, headers) =
+            (parts.method.clone(), parts.uri.clone(), parts.version, parts.headers.clone());
+        let token = self.token_source.token().await?;
+        let mut request = Request::from_parts(parts, B::from(body.clone()));
+        self.authorize(request.headers_mut(), &token)?;
+        let response = send(request).await?;
+        if !token_rejected(&response) || self.rejected.contains(&token) {
+            return Ok(response);
+        }
+
+        self.token_source.invalidate(&token);
+        let token = self.token_source.token().await?;
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .version(version)
+            .body(B::from(body))
+            .map_err(Error::from)?;
+        *request.headers_mut() = headers;
+        self.authorize(request.headers_mut(), &token)?;
+        let response = send(request).await?;
+        if token_rejected(&response) {
+            self.rejected.set(&token);
+        }
+        Ok(response)
+    }
+}
+
+// AuthLayer wraps the HTTP service to send the token of the token source with every request.
+// When the service rejects the token with 401, the cached token is invalidated and the request is sent once more
+// with a new token. The request is not retried again if the new token is also rejected.
+#[derive(Clone)]
+pub struct AuthLayer {
+    authorizer: Authorizer,
+}
+
+impl AuthLayer {
+    pub fn new(token_source: Arc<dyn TokenSource>) -> AuthLayer {
+        AuthLayer {
+            authorizer: Authorizer::new(token_source),
+        }
+    }
+
+    // Uses the token source and the quota project of the credential.
+    pub fn from_credential(credential: &Credential) -> Result<AuthLayer, Error> {
+        let layer = AuthLayer::new(credential.token_source.clone());
+        match &credential.quota_project_id {
+            Some(quota_project_id) => layer.with_quota_project_id(quota_project_id),
+            None => Ok(layer),
+        }
+    }
+
+    // Sends the project as the x-goog-user-project header.
+    pub fn with_quota_project_id(mut self, quota_project_id: &str) -> Result<AuthLayer, Error> {
+        self.authorizer = self.authorizer.with_quota_project_id(quota_project_id)?;
+        Ok(self)
+    }
+
+    // Sends the ID token instead of the access token with BearerToken::IdToken.
+    pub fn with_bearer_token(mut self, bearer_token: BearerToken) -> AuthLayer {
+        self.authorizer.bearer_token = bearer_token;
+        self
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            authorizer: self.authorizer.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    authorizer: Authorizer,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for AuthService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody + From<Bytes> + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    ResBody: Send,
+{
+    type Response = Response<ResBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(|e| e.into())
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // the service made ready by poll_ready must be the one that is called.
+        let clone = self.clone();
+        let this = std::mem::replace(self, clone);
+        Box::pin(async move {
+            // the body is buffered to send it again after the refresh.
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await.map_err(|e| e.into())?;
+
+            // the first attempt uses the ready service, and the retry waits for its clone to be ready.
+            let spare = this.inner.clone();
+            let mut ready = Some(this.inner);
+            this.authorizer
+                .send(parts, body, move |request| {
+                    let (mut inner, was_ready) = match ready.take() {
+                        Some(inner) => (inner, true),
+                        None => (spare.clone(), false),
+                    };
+                    async move {
+                        if !was_ready {
+                            std::future::poll_fn(|cx| inner.poll_ready(cx))
+                                .await
+                                .map_err(|e| e.into())?;
+                        }
+                        inner.call(request).await.map_err(|e| e.into())
+                    }
+                })
+                .await
+        })
+    }
+}
@@ -0,0 +1,52 @@
+use hyper::body::Bytes;
+use hyper::http::request::Parts;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+// Starts a local http server answering every request with the handler and returns its host:port.
+pub(crate) async fn serve<F>(handler: F) -> String
+where
+    F: Fn(Parts, Bytes) -> Response<Body> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let handler = handler.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap();
+                    Ok::<_, Infallible>(handler(parts, body))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr.to_string()
+}
+
+// Starts the fake metadata server, which adds the Metadata-Flavor: Google header to the responses of the handler.
+pub(crate) async fn serve_metadata<F>(handler: F) -> String
+where
+    F: Fn(Parts, Bytes) -> Response<Body> + Send + Sync + 'static,
+{
+    serve(move |parts, body| {
+        let mut response = handler(parts, body);
+        response
+            .headers_mut()
+            .insert("Metadata-Flavor", hyper::http::HeaderValue::from_static("Google"));
+        response
+    })
+    .await
+}
+
+// Returns the claims of the JWT without verifying the signature.
+pub(crate) fn claims(token: &str) -> json::Value {
+    let payload = token.split('.').nth(1).unwrap();
+    json::from_slice(&base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap()).unwrap()
+}
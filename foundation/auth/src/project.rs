@@ -0,0 +1,111 @@
+use crate::credentials::CredentialsFile;
+use crate::error::Error;
+use crate::metadata::{on_gce, MetadataClient};
+use std::future::Future;
+use tokio::sync::OnceCell;
+
+pub const PROJECT_ENV: &str = "GOOGLE_CLOUD_PROJECT";
+pub const LEGACY_PROJECT_ENV: &str = "GCLOUD_PROJECT";
+
+static PROJECT_ID: OnceCell<String> = OnceCell::const_new();
+
+// Returns the project the application runs in, which is found in the following order.
+// 1. GOOGLE_CLOUD_PROJECT or GCLOUD_PROJECT environment variable.
+// 2. The project_id of the credentials found in the same way as create_token_source.
+// 3. The project of the metadata server on GCE.
+// The found project is cached. The failure is not, so the next call tries again.
+pub async fn project_id() -> Result<String, Error> {
+    PROJECT_ID
+        .get_or_try_init(|| async {
+            let metadata = async { on_gce().await.then(MetadataClient::new) };
+            find_project_id(CredentialsFile::new(), metadata).await
+        })
+        .await
+        .map(|s| s.to_string())
+}
+
+// The credentials and the metadata client are awaited only when the project is not found in the previous places,
+// so that the environment variable saves the file read and the probe of the metadata server.
+pub(crate) async fn find_project_id(
+    credentials: impl Future<Output = Result<CredentialsFile, Error>>,
+    metadata: impl Future<Output = Option<MetadataClient>>,
+) -> Result<String, Error> {
+    let mut tried = vec![];
+    for env in [PROJECT_ENV, LEGACY_PROJECT_ENV] {
+        match std::env::var(env) {
+            Ok(project_id) if !project_id.is_empty() => return Ok(project_id),
+            _ => tried.push(format!("{} is not set", env)),
+        }
+    }
+
+    match credentials.await {
+        Ok(CredentialsFile {
+            project_id: Some(project_id),
+            ..
+        }) if !project_id.is_empty() => return Ok(project_id),
+        Ok(credentials) => tried.push(format!("{} credentials have no project_id", credentials.tp)),
+        Err(e) => tried.push(format!("credentials are not found: {}", e)),
+    }
+
+    match metadata.await {
+        Some(metadata) => match metadata.project_id().await {
+            Ok(project_id) if !project_id.is_empty() => return Ok(project_id),
+            Ok(_) => tried.push("metadata server returned no project".to_string()),
+            Err(e) => tried.push(format!("metadata server failed: {}", e)),
+        },
+        None => tried.push("not on GCE".to_string()),
+    }
+    Err(Error::ProjectIdNotFound(tried))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::metadata::MetadataClient;
+    use crate::mock_server;
+    use crate::project::{find_project_id, LEGACY_PROJECT_ENV, PROJECT_ENV};
+    use hyper::{Body, Response};
+    use serial_test::serial;
+
+    async fn credentials(project_id: Option<&str>) -> Result<CredentialsFile, Error> {
+        let mut credentials = json::json!({"type": "authorized_user"});
+        if let Some(project_id) = project_id {
+            credentials["project_id"] = json::Value::String(project_id.to_string());
+        }
+        CredentialsFile::new_from_str(&credentials.to_string())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_find_project_id() {
+        let host = mock_server::serve_metadata(|req, _| {
+            assert_eq!("Google", req.headers["Metadata-Flavor"]);
+            Response::new(Body::from("from-metadata"))
+        })
+        .await;
+        let metadata = || async { Some(MetadataClient::new_with_host(&host)) };
+
+        std::env::set_var(LEGACY_PROJECT_ENV, "from-legacy-env");
+        std::env::set_var(PROJECT_ENV, "from-env");
+        // neither the credentials nor the metadata server is looked up.
+        let found = find_project_id(async { unreachable!() }, async { unreachable!() }).await;
+        assert_eq!("from-env", found.unwrap());
+
+        std::env::remove_var(PROJECT_ENV);
+        let found = find_project_id(credentials(Some("from-file")), metadata()).await;
+        assert_eq!("from-legacy-env", found.unwrap());
+
+        std::env::remove_var(LEGACY_PROJECT_ENV);
+        let found = find_project_id(credentials(Some("from-file")), async { unreachable!() }).await;
+        assert_eq!("from-file", found.unwrap());
+
+        let found = find_project_id(credentials(None), metadata()).await;
+        assert_eq!("from-metadata", found.unwrap());
+
+        match find_project_id(credentials(None), async { None }).await {
+            Err(Error::ProjectIdNotFound(tried)) => assert_eq!(4, tried.len(), "{:?}", tried),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
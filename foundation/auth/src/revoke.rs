@@ -0,0 +1,77 @@
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{error_from_response, form_body};
+use hyper::http::{Method, Request};
+use hyper::Body;
+
+pub const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+// Revokes the access token or the refresh token.
+// Revoking the refresh token also revokes the access tokens issued with it.
+// https://developers.google.com/identity/protocols/oauth2/web-server#tokenrevoke
+pub async fn revoke_token(token: &str) -> Result<(), Error> {
+    revoke_token_with_client(default_http_client().as_ref(), token).await
+}
+
+// Same as revoke_token, but sends the request with the client, for example the one given to Config::with_http_client.
+pub async fn revoke_token_with_client(client: &dyn HttpClient, token: &str) -> Result<(), Error> {
+    revoke(client, &RetryPolicy::default(), REVOKE_URL, token).await
+}
+
+pub(crate) async fn revoke(
+    client: &dyn HttpClient,
+    retry_policy: &RetryPolicy,
+    revoke_url: &str,
+    token: &str,
+) -> Result<(), Error> {
+    let body = form_body(&[("token", token)]);
+    let response = request_with_retry(client, retry_policy, || {
+        Ok(Request::builder()
+            .method(Method::POST)
+            .uri(revoke_url)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(body.clone()))?)
+    })
+    .await?;
+    if !response.status().is_success() {
+        return Err(error_from_response(response).await);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::http_client::default_http_client;
+    use crate::mock_server;
+    use crate::revoke::revoke;
+    use crate::token_source::retry::RetryPolicy;
+    use hyper::{Body, Response, StatusCode};
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let host = mock_server::serve(|_, body| match body.as_ref() {
+            b"token=1%2F%2Frefresh" => Response::new(Body::empty()),
+            _ => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(
+                    r#"{"error":"invalid_token","error_description":"Token expired or revoked"}"#,
+                ))
+                .unwrap(),
+        })
+        .await;
+        let url = format!("http://{}/revoke", host);
+        let client = default_http_client();
+        let policy = RetryPolicy::default();
+
+        revoke(client.as_ref(), &policy, &url, "1//refresh").await.unwrap();
+        match revoke(client.as_ref(), &policy, &url, "unknown").await {
+            Err(Error::TokenEndpoint { status, error, .. }) => {
+                assert_eq!(StatusCode::BAD_REQUEST, status);
+                assert_eq!("invalid_token", error);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::credentials::CredentialsFile;
+use crate::error::Error;
+use crate::lru::Lru;
+use crate::token::Token;
+use crate::token_source::TokenSource;
+use crate::{create_token_source, Config};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CAPACITY: usize = 16;
+
+// ScopedTokenProvider hands out the cached token source per set of scopes,
+// so that each API is called with the narrowest scopes instead of the broadest one.
+// The sources share the credentials found once and the HTTP client of the config.
+// The least recently used source is evicted when the number of the scope sets exceeds the capacity.
+pub struct ScopedTokenProvider {
+    config: Config,
+    sources: Mutex<Lru<Arc<dyn TokenSource>>>,
+}
+
+impl ScopedTokenProvider {
+    // Finds the credentials unless they are specified in the config.
+    // On GCE without the credentials, the sources get the tokens from the metadata server.
+    pub async fn new(config: Config) -> Result<ScopedTokenProvider, Error> {
+        let mut config = config;
+        if config.credentials.is_none() {
+            config.credentials = CredentialsFile::new_with_gcloud_account(config.gcloud_account.as_deref())
+                .await
+                .ok();
+        }
+        config.http_client = Some(config.http_client()?);
+        Ok(ScopedTokenProvider {
+            config,
+            sources: Mutex::new(Lru::new(DEFAULT_CAPACITY)),
+        })
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.sources = Mutex::new(Lru::new(capacity));
+        self
+    }
+
+    // Returns the token source for the scopes. The order and the duplicates of the scopes don't matter.
+    pub async fn token_source(&self, scopes: &[&str]) -> Result<Arc<dyn TokenSource>, Error> {
+        let mut scopes = scopes.to_vec();
+        scopes.sort_unstable();
+        scopes.dedup();
+        let key = scopes.join(" ");
+
+        if let Some(source) = self.sources.lock().unwrap().get(&key) {
+            return Ok(source);
+        }
+        // the lock is not held while creating the source, which fetches the first token,
+        // so that the slow scopes don't block the others.
+        let source = create_token_source(self.config.clone().with_scopes(&scopes)).await?;
+        let mut sources = self.sources.lock().unwrap();
+        // another caller might have created the source of the same scopes meanwhile.
+        if let Some(source) = sources.get(&key) {
+            return Ok(source);
+        }
+        sources.insert(&key, source.clone());
+        Ok(source)
+    }
+
+    pub async fn token(&self, scopes: &[&str]) -> Result<Token, Error> {
+        self.token_source(scopes).await?.token().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::mock_server::claims;
+    use crate::scoped_token_provider::ScopedTokenProvider;
+    use crate::Config;
+    use std::sync::Arc;
+
+    const STORAGE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+    const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+    const SPANNER: &str = "https://www.googleapis.com/auth/spanner.data";
+
+    async fn provider() -> ScopedTokenProvider {
+        let credentials = CredentialsFile::new_from_str(include_str!("../testdata/service_account.json")).unwrap();
+        let config = Config::default()
+            .with_credentials(credentials)
+            .with_use_self_signed_jwt(true);
+        ScopedTokenProvider::new(config).await.unwrap().with_capacity(2)
+    }
+
+    #[tokio::test]
+    async fn test_token_per_scopes() {
+        let provider = provider().await;
+        let storage = provider.token(&[STORAGE]).await.unwrap();
+        assert_eq!(STORAGE, claims(&storage.access_token)["scope"]);
+
+        let iam = provider.token(&[CLOUD_PLATFORM]).await.unwrap();
+        assert_eq!(CLOUD_PLATFORM, claims(&iam.access_token)["scope"]);
+
+        let source = provider.token_source(&[STORAGE, CLOUD_PLATFORM]).await.unwrap();
+        let same = provider
+            .token_source(&[CLOUD_PLATFORM, STORAGE, CLOUD_PLATFORM])
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&source, &same));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_token_source() {
+        let provider = Arc::new(provider().await);
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let provider = provider.clone();
+                tokio::spawn(async move { provider.token_source(&[STORAGE]).await.unwrap() })
+            })
+            .collect();
+        let mut sources = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            sources.push(task.await.unwrap());
+        }
+        // the callers which created the source at the same time get the one stored first.
+        let stored = provider.token_source(&[STORAGE]).await.unwrap();
+        assert_eq!(1, provider.sources.lock().unwrap().len());
+        assert!(sources.iter().all(|source| Arc::ptr_eq(source, &stored)));
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let provider = provider().await;
+        let storage = provider.token_source(&[STORAGE]).await.unwrap();
+        let iam = provider.token_source(&[CLOUD_PLATFORM]).await.unwrap();
+
+        // storage is used recently, so iam is evicted.
+        assert!(Arc::ptr_eq(&storage, &provider.token_source(&[STORAGE]).await.unwrap()));
+        provider.token_source(&[SPANNER]).await.unwrap();
+        assert_eq!(2, provider.sources.lock().unwrap().len());
+
+        assert!(Arc::ptr_eq(&storage, &provider.token_source(&[STORAGE]).await.unwrap()));
+        assert!(!Arc::ptr_eq(&iam, &provider.token_source(&[CLOUD_PLATFORM]).await.unwrap()));
+    }
+}
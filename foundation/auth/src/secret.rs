@@ -0,0 +1,91 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+const REDACTED: &str = "<redacted>";
+
+// Secret is the string such as the private key, the client secret and the refresh token.
+// It is masked in the Debug output and overwritten with zeros when dropped, including the spare capacity.
+#[derive(Clone, Default, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: &str) -> Secret {
+        Secret(value.to_string())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Secret {
+        Secret::new(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+// Masks the token for the Debug output, leaving only the last 4 characters to tell the tokens apart.
+// The short tokens are masked entirely.
+pub(crate) fn mask(token: &str) -> String {
+    let count = token.chars().count();
+    if count < 16 {
+        return REDACTED.to_string();
+    }
+    let last: String = token.chars().skip(count - 4).collect();
+    format!("...{}", last)
+}
+
+pub(crate) fn mask_option(token: &Option<String>) -> Option<String> {
+    token.as_deref().map(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::{mask, Secret};
+
+    #[test]
+    fn test_debug() {
+        let secret = Secret::new("my-client-secret");
+        assert_eq!("<redacted>", format!("{:?}", secret));
+        assert_eq!("my-client-secret", secret.expose());
+        assert_eq!("Some(<redacted>)", format!("{:?}", Some(secret)));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let secret: Secret = json::from_str(r#""refresh""#).unwrap();
+        assert_eq!("refresh", secret.expose());
+        assert_eq!(r#""refresh""#, json::to_string(&secret).unwrap());
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!("...wxyz", mask("ya29.abcdefghijklmnopqrstuvwxyz"));
+        assert_eq!("<redacted>", mask("short"));
+    }
+}
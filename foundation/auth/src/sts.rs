@@ -0,0 +1,248 @@
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::secret::{mask, mask_option};
+use crate::token::Token;
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{form_body, ResponseExtension};
+use hyper::http::{Method, Request};
+use hyper::Body;
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub const STS_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
+pub const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+// ExchangeRequest is the OAuth 2.0 token exchange request of the Security Token Service.
+// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+#[derive(Clone)]
+pub struct ExchangeRequest {
+    pub grant_type: String,
+    pub audience: Option<String>,
+    pub scopes: Vec<String>,
+    pub subject_token: String,
+    pub subject_token_type: String,
+    pub actor_token: Option<String>,
+    pub actor_token_type: Option<String>,
+    pub requested_token_type: String,
+    // Sent as the JSON encoded options parameter, such as the credential access boundary.
+    pub options: Option<json::Value>,
+    // Sent as the basic authentication header when both are set.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+impl std::fmt::Debug for ExchangeRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExchangeRequest")
+            .field("grant_type", &self.grant_type)
+            .field("audience", &self.audience)
+            .field("scopes", &self.scopes)
+            .field("subject_token", &mask(&self.subject_token))
+            .field("subject_token_type", &self.subject_token_type)
+            .field("actor_token", &mask_option(&self.actor_token))
+            .field("actor_token_type", &self.actor_token_type)
+            .field("requested_token_type", &self.requested_token_type)
+            .field("options", &self.options)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &self.client_secret.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl ExchangeRequest {
+    pub fn new(subject_token: &str, subject_token_type: &str) -> ExchangeRequest {
+        ExchangeRequest {
+            grant_type: TOKEN_EXCHANGE_GRANT_TYPE.to_string(),
+            audience: None,
+            scopes: vec![],
+            subject_token: subject_token.to_string(),
+            subject_token_type: subject_token_type.to_string(),
+            actor_token: None,
+            actor_token_type: None,
+            requested_token_type: ACCESS_TOKEN_TYPE.to_string(),
+            options: None,
+            client_id: None,
+            client_secret: None,
+        }
+    }
+
+    fn form_body(&self) -> Result<String, Error> {
+        let scope = self.scopes.join(" ");
+        let options = self.options.as_ref().map(json::to_string).transpose()?;
+        let params = [
+            ("actor_token", self.actor_token.as_deref()),
+            ("actor_token_type", self.actor_token_type.as_deref()),
+            ("audience", self.audience.as_deref()),
+            ("grant_type", Some(self.grant_type.as_str())),
+            ("options", options.as_deref()),
+            ("requested_token_type", Some(self.requested_token_type.as_str())),
+            ("scope", Some(scope.as_str()).filter(|s| !s.is_empty())),
+            ("subject_token", Some(self.subject_token.as_str())),
+            ("subject_token_type", Some(self.subject_token_type.as_str())),
+        ];
+        let params: Vec<(&str, &str)> = params.iter().filter_map(|(k, v)| v.map(|v| (*k, v))).collect();
+        Ok(form_body(&params))
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ExchangeResponse {
+    pub access_token: String,
+    pub issued_token_type: Option<String>,
+    pub token_type: String,
+    pub expires_in: Option<i64>,
+    pub refresh_token: Option<String>,
+}
+
+impl std::fmt::Debug for ExchangeResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExchangeResponse")
+            .field("access_token", &mask(&self.access_token))
+            .field("issued_token_type", &self.issued_token_type)
+            .field("token_type", &self.token_type)
+            .field("expires_in", &self.expires_in)
+            .field("refresh_token", &mask_option(&self.refresh_token))
+            .finish()
+    }
+}
+
+impl ExchangeResponse {
+    pub fn to_token(&self, now: chrono::DateTime<chrono::Utc>) -> Token {
+        Token {
+            access_token: self.access_token.clone(),
+            token_type: self.token_type.clone(),
+            expiry: self.expires_in.map(|s| now + chrono::Duration::seconds(s)),
+            id_token: None,
+        }
+    }
+}
+
+// StsClient sends the token exchange requests to the Security Token Service.
+#[derive(Clone)]
+pub struct StsClient {
+    token_url: String,
+    retry_policy: RetryPolicy,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl Default for StsClient {
+    fn default() -> Self {
+        StsClient::new(STS_TOKEN_URL)
+    }
+}
+
+impl StsClient {
+    pub fn new(token_url: &str) -> StsClient {
+        StsClient {
+            token_url: token_url.to_string(),
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub async fn exchange_token(&self, request: &ExchangeRequest) -> Result<ExchangeResponse, Error> {
+        let body = request.form_body()?;
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .uri(self.token_url.as_str())
+                .header("Content-Type", "application/x-www-form-urlencoded");
+            if let (Some(id), Some(secret)) = (&request.client_id, &request.client_secret) {
+                let credentials = base64::encode(format!("{}:{}", id, secret));
+                builder = builder.header("Authorization", format!("Basic {}", credentials));
+            }
+            Ok(builder.body(Body::from(body.clone()))?)
+        })
+        .await?;
+        response.deserialize().await
+    }
+}
+
+// Exchanges the token at https://sts.googleapis.com/v1/token.
+pub async fn exchange_token(request: &ExchangeRequest) -> Result<ExchangeResponse, Error> {
+    StsClient::default().exchange_token(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::mock_server;
+    use crate::sts::{ExchangeRequest, StsClient, ACCESS_TOKEN_TYPE};
+    use crate::token_source::retry::RetryPolicy;
+    use hyper::{Body, Response, StatusCode};
+
+    #[test]
+    fn test_form_body() {
+        let mut request = ExchangeRequest::new("subject", "urn:ietf:params:oauth:token-type:jwt");
+        assert_eq!(
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+            &requested_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token\
+            &subject_token=subject\
+            &subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt",
+            request.form_body().unwrap()
+        );
+
+        request.audience = Some("//iam".to_string());
+        request.scopes = vec!["a".to_string(), "b".to_string()];
+        request.actor_token = Some("actor".to_string());
+        request.actor_token_type = Some(ACCESS_TOKEN_TYPE.to_string());
+        request.options = Some(json::json!({"userProject": "p"}));
+        let body = request.form_body().unwrap();
+        assert!(body.starts_with("actor_token=actor&actor_token_type=urn"));
+        assert!(body.contains("&audience=%2F%2Fiam&"));
+        assert!(body.contains("&options=%7B%22userProject%22%3A%22p%22%7D&"));
+        assert!(body.contains("&scope=a%20b&"));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token() {
+        let host = mock_server::serve(|req, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            match body.contains("subject_token=valid") {
+                true => {
+                    assert_eq!(
+                        format!("Basic {}", base64::encode("id:secret")),
+                        req.headers.get("Authorization").unwrap().to_str().unwrap()
+                    );
+                    Response::new(Body::from(
+                        r#"{"access_token":"exchanged","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+                    ))
+                }
+                false => Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(
+                        r#"{"error":"invalid_grant","error_description":"The subject token is invalid."}"#,
+                    ))
+                    .unwrap(),
+            }
+        })
+        .await;
+
+        let client = StsClient::new(&format!("http://{}/v1/token", host)).with_retry_policy(RetryPolicy::no_retry());
+        let mut request = ExchangeRequest::new("valid", ACCESS_TOKEN_TYPE);
+        request.client_id = Some("id".to_string());
+        request.client_secret = Some("secret".to_string());
+        let response = client.exchange_token(&request).await.unwrap();
+        assert_eq!("exchanged", response.access_token);
+        assert_eq!(Some(ACCESS_TOKEN_TYPE), response.issued_token_type.as_deref());
+        assert!(response.to_token(chrono::Utc::now()).expiry.is_some());
+
+        let request = ExchangeRequest::new("invalid", ACCESS_TOKEN_TYPE);
+        match client.exchange_token(&request).await {
+            Err(Error::TokenEndpoint { error, .. }) => assert_eq!("invalid_grant", error),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// The token sources for the tests of this crate and the crates using it, which are enabled by the testing feature.
+// They implement the same TokenSource trait, so they can be shared as Arc<dyn TokenSource> across the tasks.
+use crate::error::Error;
+use crate::token::Token;
+use crate::token_source::TokenSource;
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub use crate::clock::FakeClock;
+pub use crate::token_source::static_token_source::StaticTokenSource;
+
+// FailingTokenSource fails with the error made by the function on every call, because Error is not Clone.
+pub struct FailingTokenSource {
+    error: Box<dyn Fn() -> Error + Send + Sync>,
+    delay: Duration,
+    calls: AtomicUsize,
+}
+
+impl FailingTokenSource {
+    pub fn new(error: impl Fn() -> Error + Send + Sync + 'static) -> FailingTokenSource {
+        FailingTokenSource {
+            error: Box::new(error),
+            delay: Duration::ZERO,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    // Fails after the delay, for example to test the concurrent callers waiting for the failing request.
+    pub fn with_delay(mut self, delay: Duration) -> FailingTokenSource {
+        self.delay = delay;
+        self
+    }
+
+    // Fails with 503 of the token endpoint, which the chain and the retries treat as transient.
+    pub fn unavailable() -> FailingTokenSource {
+        Self::new(|| Error::TokenEndpoint {
+            status: hyper::StatusCode::SERVICE_UNAVAILABLE,
+            error: "unavailable".to_string(),
+            description: None,
+            uri: None,
+        })
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl TokenSource for FailingTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        Err((self.error)())
+    }
+}
+
+// CountingTokenSource returns the new token on every call, token-1, token-2 and so on,
+// which expires after the lifetime of 1 hour by default.
+pub struct CountingTokenSource {
+    lifetime: chrono::Duration,
+    delay: Duration,
+    calls: AtomicUsize,
+}
+
+impl Default for CountingTokenSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CountingTokenSource {
+    pub fn new() -> CountingTokenSource {
+        CountingTokenSource {
+            lifetime: chrono::Duration::hours(1),
+            delay: Duration::ZERO,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_lifetime(mut self, lifetime: chrono::Duration) -> CountingTokenSource {
+        self.lifetime = lifetime;
+        self
+    }
+
+    // Returns the token after the delay, for example to test the concurrent callers waiting for the refresh.
+    pub fn with_delay(mut self, delay: Duration) -> CountingTokenSource {
+        self.delay = delay;
+        self
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl TokenSource for CountingTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let count = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        Ok(Token {
+            access_token: format!("token-{}", count),
+            token_type: "Bearer".to_string(),
+            expiry: Some(chrono::Utc::now() + self.lifetime),
+            id_token: None,
+        })
+    }
+}
+
+// The call of the token source recorded by RecordingTokenSource.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    Token,
+    Refresh,
+    // The access token which the caller invalidated.
+    Invalidate(String),
+}
+
+// RecordingTokenSource wraps the token source and records the calls.
+// TokenSource has no scopes, so the scopes which the source is created for are recorded instead, for example by
+// the fake of the factory which creates the source for the scopes requested by the code under test.
+pub struct RecordingTokenSource<T> {
+    inner: T,
+    scopes: Vec<String>,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl<T: TokenSource> RecordingTokenSource<T> {
+    pub fn new(inner: T) -> RecordingTokenSource<T> {
+        Self::new_with_scopes(inner, &[])
+    }
+
+    pub fn new_with_scopes(inner: T, scopes: &[&str]) -> RecordingTokenSource<T> {
+        RecordingTokenSource {
+            inner,
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    // Returns the number of the calls of token and refresh, each of which returns a token.
+    pub fn token_calls(&self) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| matches!(c, Call::Token | Call::Refresh))
+            .count()
+    }
+
+    fn record(&self, call: Call) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[async_trait]
+impl<T: TokenSource> TokenSource for RecordingTokenSource<T> {
+    async fn token(&self) -> Result<Token, Error> {
+        self.record(Call::Token);
+        self.inner.token().await
+    }
+
+    async fn refresh(&self) -> Result<Token, Error> {
+        self.record(Call::Refresh);
+        self.inner.refresh().await
+    }
+
+    fn invalidate(&self, token: &Token) {
+        self.record(Call::Invalidate(token.access_token.clone()));
+        self.inner.invalidate(token)
+    }
+
+    fn revoked(&self) -> bool {
+        self.inner.revoked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::testing::{Call, CountingTokenSource, FailingTokenSource, RecordingTokenSource, StaticTokenSource};
+    use crate::token_source::{TokenSource, TokenSourceExt};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_recording() {
+        let expiry = chrono::Utc::now() + chrono::Duration::hours(1);
+        let ts = Arc::new(RecordingTokenSource::new_with_scopes(
+            StaticTokenSource::new("static").with_expiry(expiry),
+            &["https://www.googleapis.com/auth/devstorage.read_only"],
+        ));
+        let cached = ts.clone().cached();
+        let token = cached.token().await.unwrap();
+        assert_eq!(Some(expiry), token.expiry);
+        cached.token().await.unwrap();
+        cached.invalidate(&token);
+        cached.refresh().await.unwrap();
+
+        // the cached token is not requested again.
+        assert_eq!(vec![Call::Token, Call::Token], ts.calls());
+        assert_eq!(2, ts.token_calls());
+        assert_eq!(["https://www.googleapis.com/auth/devstorage.read_only"], ts.scopes());
+
+        ts.invalidate(&token);
+        assert_eq!(Call::Invalidate("static".to_string()), ts.calls()[2]);
+    }
+
+    #[tokio::test]
+    async fn test_failing() {
+        let ts = FailingTokenSource::new(|| Error::ScopeOrAudienceRequired);
+        assert!(matches!(ts.token().await, Err(Error::ScopeOrAudienceRequired)));
+        assert!(matches!(ts.token().await, Err(Error::ScopeOrAudienceRequired)));
+        assert_eq!(2, ts.calls());
+
+        // the sources are shared across the tasks as the trait object.
+        let shared: Arc<dyn TokenSource> = Arc::new(FailingTokenSource::unavailable());
+        let result = tokio::spawn(async move { shared.token().await }).await.unwrap();
+        assert!(matches!(result, Err(Error::TokenEndpoint { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_counting() {
+        let ts = CountingTokenSource::new().with_lifetime(chrono::Duration::minutes(5));
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        let token = ts.token().await.unwrap();
+        assert_eq!("token-2", token.access_token);
+        assert!(token.expiry.unwrap() <= chrono::Utc::now() + chrono::Duration::minutes(5));
+        assert_eq!(2, ts.calls());
+    }
+}
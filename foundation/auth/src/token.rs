@@ -1,32 +1,126 @@
+use crate::secret::{mask, mask_option};
 use chrono::DateTime;
 
 pub const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
-pub const AUTH_URL: &str = "https://accounts.gen.com/o/oauth2/auth";
+pub const AUTH_URL: &str = "https://accounts.google.com/o/oauth2/auth";
 
-#[derive(Debug, Clone)]
+// Tokens are treated as expired this long before the actual expiry
+// so that they don't expire on the way to the server.
+pub(crate) const EXPIRY_SKEW_SECONDS: i64 = 10;
+
+#[derive(Clone)]
 pub struct Token {
     pub access_token: String,
     pub token_type: String,
     pub expiry: Option<DateTime<chrono::Utc>>,
+    // The ID token returned with the access token, for example with the openid scope.
+    pub id_token: Option<String>,
+}
+
+// Only the last 4 characters of the access token are shown.
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Token")
+            .field("access_token", &mask(&self.access_token))
+            .field("token_type", &self.token_type)
+            .field("expiry", &self.expiry)
+            .field("id_token", &mask_option(&self.id_token))
+            .finish()
+    }
 }
 
 impl Token {
+    #[deprecated(note = "use header_value")]
     pub fn value(&self) -> String {
-        return format!("Bearer {}", self.access_token);
+        self.header_value()
     }
 
+    // Returns the value of the Authorization header.
+    pub fn header_value(&self) -> String {
+        format!("Bearer {}", self.access_token)
+    }
+
+    // Returns false if the token is empty or expired. The token without expiry never expires.
     pub fn valid(&self) -> bool {
-        !self.access_token.is_empty() && !self.expired()
+        !self.access_token.is_empty() && !self.expires_within(chrono::Duration::zero())
+    }
+
+    // Returns false if the expiry of the token is unknown, in which case the token must not be reused.
+    pub fn cacheable(&self) -> bool {
+        self.expiry.is_some()
+    }
+
+    // Returns true if the token expires within the duration.
+    pub fn expires_within(&self, duration: chrono::Duration) -> bool {
+        self.expires_within_at(chrono::Utc::now(), duration)
     }
 
-    fn expired(&self) -> bool {
+    // Same as expires_within, but with the current time given by the clock.
+    pub fn expires_within_at(&self, now: DateTime<chrono::Utc>, duration: chrono::Duration) -> bool {
         match self.expiry {
             None => false,
-            Some(s) => {
-                let now = chrono::Utc::now();
-                let exp = s + chrono::Duration::seconds(-10);
-                now > exp
+            Some(expiry) => {
+                let skew = chrono::Duration::seconds(EXPIRY_SKEW_SECONDS);
+                now + duration + skew >= expiry
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::token::Token;
+
+    fn token(expiry: Option<chrono::Duration>) -> Token {
+        Token {
+            access_token: "abc".to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: expiry.map(|d| chrono::Utc::now() + d),
+            id_token: None,
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        assert!(token(None).valid());
+        assert!(token(Some(chrono::Duration::minutes(1))).valid());
+        assert!(!token(Some(chrono::Duration::seconds(5))).valid());
+        assert!(!token(Some(chrono::Duration::seconds(-1))).valid());
+
+        let mut empty = token(None);
+        empty.access_token.clear();
+        assert!(!empty.valid());
+    }
+
+    #[test]
+    fn test_expires_within() {
+        let minutes = chrono::Duration::minutes;
+        assert!(!token(None).expires_within(minutes(60)));
+        assert!(token(Some(minutes(5))).expires_within(minutes(5)));
+        assert!(!token(Some(minutes(5))).expires_within(minutes(4)));
+
+        let now = chrono::Utc::now();
+        assert!(token(Some(minutes(5))).expires_within_at(now + minutes(5), minutes(0)));
+        assert!(!token(Some(minutes(5))).expires_within_at(now - minutes(5), minutes(5)));
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut token = token(None);
+        token.access_token = "ya29.a0ARrdaM-secret-part-1234".to_string();
+        let debug = format!("{:?}", token);
+        assert!(debug.contains(r#"access_token: "...1234""#), "{}", debug);
+        assert!(!debug.contains("secret"), "{}", debug);
+    }
+
+    #[test]
+    fn test_cacheable() {
+        assert!(token(Some(chrono::Duration::minutes(1))).cacheable());
+        assert!(!token(None).cacheable());
+    }
+
+    #[test]
+    fn test_header_value() {
+        assert_eq!("Bearer abc", token(None).header_value());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{error_from_response, ResponseExtension};
+use chrono::TimeZone;
+use hyper::http::{Method, Request, StatusCode};
+use hyper::Body;
+use serde::{de, Deserialize, Deserializer};
+
+pub const TOKEN_INFO_URL: &str = "https://www.googleapis.com/oauth2/v3/tokeninfo";
+
+// TokenInfo is the information of the access token returned by the tokeninfo endpoint.
+#[derive(Clone, Debug)]
+pub struct TokenInfo {
+    pub scopes: Vec<String>,
+    pub audience: Option<String>,
+    // The client the token was issued to.
+    pub issued_to: Option<String>,
+    pub subject: Option<String>,
+    pub expiry: Option<chrono::DateTime<chrono::Utc>>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+// The numbers and the booleans are returned as the strings.
+#[derive(Deserialize)]
+struct TokenInfoResponse {
+    azp: Option<String>,
+    aud: Option<String>,
+    sub: Option<String>,
+    scope: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    exp: Option<i64>,
+    email: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    email_verified: Option<bool>,
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        String(String),
+        Other(json::Value),
+    }
+    let value = match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(s)) => s,
+        Some(Value::Other(v)) => v.to_string(),
+        None => return Ok(None),
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("invalid value {}", value)))
+}
+
+// Returns the information of the access token.
+// The expired or revoked token results in Error::InvalidToken.
+pub async fn token_info(access_token: &str) -> Result<TokenInfo, Error> {
+    token_info_with_client(default_http_client().as_ref(), access_token).await
+}
+
+// Same as token_info, but sends the request with the client, for example the one given to Config::with_http_client.
+pub async fn token_info_with_client(client: &dyn HttpClient, access_token: &str) -> Result<TokenInfo, Error> {
+    token_info_from(client, &RetryPolicy::default(), TOKEN_INFO_URL, access_token).await
+}
+
+pub(crate) async fn token_info_from(
+    client: &dyn HttpClient,
+    retry_policy: &RetryPolicy,
+    token_info_url: &str,
+    access_token: &str,
+) -> Result<TokenInfo, Error> {
+    let uri = format!("{}?access_token={}", token_info_url, urlencoding::encode(access_token));
+    let response = request_with_retry(client, retry_policy, || {
+        Ok(Request::builder()
+            .method(Method::GET)
+            .uri(uri.as_str())
+            .body(Body::empty())?)
+    })
+    .await?;
+    if response.status() == StatusCode::BAD_REQUEST {
+        return Err(match error_from_response(response).await {
+            Error::TokenEndpoint { error, description, .. } => Error::InvalidToken(description.unwrap_or(error)),
+            e => e,
+        });
+    }
+    let response: TokenInfoResponse = response.deserialize().await?;
+    Ok(TokenInfo {
+        scopes: response
+            .scope
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect(),
+        audience: response.aud,
+        issued_to: response.azp,
+        subject: response.sub,
+        expiry: response.exp.and_then(|exp| chrono::Utc.timestamp_opt(exp, 0).single()),
+        email: response.email,
+        email_verified: response.email_verified.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::http_client::default_http_client;
+    use crate::mock_server;
+    use crate::token_info::token_info_from;
+    use crate::token_source::retry::RetryPolicy;
+    use hyper::{Body, Response, StatusCode};
+
+    #[tokio::test]
+    async fn test_token_info() {
+        let host = mock_server::serve(|req, _| match req.uri.query() {
+            Some("access_token=ya29.valid%2Btoken") => Response::new(Body::from(
+                r#"{"azp":"client.apps.googleusercontent.com","aud":"client.apps.googleusercontent.com","sub":"1234","scope":"https://www.googleapis.com/auth/userinfo.email openid","exp":"1893456000","expires_in":"3599","email":"user@example.com","email_verified":"true","access_type":"offline"}"#,
+            )),
+            _ => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(r#"{"error":"invalid_token","error_description":"Invalid Value"}"#))
+                .unwrap(),
+        })
+        .await;
+        let url = format!("http://{}/oauth2/v3/tokeninfo", host);
+        let client = default_http_client();
+        let policy = RetryPolicy::default();
+
+        let info = token_info_from(client.as_ref(), &policy, &url, "ya29.valid+token")
+            .await
+            .unwrap();
+        assert_eq!(vec!["https://www.googleapis.com/auth/userinfo.email", "openid"], info.scopes);
+        assert_eq!(Some("client.apps.googleusercontent.com"), info.issued_to.as_deref());
+        assert_eq!(Some("client.apps.googleusercontent.com"), info.audience.as_deref());
+        assert_eq!(1893456000, info.expiry.unwrap().timestamp());
+        assert_eq!(Some("user@example.com"), info.email.as_deref());
+        assert!(info.email_verified);
+
+        match token_info_from(client.as_ref(), &policy, &url, "expired").await {
+            Err(Error::InvalidToken(description)) => assert_eq!("Invalid Value", description),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,71 +1,222 @@
 use crate::credentials;
 use crate::error::Error;
-use crate::misc::{UnwrapOrEmpty, EMPTY};
+use crate::http_client::{default_http_client, HttpClient};
+use crate::misc::EMPTY;
+use crate::revoke::{revoke, REVOKE_URL};
+use crate::secret::Secret;
 use crate::token::{Token, TOKEN_URL};
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
 use crate::token_source::TokenSource;
-use crate::token_source::{default_https_client, InternalToken, ResponseExtension};
+use crate::token_source::{error_from_response, form_body, InternalToken, ResponseExtension};
 use async_trait::async_trait;
-use hyper::client::HttpConnector;
-use hyper::http::{Method, Request};
-use hyper::{Body, Client};
+use hyper::http::{Method, Request, StatusCode};
+use hyper::Body;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct UserAccountTokenSource {
     client_id: String,
-    client_secret: String,
+    client_secret: Secret,
     token_url: String,
     #[allow(dead_code)]
     redirect_url: String,
-    refresh_token: String,
+    refresh_token: Secret,
+    revoke_url: String,
+    revoked: AtomicBool,
+    retry_policy: RetryPolicy,
 
-    client: Client<hyper_tls::HttpsConnector<HttpConnector>>,
+    client: Arc<dyn HttpClient>,
 }
 
 impl UserAccountTokenSource {
-    pub(crate) fn new(cred: &credentials::CredentialsFile) -> Result<UserAccountTokenSource, Error> {
-        if cred.refresh_token.is_none() {
-            return Err(Error::RefreshTokenIsRequired);
-        }
+    pub fn new(cred: &credentials::CredentialsFile) -> Result<UserAccountTokenSource, Error> {
+        cred.ensure_type(credentials::USER_CREDENTIALS_KEY)?;
+        let refresh_token = cred.required("refresh_token", &cred.refresh_token)?.clone();
 
         let ts = UserAccountTokenSource {
-            client_id: cred.client_id.unwrap_or_empty(),
-            client_secret: cred.client_secret.unwrap_or_empty(),
+            client_id: cred.required_str("client_id", &cred.client_id)?.to_string(),
+            client_secret: cred.required("client_secret", &cred.client_secret)?.clone(),
             token_url: match &cred.token_uri {
                 None => TOKEN_URL.to_string(),
                 Some(s) => s.to_string(),
             },
             redirect_url: EMPTY.to_string(),
-            refresh_token: cred.refresh_token.unwrap_or_empty(),
-            client: default_https_client(),
+            refresh_token,
+            revoke_url: REVOKE_URL.to_string(),
+            revoked: AtomicBool::new(false),
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
         };
         Ok(ts)
     }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Revokes the refresh token, for example when the user logs out.
+    // The token source and the cache wrapping it fail with TokenRevoked after the revocation.
+    pub async fn revoke(&self) -> Result<(), Error> {
+        revoke(
+            self.client.as_ref(),
+            &self.retry_policy,
+            &self.revoke_url,
+            self.refresh_token.expose(),
+        )
+        .await?;
+        self.revoked.store(true, Ordering::SeqCst);
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl TokenSource for UserAccountTokenSource {
     async fn token(&self) -> Result<Token, Error> {
-        let data = json::json!({
-            "client_id": self.client_id,
-            "client_secret": self.client_secret,
-            "grant_type": "refresh_token".to_string(),
-            "refresh_token": self.refresh_token,
+        if self.revoked() {
+            return Err(Error::TokenRevoked);
+        }
+        let data = form_body(&[
+            ("client_id", &self.client_id),
+            ("client_secret", self.client_secret.expose()),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", self.refresh_token.expose()),
+        ]);
+
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.token_url.to_string())
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(data.clone()))?)
         })
-        .to_string();
-
-        let request = Request::builder()
-            .method(Method::POST)
-            .uri(self.token_url.to_string())
-            .header("content-type", "application/json")
-            .body(Body::from(data))?;
-
-        let it: InternalToken = self
-            .client
-            .request(request)
-            .await
-            .map_err(Error::HyperError)?
-            .deserialize()
-            .await?;
-
-        return Ok(it.to_token(chrono::Utc::now()));
+        .await?;
+        if response.status() == StatusCode::BAD_REQUEST {
+            return Err(match error_from_response(response).await {
+                Error::TokenEndpoint { error, description, .. } if error == "invalid_grant" => {
+                    Error::RefreshTokenRejected(description.unwrap_or(error))
+                }
+                e => e,
+            });
+        }
+        let it: InternalToken = response.deserialize().await?;
+
+        Ok(it.to_token(chrono::Utc::now()))
+    }
+
+    fn revoked(&self) -> bool {
+        self.revoked.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::mock_server;
+    use crate::token_source::authorized_user_token_source::UserAccountTokenSource;
+    use crate::token_source::{TokenSource, TokenSourceExt};
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn credentials(host: &str) -> CredentialsFile {
+        json::from_value(json::json!({
+            "type": "authorized_user",
+            "client_id": "client-id.apps.googleusercontent.com",
+            "client_secret": "secret&value",
+            "refresh_token": "1//refresh+token",
+            "token_uri": format!("http://{}/token", host),
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let host = mock_server::serve(|req, body| {
+            assert_eq!(
+                "application/x-www-form-urlencoded",
+                req.headers.get("content-type").unwrap()
+            );
+            assert_eq!(
+                "client_id=client-id.apps.googleusercontent.com&client_secret=secret%26value&grant_type=refresh_token&refresh_token=1%2F%2Frefresh%2Btoken",
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(
+                r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer","id_token":"x.y.z"}"#,
+            ))
+        })
+        .await;
+
+        let ts = UserAccountTokenSource::new(&credentials(&host)).unwrap();
+        let token = ts.token().await.unwrap();
+        assert_eq!("abc", token.access_token);
+        assert!(token.expiry.unwrap() > chrono::Utc::now());
+    }
+
+    #[test]
+    fn test_required_fields() {
+        let mut cred = credentials("localhost");
+        cred.refresh_token = None;
+        assert!(matches!(
+            UserAccountTokenSource::new(&cred),
+            Err(Error::MissingCredentialField {
+                field: "refresh_token",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_grant() {
+        let host = mock_server::serve(|_, _| {
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(
+                    r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked."}"#,
+                ))
+                .unwrap()
+        })
+        .await;
+
+        let ts = UserAccountTokenSource::new(&credentials(&host)).unwrap();
+        match ts.token().await {
+            Err(Error::RefreshTokenRejected(description)) => {
+                assert_eq!("Token has been expired or revoked.", description)
+            }
+            other => panic!("unexpected result {:?}", other.map(|t| t.access_token)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let revoked = Arc::new(AtomicUsize::new(0));
+        let counter = revoked.clone();
+        let host = mock_server::serve(move |req, body| match req.uri.path() {
+            "/revoke" => {
+                assert_eq!("token=1%2F%2Frefresh%2Btoken", String::from_utf8(body.to_vec()).unwrap());
+                counter.fetch_add(1, Ordering::SeqCst);
+                Response::new(Body::empty())
+            }
+            _ => Response::new(Body::from(r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#)),
+        })
+        .await;
+
+        let mut ts = UserAccountTokenSource::new(&credentials(&host)).unwrap();
+        ts.revoke_url = format!("http://{}/revoke", host);
+        let ts = Arc::new(ts);
+        let cached = ts.clone().cached();
+        assert_eq!("abc", cached.token().await.unwrap().access_token);
+
+        ts.revoke().await.unwrap();
+        assert_eq!(1, revoked.load(Ordering::SeqCst));
+        // the cached token is not used after the revocation.
+        assert!(matches!(cached.token().await.unwrap_err().root(), Error::TokenRevoked));
+        assert!(matches!(ts.token().await, Err(Error::TokenRevoked)));
     }
 }
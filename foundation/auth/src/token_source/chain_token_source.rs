@@ -0,0 +1,210 @@
+use crate::error::Error;
+use crate::token::Token;
+use crate::token_source::retry::is_transient;
+use crate::token_source::TokenSource;
+use async_trait::async_trait;
+use std::sync::RwLock;
+use tokio::sync::Mutex;
+
+// ChainTokenSource tries the sources in order on the first use and keeps using the first one that succeeds.
+// The chain is evaluated again only when the chosen source fails permanently, for example with invalid_grant.
+// The transient errors such as timeouts are returned as they are without switching the source.
+pub struct ChainTokenSource {
+    sources: Vec<Box<dyn TokenSource>>,
+    chosen: RwLock<Option<usize>>,
+    evaluate_lock: Mutex<()>,
+}
+
+impl ChainTokenSource {
+    pub fn new(sources: Vec<Box<dyn TokenSource>>) -> ChainTokenSource {
+        ChainTokenSource {
+            sources,
+            chosen: RwLock::new(None),
+            evaluate_lock: Mutex::new(()),
+        }
+    }
+
+    // Returns the index of the source in use, or None before the first use.
+    pub fn chosen(&self) -> Option<usize> {
+        *self.chosen.read().unwrap()
+    }
+
+    async fn evaluate(&self, failed: Option<usize>) -> Result<Token, Error> {
+        let _guard = self.evaluate_lock.lock().await;
+        // another task may have chosen the source while we were waiting for the lock.
+        match self.chosen() {
+            Some(index) if Some(index) != failed => return self.sources[index].token().await,
+            _ => {}
+        }
+
+        let mut errors = vec![];
+        for (index, source) in self.sources.iter().enumerate() {
+            if Some(index) == failed {
+                continue;
+            }
+            match source.token().await {
+                Ok(token) => {
+                    *self.chosen.write().unwrap() = Some(index);
+                    return Ok(token);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        *self.chosen.write().unwrap() = None;
+        Err(Error::ChainExhausted(errors))
+    }
+}
+
+#[async_trait]
+impl TokenSource for ChainTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let index = match self.chosen() {
+            Some(index) => index,
+            None => return self.evaluate(None).await,
+        };
+        match self.sources[index].token().await {
+            Err(e) if !is_transient(&e) => self.evaluate(Some(index)).await,
+            result => result,
+        }
+    }
+
+    async fn refresh(&self) -> Result<Token, Error> {
+        let index = match self.chosen() {
+            Some(index) => index,
+            None => return self.evaluate(None).await,
+        };
+        match self.sources[index].refresh().await {
+            Err(e) if !is_transient(&e) => self.evaluate(Some(index)).await,
+            result => result,
+        }
+    }
+
+    fn invalidate(&self, token: &Token) {
+        if let Some(index) = self.chosen() {
+            self.sources[index].invalidate(token)
+        }
+    }
+
+    fn revoked(&self) -> bool {
+        match self.chosen() {
+            Some(index) => self.sources[index].revoked(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::token::Token;
+    use crate::token_source::chain_token_source::ChainTokenSource;
+    use crate::token_source::TokenSource;
+    use async_trait::async_trait;
+    use hyper::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // Fails with the queued errors and then returns the token.
+    struct ScriptedTokenSource {
+        name: &'static str,
+        errors: Mutex<Vec<Error>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    fn source(name: &'static str, errors: Vec<Error>) -> (Box<dyn TokenSource>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source = ScriptedTokenSource {
+            name,
+            errors: Mutex::new(errors),
+            calls: calls.clone(),
+        };
+        (Box::new(source), calls)
+    }
+
+    #[async_trait]
+    impl TokenSource for ScriptedTokenSource {
+        async fn token(&self) -> Result<Token, Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut errors = self.errors.lock().unwrap();
+            if !errors.is_empty() {
+                return Err(errors.remove(0));
+            }
+            Ok(Token {
+                access_token: self.name.to_string(),
+                token_type: "Bearer".to_string(),
+                expiry: None,
+                id_token: None,
+            })
+        }
+    }
+
+    fn invalid_grant() -> Error {
+        Error::TokenEndpoint {
+            status: StatusCode::BAD_REQUEST,
+            error: "invalid_grant".to_string(),
+            description: None,
+            uri: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain() {
+        let (first, first_calls) = source("first", vec![Error::NoPrivateKeyFound]);
+        let (second, second_calls) = source("second", vec![Error::HttpTimeout(std::time::Duration::from_secs(1))]);
+        let (third, third_calls) = source("third", vec![]);
+        let ts = ChainTokenSource::new(vec![first, second, third]);
+        assert_eq!(None, ts.chosen());
+
+        // the first use evaluates the chain.
+        assert_eq!("third", ts.token().await.unwrap().access_token);
+        assert_eq!(Some(2), ts.chosen());
+
+        // the chosen source is used from now on.
+        assert_eq!("third", ts.token().await.unwrap().access_token);
+        assert_eq!(1, first_calls.load(Ordering::SeqCst));
+        assert_eq!(1, second_calls.load(Ordering::SeqCst));
+        assert_eq!(2, third_calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_transient_error_keeps_the_source() {
+        let timeout = Error::HttpTimeout(std::time::Duration::from_secs(1));
+        let (first, _) = source("first", vec![timeout]);
+        let (second, second_calls) = source("second", vec![]);
+        let ts = ChainTokenSource {
+            chosen: std::sync::RwLock::new(Some(0)),
+            ..ChainTokenSource::new(vec![first, second])
+        };
+        assert!(matches!(ts.token().await, Err(Error::HttpTimeout(_))));
+        assert_eq!(Some(0), ts.chosen());
+        assert_eq!("first", ts.token().await.unwrap().access_token);
+        assert_eq!(0, second_calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_permanent_error_evaluates_the_chain_again() {
+        // the chosen source starts failing permanently.
+        let (first, _) = source("first", vec![Error::TokenRevoked]);
+        let (second, _) = source("second", vec![]);
+        let ts = ChainTokenSource {
+            chosen: std::sync::RwLock::new(Some(0)),
+            ..ChainTokenSource::new(vec![first, second])
+        };
+        assert_eq!("second", ts.token().await.unwrap().access_token);
+        assert_eq!(Some(1), ts.chosen());
+    }
+
+    #[tokio::test]
+    async fn test_chain_exhausted() {
+        let (first, _) = source("first", vec![Error::NoPrivateKeyFound]);
+        let (second, _) = source("second", vec![invalid_grant()]);
+        let ts = ChainTokenSource::new(vec![first, second]);
+        match ts.token().await {
+            Err(Error::ChainExhausted(errors)) => assert_eq!(2, errors.len()),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(None, ts.chosen());
+        // the chain is evaluated again on the next use.
+        assert_eq!("first", ts.token().await.unwrap().access_token);
+    }
+}
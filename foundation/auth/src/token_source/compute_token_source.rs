@@ -1,51 +1,181 @@
 use crate::error::Error;
+use crate::http_client::HttpClient;
+use crate::metadata::MetadataClient;
 use crate::token::Token;
 use crate::token_source::TokenSource;
-use crate::token_source::{InternalToken, ResponseExtension};
+use crate::token_source::{error_from_response, jwt_expiry, InternalToken, ResponseExtension};
 use async_trait::async_trait;
-use google_cloud_metadata::{
-    default_http_connector, METADATA_FLAVOR_KEY, METADATA_GOOGLE, METADATA_HOST_ENV, METADATA_IP,
-};
-use hyper::client::Client;
-use hyper::client::HttpConnector;
-use hyper::http::{Method, Request};
+use std::sync::Arc;
 use urlencoding::encode;
 
+const DEFAULT_SERVICE_ACCOUNT: &str = "default";
+
 pub struct ComputeTokenSource {
-    token_url: String,
-    client: hyper::Client<HttpConnector>,
+    path: String,
+    metadata: MetadataClient,
 }
 
 impl ComputeTokenSource {
-    pub(crate) fn new(scope: &str) -> Result<ComputeTokenSource, Error> {
-        let host = match std::env::var(METADATA_HOST_ENV) {
-            Ok(s) => s,
-            Err(_e) => METADATA_IP.to_string(),
-        };
-
-        return Ok(ComputeTokenSource {
-            token_url: format!(
-                "http://{}/computeMetadata/v1/instance/service-accounts/default/token?{}",
-                host,
-                encode(format!("scopes={}", scope).as_str())
-            ),
-            client: Client::builder().build(default_http_connector()),
-        });
+    // scope is a comma separated list of scopes.
+    pub fn new(scope: &str) -> Result<ComputeTokenSource, Error> {
+        Self::new_with_service_account(scope, DEFAULT_SERVICE_ACCOUNT)
+    }
+
+    // service_account is the email or the alias of the service account attached to the instance.
+    pub fn new_with_service_account(scope: &str, service_account: &str) -> Result<ComputeTokenSource, Error> {
+        Ok(Self::new_with_metadata_client(MetadataClient::new(), scope, service_account))
+    }
+
+    pub fn new_with_metadata_client(
+        metadata: MetadataClient,
+        scope: &str,
+        service_account: &str,
+    ) -> ComputeTokenSource {
+        let mut path = format!("instance/service-accounts/{}/token", encode(service_account));
+        if !scope.is_empty() {
+            path = format!("{}?scopes={}", path, encode(scope));
+        }
+        ComputeTokenSource { path, metadata }
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.metadata = self.metadata.with_http_client(client);
+        self
     }
 }
 
 #[async_trait]
 impl TokenSource for ComputeTokenSource {
     async fn token(&self) -> Result<Token, Error> {
-        let body = hyper::Body::empty();
-        let request = Request::builder()
-            .method(Method::GET)
-            .uri(self.token_url.as_str())
-            .header(METADATA_FLAVOR_KEY, METADATA_GOOGLE)
-            .body(body)?;
+        let response = self.metadata.request(&self.path).await?;
+        let it: InternalToken = response.deserialize().await?;
+
+        Ok(it.to_token(chrono::Utc::now()))
+    }
+}
+
+// ComputeIdTokenSource gets the ID token of the service account attached to the instance.
+// https://cloud.google.com/compute/docs/instances/verifying-instance-identity
+pub struct ComputeIdTokenSource {
+    path: String,
+    metadata: MetadataClient,
+}
+
+impl ComputeIdTokenSource {
+    pub fn new(audience: &str) -> Result<ComputeIdTokenSource, Error> {
+        Ok(Self::new_with_metadata_client(MetadataClient::new(), audience))
+    }
+
+    pub fn new_with_metadata_client(metadata: MetadataClient, audience: &str) -> ComputeIdTokenSource {
+        ComputeIdTokenSource {
+            path: format!(
+                "instance/service-accounts/{}/identity?audience={}&format=full",
+                DEFAULT_SERVICE_ACCOUNT,
+                encode(audience)
+            ),
+            metadata,
+        }
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.metadata = self.metadata.with_http_client(client);
+        self
+    }
+}
+
+#[async_trait]
+impl TokenSource for ComputeIdTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let response = self.metadata.request(&self.path).await?;
+        if !response.status().is_success() {
+            return Err(error_from_response(response).await);
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let id_token = String::from_utf8_lossy(&body).trim().to_string();
+        Ok(Token {
+            expiry: jwt_expiry(&id_token),
+            id_token: Some(id_token.clone()),
+            access_token: id_token,
+            token_type: "Bearer".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::metadata::MetadataClient;
+    use crate::mock_server;
+    use crate::token_source::compute_token_source::{ComputeIdTokenSource, ComputeTokenSource};
+    use crate::token_source::TokenSource;
+    use hyper::{Body, Response};
+
+    #[tokio::test]
+    async fn test_token() {
+        let host = mock_server::serve_metadata(|req, _| {
+            assert_eq!("Google", req.headers.get("Metadata-Flavor").unwrap());
+            assert_eq!(
+                "/computeMetadata/v1/instance/service-accounts/sa%40example.com/token",
+                req.uri.path()
+            );
+            assert_eq!(
+                Some("scopes=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform%2Cemail"),
+                req.uri.query()
+            );
+            Response::new(Body::from(r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#))
+        })
+        .await;
+
+        let ts = ComputeTokenSource::new_with_metadata_client(
+            MetadataClient::new_with_host(&host),
+            "https://www.googleapis.com/auth/cloud-platform,email",
+            "sa@example.com",
+        );
+        let token = ts.token().await.unwrap();
+        assert_eq!("abc", token.access_token);
+        assert_eq!("Bearer", token.token_type);
+        assert!(token.expiry.unwrap() > chrono::Utc::now());
+    }
+
+    #[tokio::test]
+    async fn test_token_without_scopes() {
+        let host = mock_server::serve_metadata(|req, _| {
+            assert_eq!(None, req.uri.query());
+            Response::new(Body::from(r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#))
+        })
+        .await;
+        let ts = ComputeTokenSource::new_with_metadata_client(MetadataClient::new_with_host(&host), "", "default");
+        assert_eq!("abc", ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_server_unavailable() {
+        // nothing listens on the discard port.
+        let ts =
+            ComputeTokenSource::new_with_metadata_client(MetadataClient::new_with_host("127.0.0.1:9"), "", "default");
+        match ts.token().await {
+            Err(Error::MetadataServerUnavailable(_)) => {}
+            other => panic!("unexpected result {:?}", other.map(|t| t.access_token)),
+        }
+    }
 
-        let it: InternalToken = self.client.request(request).await?.deserialize().await?;
+    #[tokio::test]
+    async fn test_id_token() {
+        let host = mock_server::serve_metadata(|req, _| {
+            assert_eq!("Google", req.headers.get("Metadata-Flavor").unwrap());
+            assert_eq!("/computeMetadata/v1/instance/service-accounts/default/identity", req.uri.path());
+            assert_eq!(Some("audience=https%3A%2F%2Fexample.run.app&format=full"), req.uri.query());
+            let payload = base64::encode_config(r#"{"exp":1893456000}"#, base64::URL_SAFE_NO_PAD);
+            Response::new(Body::from(format!("header.{}.signature", payload)))
+        })
+        .await;
 
-        return Ok(it.to_token(chrono::Utc::now()));
+        let ts = ComputeIdTokenSource::new_with_metadata_client(
+            MetadataClient::new_with_host(&host),
+            "https://example.run.app",
+        );
+        let token = ts.token().await.unwrap();
+        assert!(token.access_token.starts_with("header."));
+        assert_eq!(1893456000, token.expiry.unwrap().timestamp());
     }
 }
@@ -0,0 +1,288 @@
+use crate::error::Error;
+use crate::http_client::HttpClient;
+use crate::sts::{ExchangeRequest, StsClient, ACCESS_TOKEN_TYPE};
+use crate::token::Token;
+use crate::token_source::retry::RetryPolicy;
+use crate::token_source::TokenSource;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Arc;
+
+// The maximum number of the rules in the credential access boundary.
+const MAX_ACCESS_BOUNDARY_RULES: usize = 10;
+
+// CredentialAccessBoundary restricts the resources and the permissions of the downscoped token.
+// https://cloud.google.com/iam/docs/downscoping-short-lived-credentials
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialAccessBoundary {
+    access_boundary: AccessBoundary,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessBoundary {
+    access_boundary_rules: Vec<AccessBoundaryRule>,
+}
+
+impl CredentialAccessBoundary {
+    pub fn new(rules: Vec<AccessBoundaryRule>) -> CredentialAccessBoundary {
+        CredentialAccessBoundary {
+            access_boundary: AccessBoundary {
+                access_boundary_rules: rules,
+            },
+        }
+    }
+
+    pub fn with_rule(mut self, rule: AccessBoundaryRule) -> CredentialAccessBoundary {
+        self.access_boundary.access_boundary_rules.push(rule);
+        self
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let rules = &self.access_boundary.access_boundary_rules;
+        if rules.is_empty() || rules.len() > MAX_ACCESS_BOUNDARY_RULES {
+            return Err(Error::InvalidAccessBoundary(format!(
+                "between 1 and {} rules are required, but got {}",
+                MAX_ACCESS_BOUNDARY_RULES,
+                rules.len()
+            )));
+        }
+        for rule in rules {
+            if rule.available_resource.is_empty() {
+                return Err(Error::InvalidAccessBoundary("available_resource is required".to_string()));
+            }
+            if rule.available_permissions.is_empty() {
+                return Err(Error::InvalidAccessBoundary(format!(
+                    "available_permissions is required for {}",
+                    rule.available_resource
+                )));
+            }
+            if let Some(condition) = &rule.availability_condition {
+                if condition.expression.is_empty() {
+                    return Err(Error::InvalidAccessBoundary(format!(
+                        "expression of availability_condition is required for {}",
+                        rule.available_resource
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// AccessBoundaryRule grants the permissions to the resource.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessBoundaryRule {
+    available_resource: String,
+    available_permissions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability_condition: Option<AvailabilityCondition>,
+}
+
+impl AccessBoundaryRule {
+    // resource is like //storage.googleapis.com/projects/_/buckets/my-bucket.
+    // permissions are the roles like inRole:roles/storage.objectViewer.
+    pub fn new(resource: &str, permissions: &[&str]) -> AccessBoundaryRule {
+        AccessBoundaryRule {
+            available_resource: resource.to_string(),
+            available_permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            availability_condition: None,
+        }
+    }
+
+    pub fn with_availability_condition(mut self, condition: AvailabilityCondition) -> AccessBoundaryRule {
+        self.availability_condition = Some(condition);
+        self
+    }
+}
+
+// AvailabilityCondition narrows the resource down with the CEL expression,
+// for example resource.name.startsWith('projects/_/buckets/my-bucket/objects/prefix/').
+#[derive(Clone, Debug, Serialize)]
+pub struct AvailabilityCondition {
+    expression: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+impl AvailabilityCondition {
+    pub fn new(expression: &str) -> AvailabilityCondition {
+        AvailabilityCondition {
+            expression: expression.to_string(),
+            title: None,
+            description: None,
+        }
+    }
+
+    pub fn with_title(mut self, title: &str) -> AvailabilityCondition {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn with_description(mut self, description: &str) -> AvailabilityCondition {
+        self.description = Some(description.to_string());
+        self
+    }
+}
+
+// DownscopedTokenSource exchanges the access token of the source for the token restricted by the access boundary.
+pub struct DownscopedTokenSource {
+    source: Arc<dyn TokenSource>,
+    boundary: json::Value,
+
+    sts: StsClient,
+}
+
+impl DownscopedTokenSource {
+    pub fn new(
+        source: Arc<dyn TokenSource>,
+        boundary: CredentialAccessBoundary,
+    ) -> Result<DownscopedTokenSource, Error> {
+        boundary.validate()?;
+        Ok(DownscopedTokenSource {
+            source,
+            boundary: json::to_value(&boundary)?,
+            sts: StsClient::default(),
+        })
+    }
+
+    pub fn with_sts_client(mut self, sts: StsClient) -> Self {
+        self.sts = sts;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.sts = self.sts.with_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.sts = self.sts.with_http_client(client);
+        self
+    }
+}
+
+#[async_trait]
+impl TokenSource for DownscopedTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let source_token = self.source.token().await?;
+        let mut request = ExchangeRequest::new(&source_token.access_token, ACCESS_TOKEN_TYPE);
+        request.options = Some(self.boundary.clone());
+
+        let now = chrono::Utc::now();
+        let response = self.sts.exchange_token(&request).await?;
+        let mut token = response.to_token(now);
+        // the downscoped token without expires_in expires at the same time as the source token.
+        if token.expiry.is_none() {
+            token.expiry = source_token.expiry;
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::mock_server;
+    use crate::sts::StsClient;
+    use crate::testing::StaticTokenSource;
+    use crate::token_source::downscoped_token_source::{
+        AccessBoundaryRule, AvailabilityCondition, CredentialAccessBoundary, DownscopedTokenSource,
+    };
+    use crate::token_source::TokenSource;
+    use hyper::{Body, Response};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn source() -> Arc<StaticTokenSource> {
+        Arc::new(StaticTokenSource::new("source-token").with_expiry(chrono::Utc::now() + chrono::Duration::minutes(30)))
+    }
+
+    fn boundary() -> CredentialAccessBoundary {
+        CredentialAccessBoundary::default().with_rule(
+            AccessBoundaryRule::new(
+                "//storage.googleapis.com/projects/_/buckets/my-bucket",
+                &["inRole:roles/storage.objectViewer"],
+            )
+            .with_availability_condition(
+                AvailabilityCondition::new("resource.name.startsWith('projects/_/buckets/my-bucket/objects/prefix/')")
+                    .with_title("prefix"),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_serialize_boundary() {
+        let expected = json::json!({
+            "accessBoundary": {
+                "accessBoundaryRules": [{
+                    "availableResource": "//storage.googleapis.com/projects/_/buckets/my-bucket",
+                    "availablePermissions": ["inRole:roles/storage.objectViewer"],
+                    "availabilityCondition": {
+                        "expression": "resource.name.startsWith('projects/_/buckets/my-bucket/objects/prefix/')",
+                        "title": "prefix"
+                    }
+                }]
+            }
+        });
+        assert_eq!(expected, json::to_value(boundary()).unwrap());
+    }
+
+    #[test]
+    fn test_validate() {
+        let rule = AccessBoundaryRule::new("//storage.googleapis.com/projects/_/buckets/b", &["inRole:roles/x"]);
+        assert!(CredentialAccessBoundary::new(vec![rule.clone(); 10]).validate().is_ok());
+        for boundary in [
+            CredentialAccessBoundary::default(),
+            CredentialAccessBoundary::new(vec![rule.clone(); 11]),
+            CredentialAccessBoundary::default().with_rule(AccessBoundaryRule::new("", &["inRole:roles/x"])),
+            CredentialAccessBoundary::default().with_rule(AccessBoundaryRule::new("//b", &[])),
+            CredentialAccessBoundary::default()
+                .with_rule(rule.with_availability_condition(AvailabilityCondition::new(""))),
+        ] {
+            assert!(matches!(
+                DownscopedTokenSource::new(source(), boundary),
+                Err(Error::InvalidAccessBoundary(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let host = mock_server::serve(|req, body| {
+            assert_eq!("/v1/token", req.uri.path());
+            let params: HashMap<String, String> = body
+                .split(|b| *b == b'&')
+                .map(|p| {
+                    let p = String::from_utf8(p.to_vec()).unwrap();
+                    let (k, v) = p.split_once('=').unwrap();
+                    (k.to_string(), urlencoding::decode(v).unwrap().to_string())
+                })
+                .collect();
+            assert_eq!("urn:ietf:params:oauth:grant-type:token-exchange", params["grant_type"]);
+            assert_eq!("source-token", params["subject_token"]);
+            assert_eq!("urn:ietf:params:oauth:token-type:access_token", params["subject_token_type"]);
+            let options: json::Value = json::from_str(&params["options"]).unwrap();
+            assert_eq!(json::to_value(boundary()).unwrap(), options);
+            Response::new(Body::from(
+                r#"{"access_token":"downscoped","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer"}"#,
+            ))
+        })
+        .await;
+
+        let source = source();
+        let ts = DownscopedTokenSource::new(source, boundary())
+            .unwrap()
+            .with_sts_client(StsClient::new(&format!("http://{}/v1/token", host)));
+        let token = ts.token().await.unwrap();
+        assert_eq!("downscoped", token.access_token);
+        // the expiry of the source token is used without expires_in.
+        let expiry = token.expiry.unwrap();
+        assert!(expiry > chrono::Utc::now() + chrono::Duration::minutes(29));
+        assert!(expiry <= chrono::Utc::now() + chrono::Duration::minutes(30));
+    }
+}
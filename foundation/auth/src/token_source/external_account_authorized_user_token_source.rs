@@ -0,0 +1,200 @@
+use crate::credentials;
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::secret::Secret;
+use crate::token::Token;
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{error_from_response, form_body, ResponseExtension, TokenSource};
+use async_trait::async_trait;
+use hyper::http::{Method, Request, StatusCode};
+use hyper::Body;
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+
+#[derive(Deserialize)]
+struct RefreshResponse {
+    access_token: String,
+    token_type: Option<String>,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+}
+
+// ExternalAccountAuthorizedUserTokenSource refreshes the token of the user signed in with the workforce identity
+// federation by `gcloud auth application-default login`, against the refresh grant of the Security Token Service.
+// The refresh token is replaced when the response has the new one.
+pub struct ExternalAccountAuthorizedUserTokenSource {
+    client_id: String,
+    client_secret: Secret,
+    token_url: String,
+    refresh_token: RwLock<Secret>,
+    retry_policy: RetryPolicy,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl ExternalAccountAuthorizedUserTokenSource {
+    pub fn new(cred: &credentials::CredentialsFile) -> Result<ExternalAccountAuthorizedUserTokenSource, Error> {
+        cred.ensure_type(credentials::EXTERNAL_ACCOUNT_AUTHORIZED_USER_KEY)?;
+        let refresh_token = cred.required("refresh_token", &cred.refresh_token)?.clone();
+        Ok(ExternalAccountAuthorizedUserTokenSource {
+            client_id: cred.required("client_id", &cred.client_id)?.clone(),
+            client_secret: cred.required("client_secret", &cred.client_secret)?.clone(),
+            token_url: cred.required("token_url", &cred.token_url_external)?.clone(),
+            refresh_token: RwLock::new(refresh_token),
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Returns the current refresh token, which may have been rotated by the Security Token Service.
+    pub fn refresh_token(&self) -> Secret {
+        self.refresh_token.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl TokenSource for ExternalAccountAuthorizedUserTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let refresh_token = self.refresh_token();
+        let data = form_body(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.expose()),
+        ]);
+        let credentials = base64::encode(format!("{}:{}", self.client_id, self.client_secret.expose()));
+
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.token_url.as_str())
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .header("Authorization", format!("Basic {}", credentials))
+                .body(Body::from(data.clone()))?)
+        })
+        .await?;
+        if response.status() == StatusCode::BAD_REQUEST {
+            return Err(match error_from_response(response).await {
+                Error::TokenEndpoint { error, description, .. } if error == "invalid_grant" => {
+                    Error::RefreshTokenRejected(description.unwrap_or(error))
+                }
+                e => e,
+            });
+        }
+        let response: RefreshResponse = response.deserialize().await?;
+        if let Some(rotated) = response.refresh_token.filter(|t| !t.is_empty()) {
+            *self.refresh_token.write().unwrap() = Secret::from(rotated);
+        }
+        Ok(Token {
+            access_token: response.access_token,
+            token_type: response.token_type.unwrap_or_else(|| "Bearer".to_string()),
+            expiry: response
+                .expires_in
+                .map(|s| chrono::Utc::now() + chrono::Duration::seconds(s)),
+            id_token: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::mock_server;
+    use crate::token_source::external_account_authorized_user_token_source::ExternalAccountAuthorizedUserTokenSource;
+    use crate::token_source::TokenSource;
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn credentials(host: &str) -> CredentialsFile {
+        let mut cred: json::Value =
+            json::from_str(include_str!("../../testdata/external_account_authorized_user.json")).unwrap();
+        cred["token_url"] = json::json!(format!("http://{}/v1/oauthtoken", host));
+        json::from_value(cred).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_token_and_rotation() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve(move |req, body| {
+            assert_eq!("/v1/oauthtoken", req.uri.path());
+            assert_eq!(
+                format!("Basic {}", base64::encode("client-id:client-secret")),
+                req.headers["Authorization"]
+            );
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => {
+                    assert_eq!("grant_type=refresh_token&refresh_token=refresh-token", body);
+                    Response::new(Body::from(
+                        r#"{"access_token":"first","expires_in":3600,"refresh_token":"rotated"}"#,
+                    ))
+                }
+                _ => {
+                    assert_eq!("grant_type=refresh_token&refresh_token=rotated", body);
+                    Response::new(Body::from(r#"{"access_token":"second","expires_in":3600}"#))
+                }
+            }
+        })
+        .await;
+
+        let ts = ExternalAccountAuthorizedUserTokenSource::new(&credentials(&host)).unwrap();
+        let token = ts.token().await.unwrap();
+        assert_eq!("first", token.access_token);
+        assert_eq!("Bearer", token.token_type);
+        assert!(token.expiry.unwrap() > chrono::Utc::now());
+        assert_eq!("rotated", ts.refresh_token().expose());
+
+        // the rotated token is kept when the response has no refresh token.
+        assert_eq!("second", ts.token().await.unwrap().access_token);
+        assert_eq!("rotated", ts.refresh_token().expose());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_grant() {
+        let host = mock_server::serve(|_, _| {
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(
+                    r#"{"error":"invalid_grant","error_description":"The refresh token has expired."}"#,
+                ))
+                .unwrap()
+        })
+        .await;
+
+        let ts = ExternalAccountAuthorizedUserTokenSource::new(&credentials(&host)).unwrap();
+        assert!(matches!(ts.token().await, Err(Error::RefreshTokenRejected(_))));
+    }
+
+    #[test]
+    fn test_required_fields() {
+        let mut cred = credentials("localhost");
+        cred.client_secret = None;
+        assert!(matches!(
+            ExternalAccountAuthorizedUserTokenSource::new(&cred),
+            Err(Error::MissingCredentialField {
+                field: "client_secret",
+                ..
+            })
+        ));
+        cred.refresh_token = None;
+        assert!(matches!(
+            ExternalAccountAuthorizedUserTokenSource::new(&cred),
+            Err(Error::MissingCredentialField {
+                field: "refresh_token",
+                ..
+            })
+        ));
+    }
+}
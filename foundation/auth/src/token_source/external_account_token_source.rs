@@ -0,0 +1,408 @@
+use crate::credentials::{CredentialSource, CredentialsFile, EXTERNAL_ACCOUNT_KEY};
+use crate::error::Error;
+use crate::http_client::HttpClient;
+use crate::secret::Secret;
+use crate::sts::{ExchangeRequest, StsClient};
+use crate::token::Token;
+use crate::token_source::retry::RetryPolicy;
+use crate::token_source::subject_token_provider::{from_credential_source, http_provider, SubjectTokenProvider};
+use crate::token_source::TokenSource;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+// ExternalAccountTokenSource exchanges the subject token issued by the external identity provider
+// for a Google access token at the Security Token Service.
+// https://cloud.google.com/iam/docs/workload-identity-federation
+pub struct ExternalAccountTokenSource {
+    audience: String,
+    subject_token_type: String,
+    scopes: String,
+    client_id: Option<String>,
+    client_secret: Option<Secret>,
+    // Sent as the userProject option of the exchange for the workforce pool, unless the client is authenticated.
+    workforce_pool_user_project: Option<String>,
+    subject_token_provider: Arc<dyn SubjectTokenProvider>,
+    // The credential source and the audience, with which the provider is created again for the HTTP client.
+    credential_source: Option<(CredentialSource, String)>,
+
+    sts: StsClient,
+}
+
+impl ExternalAccountTokenSource {
+    // scopes is a space separated list of scopes.
+    pub fn new(cred: &CredentialsFile, scopes: &str) -> Result<ExternalAccountTokenSource, Error> {
+        cred.ensure_type(EXTERNAL_ACCOUNT_KEY)?;
+        let credential_source = cred.required("credential_source", &cred.credential_source)?;
+        let audience = cred.required("audience", &cred.audience)?;
+        let provider = from_credential_source(credential_source, audience, cred)?;
+        let mut ts = Self::new_with_subject_token_provider(cred, scopes, Arc::from(provider))?;
+        ts.credential_source = Some((credential_source.clone(), audience.to_string()));
+        Ok(ts)
+    }
+
+    // Creates the token source which exchanges the subject token of the provider instead of the credential_source.
+    // The subject_token_type of the credentials takes precedence over the one of the provider.
+    pub fn new_with_subject_token_provider(
+        cred: &CredentialsFile,
+        scopes: &str,
+        provider: Arc<dyn SubjectTokenProvider>,
+    ) -> Result<ExternalAccountTokenSource, Error> {
+        cred.ensure_type(EXTERNAL_ACCOUNT_KEY)?;
+        let subject_token_type = match (&cred.subject_token_type, provider.subject_token_type()) {
+            (Some(tp), _) => tp.to_string(),
+            (None, Some(tp)) => tp.to_string(),
+            (None, None) => cred
+                .required("subject_token_type", &cred.subject_token_type)?
+                .to_string(),
+        };
+        let audience = cred.required("audience", &cred.audience)?;
+        let workforce_pool_user_project = match (is_workforce_audience(audience), &cred.workforce_pool_user_project) {
+            (false, Some(_)) => return Err(Error::UnexpectedWorkforcePoolUserProject(audience.to_string())),
+            (false, None) => None,
+            (true, Some(project)) => Some(project.to_string()),
+            // the impersonated service account is billed instead of the user project.
+            (true, None) if cred.service_account_impersonation_url.is_some() => None,
+            (true, None) => return Err(Error::WorkforcePoolUserProjectRequired),
+        };
+        Ok(ExternalAccountTokenSource {
+            audience: audience.to_string(),
+            subject_token_type,
+            scopes: scopes.to_string(),
+            client_id: cred.client_id.clone(),
+            client_secret: cred.client_secret.clone(),
+            workforce_pool_user_project,
+            subject_token_provider: provider,
+            credential_source: None,
+            sts: StsClient::new(cred.required("token_url", &cred.token_url_external)?),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.sts = self.sts.with_retry_policy(retry_policy);
+        self
+    }
+
+    // The client is also used by the provider of the credential source, such as the url and AWS,
+    // but not by the provider given to new_with_subject_token_provider.
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        if let Some((source, audience)) = &self.credential_source {
+            // the same source was accepted by new, so creating the provider again doesn't fail.
+            if let Ok(Some(provider)) = http_provider(source, audience, client.clone()) {
+                self.subject_token_provider = Arc::from(provider);
+            }
+        }
+        self.sts = self.sts.with_http_client(client);
+        self
+    }
+}
+
+// The workforce pools, which federate the human identities, are global and not under the project.
+// //iam.googleapis.com/locations/<location>/workforcePools/<pool>/providers/<provider>
+pub(crate) fn is_workforce_audience(audience: &str) -> bool {
+    let path = match audience.strip_prefix("//iam.googleapis.com/locations/") {
+        Some(path) => path,
+        None => return false,
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    matches!(
+        segments.as_slice(),
+        [location, "workforcePools", pool, "providers", provider]
+            if !location.is_empty() && !pool.is_empty() && !provider.is_empty()
+    )
+}
+
+#[async_trait]
+impl TokenSource for ExternalAccountTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let subject_token = self.subject_token_provider.subject_token().await?;
+        let mut request = ExchangeRequest::new(&subject_token, &self.subject_token_type);
+        request.audience = Some(self.audience.clone());
+        request.scopes = vec![self.scopes.clone()];
+        request.client_id = self.client_id.clone();
+        request.client_secret = self.client_secret.as_ref().map(|s| s.expose().to_string());
+        // the project of the client is billed with the client authentication, as the Go library does.
+        if let (Some(project), None) = (&self.workforce_pool_user_project, &self.client_id) {
+            request.options = Some(json::json!({ "userProject": project }));
+        }
+
+        let now = chrono::Utc::now();
+        let response = self.sts.exchange_token(&request).await?;
+        Ok(response.to_token(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::http_client::HttpClient;
+    use crate::mock_server;
+    use crate::token_source::external_account_token_source::{is_workforce_audience, ExternalAccountTokenSource};
+    use crate::token_source::subject_token_provider::SubjectTokenProvider;
+    use crate::token_source::TokenSource;
+    use async_trait::async_trait;
+    use hyper::{Body, Request, Response};
+    use std::sync::{Arc, Mutex};
+
+    struct SpiffeProvider;
+
+    #[async_trait]
+    impl SubjectTokenProvider for SpiffeProvider {
+        async fn subject_token(&self) -> Result<String, Error> {
+            Ok("spiffe-jwt-svid".to_string())
+        }
+
+        fn subject_token_type(&self) -> Option<&str> {
+            Some("urn:ietf:params:oauth:token-type:jwt")
+        }
+    }
+
+    // Answers the subject token of the url credential source and the exchange, recording the urls.
+    #[derive(Default)]
+    struct FakeHttpClient {
+        urls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl HttpClient for FakeHttpClient {
+        async fn request(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+            let url = request.uri().to_string();
+            self.urls.lock().unwrap().push(url.clone());
+            let body = match url.starts_with("http://169.254.169.254/") {
+                true => r#"{"access_token":"azure-token"}"#,
+                false => {
+                    r#"{"access_token":"federated","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#
+                }
+            };
+            Ok(Response::new(Body::from(body)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_client() {
+        let mut cred: json::Value = json::from_str(include_str!("../../testdata/external_account/url.json")).unwrap();
+        cred.as_object_mut()
+            .unwrap()
+            .remove("service_account_impersonation_url");
+        let cred: CredentialsFile = json::from_value(cred).unwrap();
+        let client = Arc::new(FakeHttpClient::default());
+        let ts = ExternalAccountTokenSource::new(&cred, "https://www.googleapis.com/auth/cloud-platform")
+            .unwrap()
+            .with_http_client(client.clone());
+        assert_eq!("federated", ts.token().await.unwrap().access_token);
+
+        // both the subject token of the credential source and the exchange are requested with the client.
+        let urls = client.urls.lock().unwrap();
+        assert_eq!(2, urls.len());
+        assert!(urls[0].starts_with("http://169.254.169.254/metadata/identity/oauth2/token"));
+        assert_eq!("https://sts.googleapis.com/v1/token", urls[1]);
+    }
+
+    // Loads the fixture with the token_url of the mock server and the subject token file.
+    fn fixture(fixture: &str, host: &str, token_file: &std::path::Path) -> json::Value {
+        let mut cred: json::Value = json::from_str(fixture).unwrap();
+        cred["token_url"] = json::Value::String(format!("http://{}/v1/token", host));
+        cred["credential_source"]["file"] = json::Value::String(token_file.to_str().unwrap().to_string());
+        cred.as_object_mut()
+            .unwrap()
+            .remove("service_account_impersonation_url");
+        cred
+    }
+
+    #[tokio::test]
+    async fn test_workload_and_workforce_fixtures() {
+        let dir = std::env::temp_dir().join("google-cloud-auth-test-workforce");
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("token");
+        std::fs::write(&token_file, "subject-token\n").unwrap();
+
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let recorded = bodies.clone();
+        let host = mock_server::serve(move |_, body| {
+            recorded.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+            Response::new(Body::from(
+                r#"{"access_token":"federated","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+            ))
+        })
+        .await;
+
+        let scopes = "https://www.googleapis.com/auth/cloud-platform";
+        let workforce = fixture(
+            include_str!("../../testdata/external_account/workforce.json"),
+            &host,
+            &token_file,
+        );
+        let mut impersonated = workforce.clone();
+        impersonated["service_account_impersonation_url"] = json::Value::String("https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@my-project.iam.gserviceaccount.com:generateAccessToken".to_string());
+        let mut client_authenticated = workforce.clone();
+        client_authenticated["client_id"] = json::Value::String("client-id".to_string());
+        client_authenticated["client_secret"] = json::Value::String("client-secret".to_string());
+        for cred in [
+            fixture(include_str!("../../testdata/external_account/file.json"), &host, &token_file),
+            workforce,
+            impersonated,
+            client_authenticated,
+        ] {
+            let cred: CredentialsFile = json::from_value(cred).unwrap();
+            let ts = ExternalAccountTokenSource::new(&cred, scopes).unwrap();
+            assert_eq!("federated", ts.token().await.unwrap().access_token);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(
+            "audience=%2F%2Fiam.googleapis.com%2Fprojects%2F123456789%2Flocations%2Fglobal%2FworkloadIdentityPools%2Fmy-pool%2Fproviders%2Fmy-provider\
+            &grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+            &requested_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token\
+            &scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform\
+            &subject_token=subject-token\
+            &subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt",
+            bodies[0]
+        );
+        assert_eq!(
+            "audience=%2F%2Fiam.googleapis.com%2Flocations%2Fglobal%2FworkforcePools%2Fmy-pool%2Fproviders%2Fmy-provider\
+            &grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+            &options=%7B%22userProject%22%3A%22my-user-project%22%7D\
+            &requested_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token\
+            &scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform\
+            &subject_token=subject-token\
+            &subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aid_token",
+            bodies[1]
+        );
+        // the user project is sent with the impersonation too.
+        assert_eq!(bodies[1], bodies[2]);
+        // the client is authenticated with the Authorization header, without the user project.
+        assert_eq!(
+            "audience=%2F%2Fiam.googleapis.com%2Flocations%2Fglobal%2FworkforcePools%2Fmy-pool%2Fproviders%2Fmy-provider\
+            &grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+            &requested_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token\
+            &scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform\
+            &subject_token=subject-token\
+            &subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aid_token",
+            bodies[3]
+        );
+    }
+
+    #[test]
+    fn test_workforce_pool_user_project() {
+        let workforce: json::Value =
+            json::from_str(include_str!("../../testdata/external_account/workforce.json")).unwrap();
+        let scopes = "https://www.googleapis.com/auth/cloud-platform";
+
+        let mut missing = workforce.clone();
+        missing.as_object_mut().unwrap().remove("workforce_pool_user_project");
+        let cred: CredentialsFile = json::from_value(missing.clone()).unwrap();
+        assert!(matches!(
+            ExternalAccountTokenSource::new(&cred, scopes),
+            Err(Error::WorkforcePoolUserProjectRequired)
+        ));
+
+        // the user project is not required with the impersonation.
+        missing["service_account_impersonation_url"] = json::Value::String("https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@my-project.iam.gserviceaccount.com:generateAccessToken".to_string());
+        let cred: CredentialsFile = json::from_value(missing).unwrap();
+        assert!(ExternalAccountTokenSource::new(&cred, scopes).is_ok());
+
+        let mut workload: json::Value =
+            json::from_str(include_str!("../../testdata/external_account/file.json")).unwrap();
+        workload["workforce_pool_user_project"] = json::Value::String("my-user-project".to_string());
+        let cred: CredentialsFile = json::from_value(workload).unwrap();
+        assert!(matches!(
+            ExternalAccountTokenSource::new(&cred, scopes),
+            Err(Error::UnexpectedWorkforcePoolUserProject(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_workforce_audience() {
+        assert!(is_workforce_audience(
+            "//iam.googleapis.com/locations/global/workforcePools/pool/providers/provider"
+        ));
+        assert!(!is_workforce_audience(
+            "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider"
+        ));
+        assert!(!is_workforce_audience(
+            "//iam.googleapis.com/locations/global/workforcePools//providers/provider"
+        ));
+        assert!(!is_workforce_audience(
+            "//iam.googleapis.com/locations/global/workforcePools/pool"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_custom_provider() {
+        let host = mock_server::serve(|_, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains("&subject_token=spiffe-jwt-svid"), "{}", body);
+            assert!(
+                body.ends_with("&subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt"),
+                "{}",
+                body
+            );
+            Response::new(Body::from(
+                r#"{"access_token":"federated","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+            ))
+        })
+        .await;
+
+        // neither credential_source nor subject_token_type is required.
+        let cred: CredentialsFile = json::from_value(json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/spiffe",
+            "token_url": format!("http://{}/v1/token", host),
+        }))
+        .unwrap();
+        let ts = ExternalAccountTokenSource::new_with_subject_token_provider(
+            &cred,
+            "https://www.googleapis.com/auth/cloud-platform",
+            Arc::new(SpiffeProvider),
+        )
+        .unwrap();
+        assert_eq!("federated", ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_file_sourced_token() {
+        let dir = std::env::temp_dir().join("google-cloud-auth-test-file-sourced-token");
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("token.json");
+        std::fs::write(&token_file, r#"{"id_token":"subject-token"}"#).unwrap();
+
+        let host = mock_server::serve(|req, body| {
+            assert_eq!("/v1/token", req.uri.path());
+            assert_eq!(None, req.headers.get("Authorization"));
+            assert_eq!(
+                "audience=%2F%2Fiam.googleapis.com%2Fprojects%2F123%2Flocations%2Fglobal%2FworkloadIdentityPools%2Fpool%2Fproviders%2Fprovider\
+                &grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+                &requested_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token\
+                &scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform\
+                &subject_token=subject-token\
+                &subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt",
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(
+                r#"{"access_token":"federated","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+            ))
+        })
+        .await;
+
+        let cred: CredentialsFile = json::from_value(json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("http://{}/v1/token", host),
+            "credential_source": {
+                "file": token_file.to_str().unwrap(),
+                "format": {
+                    "type": "json",
+                    "subject_token_field_name": "id_token"
+                }
+            }
+        }))
+        .unwrap();
+        let ts = ExternalAccountTokenSource::new(&cred, "https://www.googleapis.com/auth/cloud-platform").unwrap();
+        let token = ts.token().await.unwrap();
+        assert_eq!("federated", token.access_token);
+        assert!(token.expiry.unwrap() > chrono::Utc::now());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token::Token;
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::{jwt_expiry, ResponseExtension, TokenSource};
+use async_trait::async_trait;
+use hyper::http::{Method, Request};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+// The lifetime longer than 1 hour requires constraints/iam.allowServiceAccountCredentialLifetimeExtension.
+const MAX_LIFETIME: Duration = Duration::from_secs(12 * 3600);
+
+const LIFETIME_EXTENSION_CONSTRAINT: &str = "constraints/iam.allowServiceAccountCredentialLifetimeExtension";
+
+const DELEGATE_PREFIX: &str = "projects/-/serviceAccounts/";
+
+#[derive(Serialize)]
+struct GenerateAccessTokenRequest<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    delegates: &'a [String],
+    scope: &'a [String],
+    lifetime: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: String,
+}
+
+// ImpersonateTokenSource uses the token of the target to get the access token of the service account
+// through the IAM Credentials API.
+// https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateAccessToken
+pub struct ImpersonateTokenSource {
+    target: Box<dyn TokenSource>,
+    url: String,
+    scopes: Vec<String>,
+    lifetime: Duration,
+    delegates: Vec<String>,
+    retry_policy: RetryPolicy,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl ImpersonateTokenSource {
+    // url is the generateAccessToken endpoint of the service account to impersonate.
+    pub fn new(target: Box<dyn TokenSource>, url: &str, scopes: Vec<String>) -> ImpersonateTokenSource {
+        ImpersonateTokenSource {
+            target,
+            url: url.to_string(),
+            scopes,
+            lifetime: DEFAULT_LIFETIME,
+            delegates: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
+        }
+    }
+
+    // The lifetime of the access token, which is 1 hour by default.
+    // Up to 12 hours is allowed if the organization policy has constraints/iam.allowServiceAccountCredentialLifetimeExtension.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Result<Self, Error> {
+        if lifetime.as_secs() == 0 || lifetime > MAX_LIFETIME || lifetime.subsec_nanos() != 0 {
+            return Err(Error::InvalidImpersonationConfig(format!(
+                "lifetime must be whole seconds between 1s and {}s, but it is {:?}",
+                MAX_LIFETIME.as_secs(),
+                lifetime
+            )));
+        }
+        self.lifetime = lifetime;
+        Ok(self)
+    }
+
+    // The chain of the service accounts, each of which has the Service Account Token Creator role on the next one.
+    // The last one has the role on the service account to impersonate.
+    pub fn with_delegates(mut self, delegates: Vec<String>) -> Result<Self, Error> {
+        self.delegates = validate_delegates(delegates)?;
+        Ok(self)
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+pub(crate) fn validate_delegates(delegates: Vec<String>) -> Result<Vec<String>, Error> {
+    for delegate in &delegates {
+        match delegate.strip_prefix(DELEGATE_PREFIX) {
+            Some(email) if email.contains('@') && !email.contains('/') => {}
+            _ => {
+                return Err(Error::InvalidImpersonationConfig(format!(
+                    "delegate must be {}{{email}}, but it is {}",
+                    DELEGATE_PREFIX, delegate
+                )))
+            }
+        }
+    }
+    Ok(delegates)
+}
+
+// The IAM error doesn't say which organization policy to change, so it is surfaced with the constraint.
+fn lifetime_error(e: Error, lifetime: Duration) -> Error {
+    match e {
+        Error::TokenEndpoint {
+            description: Some(message),
+            ..
+        } if message.contains(LIFETIME_EXTENSION_CONSTRAINT) => {
+            Error::LifetimeExtensionNotAllowed { lifetime, message }
+        }
+        e => e,
+    }
+}
+
+#[async_trait]
+impl TokenSource for ImpersonateTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let source_token = self.target.token().await?;
+        let body = json::to_string(&GenerateAccessTokenRequest {
+            delegates: &self.delegates,
+            scope: &self.scopes,
+            lifetime: format!("{}s", self.lifetime.as_secs()),
+        })?;
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.url.as_str())
+                .header("Content-Type", "application/json")
+                .header("Authorization", source_token.header_value())
+                .body(Body::from(body.clone()))?)
+        })
+        .await?;
+        let response: GenerateAccessTokenResponse = response
+            .deserialize()
+            .await
+            .map_err(|e| lifetime_error(e, self.lifetime))?;
+        let expiry = chrono::DateTime::parse_from_rfc3339(&response.expire_time)?;
+        Ok(Token {
+            access_token: response.access_token,
+            token_type: "Bearer".to_string(),
+            expiry: Some(expiry.with_timezone(&chrono::Utc)),
+            id_token: None,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateIdTokenRequest<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    delegates: &'a [String],
+    audience: &'a str,
+    include_email: bool,
+}
+
+#[derive(Deserialize)]
+struct GenerateIdTokenResponse {
+    token: String,
+}
+
+// ImpersonateIdTokenSource uses the token of the target to get the ID token of the service account
+// through the IAM Credentials API.
+// https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateIdToken
+pub struct ImpersonateIdTokenSource {
+    target: Box<dyn TokenSource>,
+    url: String,
+    audience: String,
+    delegates: Vec<String>,
+    retry_policy: RetryPolicy,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl ImpersonateIdTokenSource {
+    // url is the generateIdToken endpoint of the service account to impersonate.
+    pub fn new(target: Box<dyn TokenSource>, url: &str, audience: &str) -> ImpersonateIdTokenSource {
+        ImpersonateIdTokenSource {
+            target,
+            url: url.to_string(),
+            audience: audience.to_string(),
+            delegates: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_delegates(mut self, delegates: Vec<String>) -> Result<Self, Error> {
+        self.delegates = validate_delegates(delegates)?;
+        Ok(self)
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+#[async_trait]
+impl TokenSource for ImpersonateIdTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let source_token = self.target.token().await?;
+        let body = json::to_string(&GenerateIdTokenRequest {
+            delegates: &self.delegates,
+            audience: &self.audience,
+            include_email: true,
+        })?;
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.url.as_str())
+                .header("Content-Type", "application/json")
+                .header("Authorization", source_token.header_value())
+                .body(Body::from(body.clone()))?)
+        })
+        .await?;
+        let response: GenerateIdTokenResponse = response.deserialize().await?;
+        Ok(Token {
+            expiry: jwt_expiry(&response.token),
+            id_token: Some(response.token.clone()),
+            access_token: response.token,
+            token_type: "Bearer".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::mock_server;
+    use crate::testing::StaticTokenSource;
+    use crate::token_source::impersonate_token_source::{ImpersonateIdTokenSource, ImpersonateTokenSource};
+    use crate::token_source::TokenSource;
+    use hyper::{Body, Response, StatusCode};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_token() {
+        let host = mock_server::serve(|req, body| {
+            assert_eq!(
+                "/v1/projects/-/serviceAccounts/sa@example.iam.gserviceaccount.com:generateAccessToken",
+                req.uri.path()
+            );
+            assert_eq!("Bearer source", req.headers.get("Authorization").unwrap());
+            assert_eq!(
+                r#"{"scope":["https://www.googleapis.com/auth/devstorage.read_only"],"lifetime":"3600s"}"#,
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(
+                r#"{"accessToken":"impersonated","expireTime":"2099-10-01T10:00:00Z"}"#,
+            ))
+        })
+        .await;
+
+        let url = format!(
+            "http://{}/v1/projects/-/serviceAccounts/sa@example.iam.gserviceaccount.com:generateAccessToken",
+            host
+        );
+        let ts = ImpersonateTokenSource::new(
+            Box::new(StaticTokenSource::new("source")),
+            &url,
+            vec!["https://www.googleapis.com/auth/devstorage.read_only".to_string()],
+        );
+        let token = ts.token().await.unwrap();
+        assert_eq!("impersonated", token.access_token);
+        assert_eq!("2099-10-01T10:00:00+00:00", token.expiry.unwrap().to_rfc3339());
+    }
+
+    #[tokio::test]
+    async fn test_token_lifetime_and_delegates() {
+        let host = mock_server::serve(|_, body| {
+            assert_eq!(
+                r#"{"delegates":["projects/-/serviceAccounts/delegate@example.iam.gserviceaccount.com"],"scope":["https://www.googleapis.com/auth/cloud-platform"],"lifetime":"43200s"}"#,
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(
+                r#"{"accessToken":"impersonated","expireTime":"2099-10-01T10:00:00Z"}"#,
+            ))
+        })
+        .await;
+        let scopes = vec!["https://www.googleapis.com/auth/cloud-platform".to_string()];
+        let ts = ImpersonateTokenSource::new(
+            Box::new(StaticTokenSource::new("source")),
+            &format!("http://{}/", host),
+            scopes.clone(),
+        )
+        .with_lifetime(Duration::from_secs(12 * 3600))
+        .unwrap()
+        .with_delegates(vec![
+            "projects/-/serviceAccounts/delegate@example.iam.gserviceaccount.com".to_string()
+        ])
+        .unwrap();
+        assert_eq!("impersonated", ts.token().await.unwrap().access_token);
+
+        // longer than 1 hour without the delegates.
+        let host = mock_server::serve(|_, body| {
+            assert_eq!(
+                r#"{"scope":["https://www.googleapis.com/auth/cloud-platform"],"lifetime":"7200s"}"#,
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            Response::new(Body::from(
+                r#"{"accessToken":"impersonated","expireTime":"2099-10-01T10:00:00Z"}"#,
+            ))
+        })
+        .await;
+        let ts = ImpersonateTokenSource::new(
+            Box::new(StaticTokenSource::new("source")),
+            &format!("http://{}/", host),
+            scopes,
+        )
+        .with_lifetime(Duration::from_secs(7200))
+        .unwrap();
+        assert_eq!("impersonated", ts.token().await.unwrap().access_token);
+    }
+
+    #[test]
+    fn test_invalid_lifetime_and_delegates() {
+        let ts =
+            || ImpersonateTokenSource::new(Box::new(StaticTokenSource::new("source")), "http://localhost/", vec![]);
+        for lifetime in [
+            Duration::ZERO,
+            Duration::from_secs(12 * 3600 + 1),
+            Duration::from_millis(1500),
+        ] {
+            assert!(matches!(
+                ts().with_lifetime(lifetime),
+                Err(Error::InvalidImpersonationConfig(_))
+            ));
+        }
+        for delegate in [
+            "delegate@example.iam.gserviceaccount.com",
+            "projects/my-project/serviceAccounts/delegate@example.iam.gserviceaccount.com",
+            "projects/-/serviceAccounts/",
+        ] {
+            assert!(matches!(
+                ts().with_delegates(vec![delegate.to_string()]),
+                Err(Error::InvalidImpersonationConfig(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifetime_extension_not_allowed() {
+        let host = mock_server::serve(|_, _| {
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(
+                    r#"{"error":{"code":400,"message":"Request contains an invalid argument. Lifetime exceeds the maximum allowed by constraints/iam.allowServiceAccountCredentialLifetimeExtension.","status":"FAILED_PRECONDITION"}}"#,
+                ))
+                .unwrap()
+        })
+        .await;
+        let ts = ImpersonateTokenSource::new(
+            Box::new(StaticTokenSource::new("source")),
+            &format!("http://{}/", host),
+            vec![],
+        )
+        .with_lifetime(Duration::from_secs(7200))
+        .unwrap();
+        match ts.token().await {
+            Err(Error::LifetimeExtensionNotAllowed { lifetime, message }) => {
+                assert_eq!(Duration::from_secs(7200), lifetime);
+                assert!(message.contains("constraints/iam.allowServiceAccountCredentialLifetimeExtension"));
+            }
+            r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_id_token() {
+        let host = mock_server::serve(|req, body| {
+            assert_eq!(
+                "/v1/projects/-/serviceAccounts/sa@example.iam.gserviceaccount.com:generateIdToken",
+                req.uri.path()
+            );
+            assert_eq!("Bearer source", req.headers.get("Authorization").unwrap());
+            assert_eq!(
+                r#"{"audience":"https://example.run.app","includeEmail":true}"#,
+                String::from_utf8(body.to_vec()).unwrap()
+            );
+            let payload = base64::encode_config(r#"{"exp":1893456000}"#, base64::URL_SAFE_NO_PAD);
+            Response::new(Body::from(
+                json::json!({ "token": format!("header.{}.signature", payload) }).to_string(),
+            ))
+        })
+        .await;
+
+        let url = format!(
+            "http://{}/v1/projects/-/serviceAccounts/sa@example.iam.gserviceaccount.com:generateIdToken",
+            host
+        );
+        let ts =
+            ImpersonateIdTokenSource::new(Box::new(StaticTokenSource::new("source")), &url, "https://example.run.app");
+        let token = ts.token().await.unwrap();
+        assert_eq!(1893456000, token.expiry.unwrap().timestamp());
+    }
+}
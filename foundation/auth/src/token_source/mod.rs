@@ -1,28 +1,200 @@
 pub mod authorized_user_token_source;
+pub mod chain_token_source;
 pub mod compute_token_source;
+pub mod downscoped_token_source;
+pub mod external_account_authorized_user_token_source;
+pub mod external_account_token_source;
+pub mod impersonate_token_source;
+pub mod retry;
 pub mod reuse_token_source;
 pub mod service_account_token_source;
+pub mod static_token_source;
+pub mod subject_token_provider;
 
 use crate::error::Error;
+use crate::metrics::{MeteredTokenSource, TokenMetricsSink};
 use crate::token::Token;
+use crate::token_source::reuse_token_source::ReuseTokenSource;
+use crate::Credential;
 use async_trait::async_trait;
-use google_cloud_metadata::default_http_connector;
-use hyper::client::HttpConnector;
+use chrono::TimeZone;
 use hyper::http::Response;
-use hyper_tls::HttpsConnector;
 use serde::{de, Deserialize};
+use std::sync::Arc;
 
 #[async_trait]
 pub trait TokenSource: Send + Sync {
     async fn token(&self) -> Result<Token, Error>;
+
+    // Returns a new token instead of the cached one, for example after the token is rejected with 401.
+    // The token sources without the cache just return the token.
+    async fn refresh(&self) -> Result<Token, Error> {
+        self.token().await
+    }
+
+    // Drops the cached token if it is the token rejected with 401, so that the next call of token returns a new one.
+    // The token which another caller has already replaced is kept. The token sources without the cache do nothing.
+    fn invalidate(&self, _token: &Token) {}
+
+    // Returns true if the token source can't issue the tokens anymore because it was revoked.
+    // The cached tokens of the revoked token source must not be used.
+    fn revoked(&self) -> bool {
+        false
+    }
 }
 
-fn default_https_client() -> hyper::Client<HttpsConnector<HttpConnector>> {
-    hyper::Client::builder().build(HttpsConnector::new_with_connector(default_http_connector()))
+// Shares the token source, for example to revoke the token source wrapped by the cache.
+#[async_trait]
+impl<T: TokenSource + ?Sized> TokenSource for Arc<T> {
+    async fn token(&self) -> Result<Token, Error> {
+        self.as_ref().token().await
+    }
+
+    async fn refresh(&self) -> Result<Token, Error> {
+        self.as_ref().refresh().await
+    }
+
+    fn invalidate(&self, token: &Token) {
+        self.as_ref().invalidate(token)
+    }
+
+    fn revoked(&self) -> bool {
+        self.as_ref().revoked()
+    }
 }
 
 #[async_trait]
-trait ResponseExtension {
+impl<T: TokenSource + ?Sized> TokenSource for Box<T> {
+    async fn token(&self) -> Result<Token, Error> {
+        self.as_ref().token().await
+    }
+
+    async fn refresh(&self) -> Result<Token, Error> {
+        self.as_ref().refresh().await
+    }
+
+    fn invalidate(&self, token: &Token) {
+        self.as_ref().invalidate(token)
+    }
+
+    fn revoked(&self) -> bool {
+        self.as_ref().revoked()
+    }
+}
+
+pub trait TokenSourceExt: TokenSource + Sized + 'static {
+    // Wraps the token source so that the token is reused until it is about to expire.
+    fn cached(self) -> ReuseTokenSource {
+        ReuseTokenSource::new(Box::new(self), None)
+    }
+
+    // Returns the credential which sends the quota project with the token, for AuthorizedClient and the middleware.
+    fn with_quota_project(self, quota_project_id: &str) -> Credential {
+        Credential {
+            token_source: self.into_shared(),
+            project_id: None,
+            quota_project_id: Some(quota_project_id.to_string()),
+        }
+    }
+
+    // Records the latency and the errors of every token request, for the token source without the cache.
+    fn metered(self, sink: Arc<dyn TokenMetricsSink>) -> MeteredTokenSource<Self> {
+        MeteredTokenSource::new(self, sink)
+    }
+
+    // Returns the token source shared between the tasks.
+    fn into_shared(self) -> Arc<dyn TokenSource> {
+        Arc::new(self)
+    }
+}
+
+impl<T: TokenSource + 'static> TokenSourceExt for T {}
+
+// Encodes the parameters as an application/x-www-form-urlencoded body.
+pub(crate) fn form_body(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// Error response of the OAuth 2.0 token endpoint.
+// https://datatracker.ietf.org/doc/html/rfc6749#section-5.2
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+    error_uri: Option<String>,
+}
+
+// Error response of Google APIs such as the IAM Credentials API.
+#[derive(Deserialize)]
+struct GoogleErrorResponse {
+    error: GoogleError,
+}
+
+#[derive(Deserialize)]
+struct GoogleError {
+    message: Option<String>,
+    status: Option<String>,
+}
+
+// The body of the error response other than JSON, such as the HTML page of the proxy, is cut to this length.
+const MAX_ERROR_BODY_LENGTH: usize = 256;
+
+// Converts the non-success response into the error with the reason given by the server.
+// The body which is not the JSON error, such as 502 of the load balancer, is the description as it is.
+pub(crate) async fn error_from_response(response: Response<hyper::body::Body>) -> Error {
+    let status = response.status();
+    let body = match hyper::body::to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return Error::HyperError(e),
+    };
+    if let Ok(e) = json::from_slice::<ErrorResponse>(&body) {
+        return Error::TokenEndpoint {
+            status,
+            error: e.error,
+            description: e.error_description,
+            uri: e.error_uri,
+        };
+    }
+    if let Ok(e) = json::from_slice::<GoogleErrorResponse>(&body) {
+        return Error::TokenEndpoint {
+            status,
+            error: e.error.status.unwrap_or_else(|| status.to_string()),
+            description: e.error.message,
+            uri: None,
+        };
+    }
+    let body = String::from_utf8_lossy(&body);
+    let body = body.trim();
+    Error::TokenEndpoint {
+        status,
+        error: status.to_string(),
+        description: match body.char_indices().nth(MAX_ERROR_BODY_LENGTH) {
+            _ if body.is_empty() => None,
+            Some((end, _)) => Some(format!("{}...", &body[..end])),
+            None => Some(body.to_string()),
+        },
+        uri: None,
+    }
+}
+
+// Reads the exp claim of the JWT without verifying the signature.
+fn jwt_expiry(token: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: Option<i64>,
+    }
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Claims = json::from_slice(&payload).ok()?;
+    chrono::Utc.timestamp_opt(claims.exp?, 0).single()
+}
+
+#[async_trait]
+pub(crate) trait ResponseExtension {
     async fn deserialize<T>(self) -> Result<T, Error>
     where
         T: de::DeserializeOwned;
@@ -35,16 +207,26 @@ impl ResponseExtension for Response<hyper::body::Body> {
         T: de::DeserializeOwned,
     {
         if !self.status().is_success() {
-            return Err(Error::DeserializeError(self.status().to_string()));
+            return Err(error_from_response(self).await);
         }
         let (_, body) = self.into_parts();
         let body = hyper::body::to_bytes(body).await.map_err(Error::HyperError)?;
-        let token = json::from_slice(&body).map_err(Error::JsonError)?;
+        let token = json::from_slice(&body).map_err(|e| invalid_response(&e))?;
 
         Ok(token)
     }
 }
 
+// The messages of the JSON errors may quote the values such as the tokens in the response,
+// so only the missing or unknown fields and the position are kept.
+fn invalid_response(e: &json::Error) -> Error {
+    let message = e.to_string();
+    if message.starts_with("missing field") || message.starts_with("unknown field") {
+        return Error::InvalidResponse(message);
+    }
+    Error::InvalidResponse(format!("{:?} error at line {} column {}", e.classify(), e.line(), e.column()))
+}
+
 #[derive(Clone, Deserialize)]
 struct InternalToken {
     pub access_token: String,
@@ -54,17 +236,31 @@ struct InternalToken {
 }
 
 impl InternalToken {
+    // Without expires_in, the exp claim of the ID token is used as the expiry.
     fn to_token(&self, now: chrono::DateTime<chrono::Utc>) -> Token {
-        //TODO support use ID token
         Token {
             access_token: self.access_token.clone(),
             token_type: self.token_type.clone(),
-            expiry: self.expires_in.map(|s| now + chrono::Duration::seconds(s)),
+            expiry: token_expiry(now, self.expires_in, self.id_token.as_deref()),
+            id_token: self.id_token.clone(),
         }
     }
 }
 
+// Returns the expiry from expires_in, or from the exp claim of the ID token if expires_in is absent.
+pub(crate) fn token_expiry(
+    now: chrono::DateTime<chrono::Utc>,
+    expires_in: Option<i64>,
+    id_token: Option<&str>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    match expires_in {
+        Some(s) => Some(now + chrono::Duration::seconds(s)),
+        None => id_token.and_then(jwt_expiry),
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use crate::credentials::CredentialsFile;
     use crate::error::Error;
@@ -75,9 +271,131 @@ mod tests {
         OAuth2ServiceAccountTokenSource, ServiceAccountTokenSource,
     };
     use crate::token_source::TokenSource;
+    use crate::token_source::{jwt_expiry, InternalToken, ResponseExtension};
+    use hyper::{Body, Response, StatusCode};
     use std::fs::File;
     use std::io::Write;
 
+    async fn deserialize_error(status: StatusCode, body: &str) -> Error {
+        let response = Response::builder()
+            .status(status)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        response.deserialize::<json::Value>().await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let body = r#"{"error":"invalid_grant","error_description":"Invalid JWT: Token must be a short-lived token (60 minutes) and in a reasonable timeframe. Check your iat and exp values in the JWT claim.","error_uri":"https://developers.google.com/identity/protocols/oauth2"}"#;
+        match deserialize_error(StatusCode::BAD_REQUEST, body).await {
+            Error::TokenEndpoint {
+                status,
+                error,
+                description,
+                uri,
+            } => {
+                assert_eq!(StatusCode::BAD_REQUEST, status);
+                assert_eq!("invalid_grant", error);
+                assert!(description
+                    .unwrap()
+                    .starts_with("Invalid JWT: Token must be a short-lived token"));
+                assert_eq!(Some("https://developers.google.com/identity/protocols/oauth2"), uri.as_deref());
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let body = r#"{"error":{"code":403,"message":"Permission 'iam.serviceAccounts.getAccessToken' denied","status":"PERMISSION_DENIED"}}"#;
+        match deserialize_error(StatusCode::FORBIDDEN, body).await {
+            Error::TokenEndpoint { error, description, .. } => {
+                assert_eq!("PERMISSION_DENIED", error);
+                assert_eq!(
+                    Some("Permission 'iam.serviceAccounts.getAccessToken' denied"),
+                    description.as_deref()
+                );
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match deserialize_error(StatusCode::BAD_GATEWAY, "<html>Bad Gateway</html>").await {
+            Error::TokenEndpoint {
+                status,
+                error,
+                description,
+                ..
+            } => {
+                assert_eq!(StatusCode::BAD_GATEWAY, status);
+                assert_eq!("502 Bad Gateway", error);
+                assert_eq!(Some("<html>Bad Gateway</html>"), description.as_deref());
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // the long page is cut, and the empty body has no description.
+        let page = "x".repeat(1000);
+        match deserialize_error(StatusCode::SERVICE_UNAVAILABLE, &page).await {
+            Error::TokenEndpoint { description, .. } => assert_eq!(259, description.unwrap().len()),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(matches!(
+            deserialize_error(StatusCode::SERVICE_UNAVAILABLE, "").await,
+            Error::TokenEndpoint { description: None, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_response() {
+        #[derive(serde::Deserialize, Debug)]
+        struct ExpiresIn {
+            #[allow(dead_code)]
+            expires_in: i64,
+        }
+        let deserialize = |body: &'static str| async move {
+            Response::new(Body::from(body))
+                .deserialize::<ExpiresIn>()
+                .await
+                .unwrap_err()
+                .to_string()
+        };
+        let message = deserialize(r#"{"expires_in":"ya29.secret-token"}"#).await;
+        assert!(message.starts_with("invalid response"), "{}", message);
+        assert!(!message.contains("secret"), "{}", message);
+
+        let message = deserialize(r#"{"access_token":"ya29.secret-token"}"#).await;
+        assert!(message.contains("missing field `expires_in`"), "{}", message);
+    }
+
+    #[test]
+    fn test_jwt_expiry() {
+        let payload = base64::encode_config(r#"{"aud":"x","exp":1893456000}"#, base64::URL_SAFE_NO_PAD);
+        let token = format!("eyJhbGciOiJSUzI1NiJ9.{}.signature", payload);
+        assert_eq!(1893456000, jwt_expiry(&token).unwrap().timestamp());
+        assert!(jwt_expiry("opaque-access-token").is_none());
+    }
+
+    #[test]
+    fn test_internal_token_expiry() {
+        let payload = base64::encode_config(r#"{"aud":"x","exp":1893456000}"#, base64::URL_SAFE_NO_PAD);
+        let id_token = format!("eyJhbGciOiJSUzI1NiJ9.{}.signature", payload);
+        let now = chrono::Utc::now();
+        let mut it = InternalToken {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: Some(3600),
+            id_token: Some(id_token.clone()),
+        };
+        let token = it.to_token(now);
+        assert_eq!(Some(now + chrono::Duration::seconds(3600)), token.expiry);
+        assert_eq!(Some(id_token.as_str()), token.id_token.as_deref());
+
+        // the exp claim of the ID token is used without expires_in.
+        it.expires_in = None;
+        assert_eq!(1893456000, it.to_token(now).expiry.unwrap().timestamp());
+
+        // the token with neither is not cached.
+        it.id_token = None;
+        assert!(!it.to_token(now).cacheable());
+    }
+
     #[tokio::test]
     async fn test_user_account_token_source() -> Result<(), Error> {
         let authorized_user_credentials = std::env::var("TEST_USER_CREDENTIALS").map_err(Error::VarError)?;
@@ -112,7 +430,7 @@ mod tests {
         let token = ts.token().await?;
         assert_eq!(true, token.expiry.unwrap().timestamp() > 0);
         let old_token_value = token.access_token.clone();
-        let rts = ReuseTokenSource::new(Box::new(ts), token);
+        let rts = ReuseTokenSource::new(Box::new(ts), Some(token));
         let new_token = rts.token().await?;
         assert_eq!(old_token_value, new_token.access_token);
         Ok(())
@@ -133,10 +451,27 @@ mod tests {
     async fn test_oauth2_token_source() -> Result<(), Error> {
         let credentials = CredentialsFile::new().await?;
         let scope = "https://www.googleapis.com/auth/cloud-platform https://www.googleapis.com/auth/spanner.data";
-        let ts = OAuth2ServiceAccountTokenSource::new(&credentials, scope)?;
+        let ts = OAuth2ServiceAccountTokenSource::new(&credentials, scope, None)?;
         let token = ts.token().await?;
         assert_eq!("Bearer", token.token_type);
         assert_eq!(true, token.expiry.unwrap().timestamp() > 0);
         Ok(())
     }
+
+    // The token sources are moved to the other tasks as the trait objects.
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Box<dyn TokenSource>>();
+        assert_send_sync::<std::sync::Arc<dyn TokenSource>>();
+        assert_send_sync::<ComputeTokenSource>();
+        assert_send_sync::<ServiceAccountTokenSource>();
+        assert_send_sync::<OAuth2ServiceAccountTokenSource>();
+        assert_send_sync::<UserAccountTokenSource>();
+        assert_send_sync::<ReuseTokenSource>();
+        assert_send_sync::<crate::token_source::impersonate_token_source::ImpersonateTokenSource>();
+        assert_send_sync::<crate::token_source::external_account_token_source::ExternalAccountTokenSource>();
+        assert_send_sync::<crate::token_source::downscoped_token_source::DownscopedTokenSource>();
+        assert_send_sync::<crate::token_source::chain_token_source::ChainTokenSource>();
+    }
 }
@@ -0,0 +1,212 @@
+use crate::error::Error;
+use crate::http_client::HttpClient;
+use crate::token_source::error_from_response;
+use hyper::http::{Request, Response, StatusCode};
+use hyper::Body;
+use rand::Rng;
+use std::time::Duration;
+
+// RetryPolicy specifies how the requests to the token endpoints are retried.
+// 5xx, 408 and 429 responses and connection errors are retried with the exponential backoff and jitter.
+// Other responses such as the 400 for invalid_grant are never retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // The total number of attempts including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Sends the request only once.
+    pub fn no_retry() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    // Returns the backoff before the attempt, which is randomized between the half and the full backoff.
+    // The first retry, which is the second attempt, waits for the initial_backoff.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let retries = attempt.saturating_sub(2);
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retries as i32);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        Duration::from_secs_f64(rand::thread_rng().gen_range(backoff / 2.0..=backoff))
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_retryable_error(error: &Error) -> bool {
+    matches!(error, Error::HyperError(_) | Error::HttpTimeout(_))
+}
+
+// Returns true if the error may go away later, such as the timeouts and the 5xx responses.
+pub(crate) fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::RetryExhausted { .. } => true,
+        Error::Shared(e) => is_transient(e),
+        Error::TokenEndpoint { status, .. } => is_retryable(*status),
+        e => is_retryable_error(e),
+    }
+}
+
+// Sends the request built by build_request until it succeeds or the attempts are exhausted.
+// The response that must not be retried is returned as it is for the caller to handle.
+pub(crate) async fn request_with_retry<F>(
+    client: &dyn HttpClient,
+    policy: &RetryPolicy,
+    build_request: F,
+) -> Result<Response<Body>, Error>
+where
+    F: Fn() -> Result<Request<Body>, Error>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let error = match client.request(build_request()?).await {
+            Ok(response) if !is_retryable(response.status()) || max_attempts == 1 => return Ok(response),
+            Ok(response) => error_from_response(response).await,
+            Err(e) if !is_retryable_error(&e) || max_attempts == 1 => return Err(e),
+            Err(e) => e,
+        };
+        if attempt >= max_attempts {
+            return Err(Error::RetryExhausted {
+                attempts: attempt,
+                source: Box::new(error),
+            });
+        }
+        attempt += 1;
+        tokio::time::sleep(policy.backoff(attempt)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::http_client::DefaultHttpClient;
+    use crate::mock_server;
+    use crate::token_source::retry::{request_with_retry, RetryPolicy};
+    use hyper::http::{Method, Request};
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    // Responds with the statuses in order, and then 200.
+    async fn serve(statuses: Vec<StatusCode>, calls: Arc<AtomicUsize>) -> String {
+        let host = mock_server::serve(move |_, _| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            Response::builder()
+                .status(statuses.get(call).cloned().unwrap_or(StatusCode::OK))
+                .body(Body::from(r#"{"error":"invalid_grant"}"#))
+                .unwrap()
+        })
+        .await;
+        format!("http://{}/token", host)
+    }
+
+    async fn request(url: &str, policy: &RetryPolicy) -> Result<StatusCode, Error> {
+        let client = DefaultHttpClient::default();
+        let response = request_with_retry(&client, policy, || {
+            Ok(Request::builder().method(Method::POST).uri(url).body(Body::empty())?)
+        })
+        .await?;
+        Ok(response.status())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for _ in 0..100 {
+            // the first retry waits for the initial backoff.
+            let first = policy.backoff(2);
+            assert!(
+                first >= Duration::from_millis(50) && first <= Duration::from_millis(100),
+                "{:?}",
+                first
+            );
+            let second = policy.backoff(3);
+            assert!(
+                second >= Duration::from_millis(100) && second <= Duration::from_millis(200),
+                "{:?}",
+                second
+            );
+        }
+        assert!(policy.backoff(20) <= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let statuses = vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::TOO_MANY_REQUESTS];
+        let url = serve(statuses, calls.clone()).await;
+        assert_eq!(StatusCode::OK, request(&url, &policy()).await.unwrap());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_for_bad_request() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = serve(vec![StatusCode::BAD_REQUEST], calls.clone()).await;
+        assert_eq!(StatusCode::BAD_REQUEST, request(&url, &policy()).await.unwrap());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_retry_exhausted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = serve(vec![StatusCode::INTERNAL_SERVER_ERROR; 5], calls.clone()).await;
+        match request(&url, &policy()).await {
+            Err(Error::RetryExhausted { attempts, source }) => {
+                assert_eq!(3, attempts);
+                assert!(matches!(*source, Error::TokenEndpoint { .. }));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        // the status is returned as it is without retries.
+        let statuses = vec![StatusCode::INTERNAL_SERVER_ERROR];
+        let url = serve(statuses, Arc::new(AtomicUsize::new(0))).await;
+        let status = request(&url, &RetryPolicy::no_retry()).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+    }
+
+    #[tokio::test]
+    async fn test_retry_connection_error() {
+        // nothing listens on the port after the listener is dropped.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        drop(listener);
+        match request(&url, &policy()).await {
+            Err(Error::RetryExhausted { attempts, source }) => {
+                assert_eq!(3, attempts);
+                assert!(matches!(*source, Error::HyperError(_)));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
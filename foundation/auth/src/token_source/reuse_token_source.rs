@@ -1,37 +1,566 @@
+use crate::clock::{system_clock, Clock};
 use crate::error::Error;
+use crate::metrics::{ErrorKind, TokenMetricsSink};
 use crate::token::Token;
 use crate::token_source::TokenSource;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
+// Tokens are refreshed this long before they actually expire.
+// Same as the default of the google-auth-library for other languages (3m45s).
+pub(crate) const DEFAULT_REFRESH_MARGIN_SECONDS: i64 = 225;
+
+// Up to this long is added to the refresh margin at random, so that the replicas which got the tokens
+// at the same time don't refresh them at the same time.
+pub(crate) const DEFAULT_REFRESH_JITTER_SECONDS: i64 = 30;
+
+// RefreshObserver is notified of the refreshes of the cached token, for example to export the metrics.
+// The methods are called without holding any lock of the token source, but they should return quickly
+// because the caller that refreshed the token waits for them.
+pub trait RefreshObserver: Send + Sync {
+    fn on_refresh_start(&self) {}
+
+    // expiry is None if the token doesn't expire, in which case it is not cached.
+    fn on_refresh_success(&self, _expiry: Option<DateTime<Utc>>, _latency: Duration) {}
+
+    fn on_refresh_error(&self, _error: &Error) {}
+}
+
+// ReuseTokenSource caches the token of the target and returns it until it is about to expire.
+// Concurrent callers that see a stale token wait for a single refresh instead of all calling the target.
 pub struct ReuseTokenSource {
     target: Box<dyn TokenSource>,
-    current_token: std::sync::RwLock<Token>,
+    current_token: RwLock<Option<Token>>,
+    // The refresh in flight, whose caller the others wait for with the notification of refreshed.
+    flights: Mutex<Flights>,
+    refreshed: Notify,
+    refresh_margin: chrono::Duration,
+    refresh_jitter: chrono::Duration,
+    // The jitter of the current token, which is chosen when the token is stored.
+    current_jitter: RwLock<chrono::Duration>,
+    // Incremented whenever the token is stored, so that the callers of refresh waiting for the lock
+    // can tell that another caller has just refreshed the token.
+    generation: AtomicU64,
+    observer: Option<Arc<dyn RefreshObserver>>,
+    // None on the hot path costs nothing, and AtomicMetricsSink costs an atomic increment per cache hit.
+    metrics: Option<Arc<dyn TokenMetricsSink>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct Flights {
+    // The id of the refresh in flight.
+    current: Option<u64>,
+    // The number of the callers waiting for the refresh in flight.
+    waiters: usize,
+    last_id: u64,
+    // The result of the last refresh which had the waiters, error included, so that they don't refresh again.
+    last_result: Option<(u64, Result<Token, Arc<Error>>)>,
+}
+
+// Ends the refresh even if the future of the refreshing caller is dropped, so that the waiting callers go on.
+// The refresh which another caller has started in the meantime is kept.
+struct Flight<'a>(u64, &'a ReuseTokenSource);
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        {
+            let mut flights = self.1.flights.lock().unwrap();
+            if flights.current == Some(self.0) {
+                flights.current = None;
+            }
+        }
+        self.1.refreshed.notify_waiters();
+    }
 }
 
 impl ReuseTokenSource {
-    pub(crate) fn new(target: Box<dyn TokenSource>, token: Token) -> ReuseTokenSource {
+    pub fn new(target: Box<dyn TokenSource>, token: Option<Token>) -> ReuseTokenSource {
         ReuseTokenSource {
             target,
-            current_token: std::sync::RwLock::new(token),
+            current_token: RwLock::new(token),
+            flights: Mutex::new(Flights::default()),
+            refreshed: Notify::new(),
+            refresh_margin: chrono::Duration::seconds(DEFAULT_REFRESH_MARGIN_SECONDS),
+            refresh_jitter: chrono::Duration::seconds(DEFAULT_REFRESH_JITTER_SECONDS),
+            current_jitter: RwLock::new(jitter(chrono::Duration::seconds(DEFAULT_REFRESH_JITTER_SECONDS))),
+            generation: AtomicU64::new(0),
+            observer: None,
+            metrics: None,
+            clock: system_clock(),
+        }
+    }
+
+    // The token which doesn't live longer than the margin and the jitter is fetched on every call, for example
+    // the self-signed JWT, whose ServiceAccountTokenSource::cached validates its lifetime against its margin instead.
+    pub fn with_refresh_margin(mut self, refresh_margin: chrono::Duration) -> ReuseTokenSource {
+        self.refresh_margin = refresh_margin;
+        self
+    }
+
+    // The maximum of the random duration added to the refresh margin. Zero disables the jitter.
+    pub fn with_refresh_jitter(mut self, refresh_jitter: chrono::Duration) -> ReuseTokenSource {
+        self.refresh_jitter = refresh_jitter;
+        *self.current_jitter.write().unwrap() = jitter(refresh_jitter);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ReuseTokenSource {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_refresh_observer(mut self, observer: Arc<dyn RefreshObserver>) -> ReuseTokenSource {
+        self.observer = Some(observer);
+        self
+    }
+
+    // Records the cache hits and misses, and the latency and the errors of the refreshes.
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn TokenMetricsSink>) -> ReuseTokenSource {
+        self.metrics = Some(sink);
+        self
+    }
+
+    fn cached_token(&self) -> Option<Token> {
+        if self.target.revoked() {
+            return None;
+        }
+        let r_lock = self.current_token.read().unwrap();
+        match r_lock.as_ref() {
+            Some(token) if self.fresh(token) => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    fn fresh(&self, token: &Token) -> bool {
+        let margin = self.refresh_margin + *self.current_jitter.read().unwrap();
+        token.cacheable() && !token.access_token.is_empty() && !token.expires_within_at(self.clock.now(), margin)
+    }
+
+    fn store(&self, token: &Token) {
+        *self.current_jitter.write().unwrap() = jitter(self.refresh_jitter);
+        *self.current_token.write().unwrap() = Some(token.clone());
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Refreshes the token unless another caller is refreshing it, in which case waits for that caller and returns
+    // its result. fresh returns the token which makes the refresh unnecessary, which is checked before waiting.
+    // The failed refresh returns Error::Shared to the refreshing caller and the waiting ones alike, so that the shape
+    // of the error doesn't depend on the concurrency. Error::root returns the error of the refresh.
+    async fn single_flight(&self, fresh: impl Fn() -> Option<Token>) -> Result<Token, Error> {
+        let id = loop {
+            if let Some(token) = fresh() {
+                return Ok(token);
+            }
+            // the waiter is registered before the lock is released, so that the notification is not missed.
+            let (id, notified) = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.current {
+                    Some(id) => {
+                        flights.waiters += 1;
+                        (id, self.refreshed.notified())
+                    }
+                    None => {
+                        flights.last_id += 1;
+                        flights.current = Some(flights.last_id);
+                        flights.waiters = 0;
+                        break flights.last_id;
+                    }
+                }
+            };
+            notified.await;
+            // the result is missing if the refreshing caller was cancelled, in which case one of the waiters refreshes.
+            if let Some((last_id, result)) = &self.flights.lock().unwrap().last_result {
+                if *last_id == id {
+                    return result.clone().map_err(Error::Shared);
+                }
+            }
+        };
+
+        let flight = Flight(id, self);
+        if let Some(observer) = &self.observer {
+            observer.on_refresh_start();
         }
+        let start = Instant::now();
+        let result = self.target.token().await;
+        if let Ok(token) = &result {
+            self.store(token);
+        }
+        let result = result.map_err(Arc::new);
+        {
+            let mut flights = self.flights.lock().unwrap();
+            flights.current = None;
+            flights.last_result = match flights.waiters {
+                0 => None,
+                _ => Some((id, result.clone())),
+            };
+        }
+        let result = result.map_err(Error::Shared);
+        drop(flight);
+        let latency = start.elapsed();
+        if let Some(metrics) = &self.metrics {
+            metrics.record_refresh_latency(latency);
+            if let Err(e) = &result {
+                metrics.record_refresh_error(ErrorKind::of(e));
+            }
+        }
+        if let Some(observer) = &self.observer {
+            match &result {
+                Ok(token) => observer.on_refresh_success(token.expiry, latency),
+                Err(e) => observer.on_refresh_error(e),
+            }
+        }
+        result
+    }
+}
+
+fn jitter(max: chrono::Duration) -> chrono::Duration {
+    match max.num_milliseconds() {
+        max if max > 0 => chrono::Duration::milliseconds(rand::thread_rng().gen_range(0..=max)),
+        _ => chrono::Duration::zero(),
     }
 }
 
 #[async_trait]
 impl TokenSource for ReuseTokenSource {
     async fn token(&self) -> Result<Token, Error> {
-        {
-            let r_lock = self.current_token.read().unwrap();
-            if r_lock.valid() {
-                return Ok(Token {
-                    access_token: r_lock.access_token.to_string(),
-                    token_type: r_lock.token_type.to_string(),
-                    expiry: r_lock.expiry,
-                });
-            }
-        }
-        let token = self.target.token().await?;
-        *self.current_token.write().unwrap() = token.clone();
-        return Ok(token);
+        if let Some(token) = self.cached_token() {
+            if let Some(metrics) = &self.metrics {
+                metrics.record_cache_hit();
+            }
+            return Ok(token);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record_cache_miss();
+        }
+        // another task may have refreshed the token while we were waiting for it.
+        self.single_flight(|| self.cached_token()).await
+    }
+
+    async fn refresh(&self) -> Result<Token, Error> {
+        let seen = self.generation.load(Ordering::SeqCst);
+        // the token refreshed by another caller while we were waiting for it is new enough.
+        self.single_flight(|| {
+            if self.generation.load(Ordering::SeqCst) == seen || self.target.revoked() {
+                return None;
+            }
+            self.current_token.read().unwrap().clone()
+        })
+        .await
+    }
+
+    fn invalidate(&self, token: &Token) {
+        let mut current_token = self.current_token.write().unwrap();
+        if matches!(current_token.as_ref(), Some(current) if current.access_token == token.access_token) {
+            *current_token = None;
+        }
+    }
+
+    fn revoked(&self) -> bool {
+        self.target.revoked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::FakeClock;
+    use crate::error::Error;
+    use crate::testing::{CountingTokenSource, FailingTokenSource};
+    use crate::token::Token;
+    use crate::token_source::reuse_token_source::{jitter, Flight, RefreshObserver, ReuseTokenSource};
+    use crate::token_source::{TokenSource, TokenSourceExt};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // The token is returned after the delay, so that the concurrent callers wait for the same refresh.
+    fn counting(lifetime: chrono::Duration) -> Arc<CountingTokenSource> {
+        Arc::new(
+            CountingTokenSource::new()
+                .with_lifetime(lifetime)
+                .with_delay(Duration::from_millis(50)),
+        )
+    }
+
+    async fn hammer(ts: Arc<ReuseTokenSource>) -> Vec<String> {
+        let mut tasks = Vec::with_capacity(100);
+        for _ in 0..100 {
+            let ts = ts.clone();
+            tasks.push(tokio::spawn(async move { ts.token().await.unwrap().access_token }));
+        }
+        let mut tokens = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            tokens.push(task.await.unwrap());
+        }
+        tokens
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_first_fetch() {
+        let target = counting(chrono::Duration::hours(1));
+        let ts = Arc::new(target.clone().cached());
+        let tokens = hammer(ts.clone()).await;
+        assert_eq!(1, target.calls());
+        assert!(tokens.iter().all(|t| t == "token-1"));
+
+        hammer(ts).await;
+        assert_eq!(1, target.calls());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_refresh_within_margin() {
+        let stale = Token {
+            access_token: "stale".to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: Some(chrono::Utc::now() + chrono::Duration::seconds(60)),
+            id_token: None,
+        };
+        let target = counting(chrono::Duration::hours(1));
+        let ts = Arc::new(ReuseTokenSource::new(Box::new(target.clone()), Some(stale)));
+        let tokens = hammer(ts).await;
+        assert_eq!(1, target.calls());
+        assert!(tokens.iter().all(|t| t == "token-1"));
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        let clock = Arc::new(FakeClock::new(chrono::Utc::now()));
+        let target = counting(chrono::Duration::hours(1));
+        let ts = ReuseTokenSource::new(Box::new(target.clone()), None).with_clock(clock.clone());
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+
+        clock.advance(chrono::Duration::minutes(50));
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+
+        // the token is refreshed within the refresh margin.
+        clock.advance(chrono::Duration::minutes(7));
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_refresh() {
+        let target = counting(chrono::Duration::hours(1));
+        let ts = Arc::new(target.clone().cached());
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+
+        // for example, all the requests with the token are rejected with 401 at once.
+        let mut tasks = Vec::with_capacity(100);
+        for _ in 0..100 {
+            let ts = ts.clone();
+            tasks.push(tokio::spawn(async move { ts.refresh().await.unwrap().access_token }));
+        }
+        for task in tasks {
+            assert_eq!("token-2", task.await.unwrap());
+        }
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_jitter() {
+        let now = chrono::Utc::now();
+        let token = Token {
+            access_token: "initial".to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: Some(now + chrono::Duration::hours(1)),
+            id_token: None,
+        };
+        let new_source = |clock: Arc<FakeClock>| {
+            let target = counting(chrono::Duration::hours(1));
+            ReuseTokenSource::new(Box::new(target.clone()), Some(token.clone()))
+                .with_refresh_jitter(chrono::Duration::seconds(60))
+                .with_clock(clock)
+        };
+
+        // the threshold is between the margin and the margin plus the jitter, besides the skew of 10 seconds.
+        let before = Arc::new(FakeClock::new(
+            now + chrono::Duration::hours(1) - chrono::Duration::seconds(296),
+        ));
+        assert_eq!("initial", new_source(before).token().await.unwrap().access_token);
+        let after = Arc::new(FakeClock::new(
+            now + chrono::Duration::hours(1) - chrono::Duration::seconds(225),
+        ));
+        assert_eq!("token-1", new_source(after).token().await.unwrap().access_token);
+
+        assert!(jitter(chrono::Duration::zero()).is_zero());
+        for _ in 0..100 {
+            let jitter = jitter(chrono::Duration::seconds(30));
+            assert!(jitter >= chrono::Duration::zero() && jitter <= chrono::Duration::seconds(30));
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl RefreshObserver for RecordingObserver {
+        fn on_refresh_start(&self) {
+            self.events.lock().unwrap().push("start".to_string());
+        }
+
+        fn on_refresh_success(&self, expiry: Option<chrono::DateTime<chrono::Utc>>, latency: Duration) {
+            assert!(expiry.is_some());
+            assert!(latency >= Duration::from_millis(50));
+            self.events.lock().unwrap().push("success".to_string());
+        }
+
+        fn on_refresh_error(&self, error: &Error) {
+            self.events.lock().unwrap().push(format!("error: {}", error));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refresh_observer() {
+        let observer = Arc::new(RecordingObserver::default());
+        let target = counting(chrono::Duration::hours(1));
+        let ts = Arc::new(ReuseTokenSource::new(Box::new(target), None).with_refresh_observer(observer.clone()));
+        // the concurrent callers share the single refresh.
+        hammer(ts.clone()).await;
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        assert_eq!(vec!["start", "success"], *observer.events.lock().unwrap());
+
+        let observer = Arc::new(RecordingObserver::default());
+        let cached = ts.token().await.unwrap();
+        let target = FailingTokenSource::new(|| Error::InvalidToken("unavailable".to_string()));
+        let ts = ReuseTokenSource::new(Box::new(target), Some(cached)).with_refresh_observer(observer.clone());
+        assert!(ts.refresh().await.is_err());
+        assert_eq!(
+            vec!["start", "error: invalid token: unavailable"],
+            *observer.events.lock().unwrap()
+        );
+        // the token is still cached after the failed refresh.
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_failed_refresh() {
+        let target = Arc::new(
+            FailingTokenSource::new(|| Error::InvalidToken("unavailable".to_string()))
+                .with_delay(Duration::from_millis(50)),
+        );
+        let ts = Arc::new(target.clone().cached());
+        let mut tasks = Vec::with_capacity(100);
+        for _ in 0..100 {
+            let ts = ts.clone();
+            tasks.push(tokio::spawn(async move { ts.token().await }));
+        }
+        // the waiters get the error of the refresh they waited for instead of refreshing one after another,
+        // in the same shape as the refreshing caller.
+        for task in tasks {
+            match task.await.unwrap() {
+                Err(e @ Error::Shared(_)) => assert!(matches!(e.root(), Error::InvalidToken(_))),
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        assert_eq!(1, target.calls());
+
+        // the next caller refreshes again, and gets the error in the same shape without the waiters.
+        match ts.token().await {
+            Err(e @ Error::Shared(_)) => assert!(matches!(e.root(), Error::InvalidToken(_))),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_refresh() {
+        let target = counting(chrono::Duration::hours(1));
+        let ts = target.clone().cached();
+        // the caller gives up while refreshing, then the next caller refreshes the token.
+        assert!(tokio::time::timeout(Duration::from_millis(10), ts.token())
+            .await
+            .is_err());
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refresh_started_while_another_finishes() {
+        let target = counting(chrono::Duration::hours(1));
+        let ts = Arc::new(target.clone().cached());
+        // the refresh which has stored its result, but not dropped its flight yet.
+        let finishing = {
+            let mut flights = ts.flights.lock().unwrap();
+            flights.last_id += 1;
+            Flight(flights.last_id, &ts)
+        };
+        let refresh = {
+            let ts = ts.clone();
+            tokio::spawn(async move { ts.refresh().await.unwrap().access_token })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let waiters: Vec<_> = (0..10)
+            .map(|_| {
+                let ts = ts.clone();
+                tokio::spawn(async move { ts.token().await.unwrap().access_token })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // the waiters keep waiting for the refresh started in the meantime.
+        drop(finishing);
+        assert_eq!("token-1", refresh.await.unwrap());
+        for waiter in waiters {
+            assert_eq!("token-1", waiter.await.unwrap());
+        }
+        assert_eq!(1, target.calls());
+    }
+
+    #[tokio::test]
+    async fn test_token_without_expiry() {
+        let unknown = Token {
+            access_token: "unknown".to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: None,
+            id_token: None,
+        };
+        let target = counting(chrono::Duration::hours(1));
+        let ts = ReuseTokenSource::new(Box::new(target.clone()), Some(unknown));
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        assert_eq!(1, target.calls());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refresh_margin() {
+        let target = counting(chrono::Duration::seconds(120));
+        let ts =
+            ReuseTokenSource::new(Box::new(target.clone()), None).with_refresh_margin(chrono::Duration::seconds(60));
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        assert_eq!(1, target.calls());
+
+        let ts = ts.with_refresh_margin(chrono::Duration::seconds(180));
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let target = counting(chrono::Duration::hours(1));
+        let ts = target.clone().cached();
+        assert_eq!("token-1", ts.token().await.unwrap().access_token);
+        assert_eq!("token-2", ts.refresh().await.unwrap().access_token);
+        // the refreshed token is cached.
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let target = counting(chrono::Duration::hours(1));
+        let ts = target.clone().cached();
+        let rejected = ts.token().await.unwrap();
+        ts.invalidate(&rejected);
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+
+        // the token already replaced by another caller is kept.
+        ts.invalidate(&rejected);
+        assert_eq!("token-2", ts.token().await.unwrap().access_token);
+        assert_eq!(2, target.calls());
     }
 }
@@ -1,155 +1,709 @@
+use crate::clock::{system_clock, Clock};
 use crate::credentials;
 use crate::error::Error;
-use crate::misc::UnwrapOrEmpty;
+use crate::http_client::{default_http_client, HttpClient};
 use crate::token::{Token, TOKEN_URL};
+use crate::token_source::retry::{request_with_retry, RetryPolicy};
+use crate::token_source::reuse_token_source::{
+    ReuseTokenSource, DEFAULT_REFRESH_JITTER_SECONDS, DEFAULT_REFRESH_MARGIN_SECONDS,
+};
 use crate::token_source::TokenSource;
-use crate::token_source::{default_https_client, InternalToken, ResponseExtension};
+use crate::token_source::{form_body, jwt_expiry, InternalToken, ResponseExtension};
 use async_trait::async_trait;
-use hyper::client::HttpConnector;
 use hyper::http::{Method, Request};
 use serde::{Deserialize, Serialize};
-use crate::credentials::CredentialsFile;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Google rejects the JWT whose exp is more than 1 hour after its iat.
+pub const MAX_JWT_LIFETIME: Duration = Duration::from_secs(3600);
+
+fn validate_jwt_lifetime(lifetime: Duration) -> Result<Duration, Error> {
+    if lifetime.as_secs() == 0 || lifetime > MAX_JWT_LIFETIME || lifetime.subsec_nanos() != 0 {
+        return Err(Error::InvalidJwtLifetime(lifetime));
+    }
+    Ok(lifetime)
+}
+
+// The JWT whose exp is moved back by the skew of the lifetime or more is expired when it is signed.
+fn validate_clock_skew(lifetime: Duration, skew: Duration) -> Result<(), Error> {
+    if skew >= lifetime {
+        return Err(Error::InvalidJwtClockSkew { skew, lifetime });
+    }
+    Ok(())
+}
+
+// Returns the refresh margin and jitter of the cache of the self-signed JWT, whose expiry follows its exp.
+// They are shortened to a quarter of the lifetime after the skew, so that the short-lived JWT is still reused.
+pub(crate) fn jwt_refresh_margin(
+    lifetime: Duration,
+    skew: Duration,
+    margin: chrono::Duration,
+    jitter: chrono::Duration,
+) -> (chrono::Duration, chrono::Duration) {
+    let quarter = chrono::Duration::seconds(lifetime.saturating_sub(skew).as_secs() as i64) / 4;
+    if margin <= quarter {
+        return (margin, jitter);
+    }
+    // the jitter is scaled by the same ratio as the margin.
+    let scaled =
+        jitter.num_milliseconds() as i128 * quarter.num_milliseconds() as i128 / margin.num_milliseconds() as i128;
+    (quarter, chrono::Duration::milliseconds(scaled as i64))
+}
+
+// Returns the iat and exp of the JWT. The iat is moved back by the skew for the verifiers whose clock is behind,
+// and the exp follows it so that the JWT never lives longer than the lifetime.
+fn jwt_times(
+    clock: &dyn Clock,
+    lifetime: Duration,
+    skew: Duration,
+) -> (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>) {
+    let iat = clock.now() - chrono::Duration::seconds(skew.as_secs() as i64);
+    (iat, iat + chrono::Duration::seconds(lifetime.as_secs() as i64))
+}
 
 #[derive(Clone, Serialize)]
 struct Claims<'a> {
     iss: &'a str,
     sub: Option<&'a str>,
     scope: Option<&'a str>,
-    aud: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
     exp: i64,
     iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_audience: Option<&'a str>,
 }
 
 impl Claims<'_> {
-    fn token(&self, pk: &jwt::EncodingKey, pk_id: &str) -> Result<String, Error> {
-        let mut header = jwt::Header::new(jwt::Algorithm::RS256);
-        header.kid = Some(pk_id.to_string());
+    fn token(&self, pk: &jwt::EncodingKey, algorithm: jwt::Algorithm, pk_id: &str) -> Result<String, Error> {
+        let mut header = jwt::Header::new(algorithm);
+        // The key of the p12 file has no id, which the token endpoint doesn't need.
+        header.kid = Some(pk_id.to_string()).filter(|id| !id.is_empty());
         let v = jwt::encode(&header, self, pk)?;
         Ok(v)
     }
 }
 
 // Does not use any OAuth2 flow but instead creates a JWT and sends that as the access token.
+// Google finds the public key by the private_key_id, so it is required in addition to the client_email.
 // The audience is typically a URL that specifies the scope of the credentials.
+// Instead of the audience, the JWT can have the scope claim, which most googleapis.com services accept.
 // see golang.org/x/oauth2/gen/jwt.go
 pub struct ServiceAccountTokenSource {
     email: String,
     pk: jwt::EncodingKey,
+    algorithm: jwt::Algorithm,
     pk_id: String,
-    audience: String,
+    audience: Option<String>,
+    scopes: Option<String>,
+    lifetime: Duration,
+    clock_skew: Duration,
+    // The refresh margin and its jitter of the cache returned by cached, before they are shortened for the JWT.
+    refresh_margin: chrono::Duration,
+    refresh_jitter: chrono::Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl ServiceAccountTokenSource {
-    pub(crate) fn new(cred: &credentials::CredentialsFile, audience: &str) -> Result<ServiceAccountTokenSource, Error> {
+    pub fn new(cred: &credentials::CredentialsFile, audience: &str) -> Result<ServiceAccountTokenSource, Error> {
+        cred.ensure_type(credentials::SERVICE_ACCOUNT_KEY)?;
+        let (pk, algorithm) = cred.try_to_private_key()?;
         Ok(ServiceAccountTokenSource {
-            email: cred.client_email.unwrap_or_empty(),
-            pk: cred.try_to_private_key()?,
-            pk_id: cred.private_key_id.unwrap_or_empty(),
+            email: cred.required_str("client_email", &cred.client_email)?.to_string(),
+            pk,
+            algorithm,
+            pk_id: cred.required_str("private_key_id", &cred.private_key_id)?.to_string(),
             audience: match &cred.audience {
-                None => audience.to_string(),
-                Some(s) => s.to_string(),
+                None => Some(audience.to_string()),
+                Some(s) => Some(s.to_string()),
             },
+            scopes: None,
+            lifetime: MAX_JWT_LIFETIME,
+            clock_skew: Duration::ZERO,
+            refresh_margin: chrono::Duration::seconds(DEFAULT_REFRESH_MARGIN_SECONDS),
+            refresh_jitter: chrono::Duration::seconds(DEFAULT_REFRESH_JITTER_SECONDS),
+            clock: system_clock(),
         })
     }
+
+    // Creates the token source whose JWT has the space-delimited scopes instead of the audience.
+    pub fn new_with_scopes(
+        cred: &credentials::CredentialsFile,
+        scopes: &str,
+    ) -> Result<ServiceAccountTokenSource, Error> {
+        cred.ensure_type(credentials::SERVICE_ACCOUNT_KEY)?;
+        let (pk, algorithm) = cred.try_to_private_key()?;
+        Ok(ServiceAccountTokenSource {
+            email: cred.required_str("client_email", &cred.client_email)?.to_string(),
+            pk,
+            algorithm,
+            pk_id: cred.required_str("private_key_id", &cred.private_key_id)?.to_string(),
+            audience: None,
+            scopes: Some(scopes.to_string()),
+            lifetime: MAX_JWT_LIFETIME,
+            clock_skew: Duration::ZERO,
+            refresh_margin: chrono::Duration::seconds(DEFAULT_REFRESH_MARGIN_SECONDS),
+            refresh_jitter: chrono::Duration::seconds(DEFAULT_REFRESH_JITTER_SECONDS),
+            clock: system_clock(),
+        })
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // The lifetime of the JWT in whole seconds, up to MAX_JWT_LIFETIME which is the default.
+    // The cache returned by cached refreshes the JWT a quarter of its lifetime before its exp at the latest.
+    pub fn with_jwt_lifetime(mut self, lifetime: Duration) -> Result<Self, Error> {
+        validate_clock_skew(validate_jwt_lifetime(lifetime)?, self.clock_skew)?;
+        self.lifetime = lifetime;
+        Ok(self)
+    }
+
+    // Subtracted from the iat and the exp of the JWT, in whole seconds. No skew by default.
+    pub fn with_clock_skew(mut self, skew: Duration) -> Result<Self, Error> {
+        validate_clock_skew(self.lifetime, skew)?;
+        self.clock_skew = skew;
+        Ok(self)
+    }
+
+    // The refresh margin and jitter of the cache returned by cached, which default to the ones of ReuseTokenSource.
+    // They are shortened for the JWT living less than 4 times the margin, so wrap the token source with cached
+    // instead of ReuseTokenSource to cache it.
+    pub fn with_refresh_margin(mut self, margin: chrono::Duration, jitter: chrono::Duration) -> Self {
+        self.refresh_margin = margin;
+        self.refresh_jitter = jitter;
+        self
+    }
+
+    // Wraps the token source with the cache whose refresh margin is shortened for the lifetime of the JWT.
+    pub fn cached(self) -> ReuseTokenSource {
+        let (margin, jitter) =
+            jwt_refresh_margin(self.lifetime, self.clock_skew, self.refresh_margin, self.refresh_jitter);
+        ReuseTokenSource::new(Box::new(self), None)
+            .with_refresh_margin(margin)
+            .with_refresh_jitter(jitter)
+    }
 }
 
 #[async_trait]
 impl TokenSource for ServiceAccountTokenSource {
     async fn token(&self) -> Result<Token, Error> {
-        let iat = chrono::Utc::now();
-        let exp = iat + chrono::Duration::hours(1);
+        let (iat, exp) = jwt_times(self.clock.as_ref(), self.lifetime, self.clock_skew);
 
         let token = Claims {
             iss: self.email.as_ref(),
             sub: Some(self.email.as_ref()),
-            scope: None,
-            aud: self.audience.as_ref(),
+            scope: self.scopes.as_deref(),
+            aud: self.audience.as_deref(),
             exp: exp.timestamp(),
             iat: iat.timestamp(),
+            target_audience: None,
         }
-        .token(&self.pk, &self.pk_id)?;
+        .token(&self.pk, self.algorithm, &self.pk_id)?;
 
-        return Ok(Token {
+        Ok(Token {
             access_token: token,
             token_type: "Bearer".to_string(),
             expiry: Some(exp),
-        });
+            id_token: None,
+        })
     }
 }
 
-#[derive(Clone, Deserialize)]
-struct OAuth2Token {
-    pub access_token: String,
-    pub token_type: String,
-    pub id_token: Option<String>,
-    pub expires_in: Option<i64>,
-}
-
 //jwt implements the OAuth 2.0 JSON Web Token flow
+// The empty pk_id omits the kid header, for the key of the p12 file.
 pub struct OAuth2ServiceAccountTokenSource {
     pub email: String,
     pub delegation_email: Option<String>,
     pub pk: jwt::EncodingKey,
+    pub algorithm: jwt::Algorithm,
     pub pk_id: String,
     pub scopes: String,
+    // Sends the scopes also as the scope parameter of the form, which some token endpoints require.
+    pub scope_in_body: bool,
     pub token_url: String,
+    pub retry_policy: RetryPolicy,
+    // The lifetime of the assertion sent to the token endpoint, not of the access token.
+    pub jwt_lifetime: Duration,
+    pub clock_skew: Duration,
+    pub clock: Arc<dyn Clock>,
 
-    pub client: hyper::Client<hyper_tls::HttpsConnector<HttpConnector>>,
+    pub client: Arc<dyn HttpClient>,
 }
 
 impl OAuth2ServiceAccountTokenSource {
-    pub(crate) fn new(
+    pub fn new(
         cred: &credentials::CredentialsFile,
         scopes: &str,
-        delegation_email: Option<&str>
+        delegation_email: Option<&str>,
     ) -> Result<OAuth2ServiceAccountTokenSource, Error> {
+        cred.ensure_type(credentials::SERVICE_ACCOUNT_KEY)?;
+        let (pk, algorithm) = cred.try_to_private_key()?;
         Ok(OAuth2ServiceAccountTokenSource {
-            email: cred.client_email.unwrap_or_empty(),
-            delegation_email: match delegation_email {
-                Some(email) => Some(email.to_string()),
-                None => None
-            },
-            pk: cred.try_to_private_key()?,
-            pk_id: cred.private_key_id.unwrap_or_empty(),
+            email: cred.required_str("client_email", &cred.client_email)?.to_string(),
+            delegation_email: delegation_email.map(|s| s.to_string()),
+            pk,
+            algorithm,
+            pk_id: cred.private_key_id.clone().unwrap_or_default(),
             scopes: scopes.to_string(),
+            scope_in_body: false,
             token_url: match &cred.token_uri {
                 None => TOKEN_URL.to_string(),
                 Some(s) => s.to_string(),
             },
-            client: default_https_client(),
+            retry_policy: RetryPolicy::default(),
+            jwt_lifetime: MAX_JWT_LIFETIME,
+            clock_skew: Duration::ZERO,
+            clock: system_clock(),
+            client: default_http_client(),
         })
     }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_jwt_lifetime(mut self, lifetime: Duration) -> Result<Self, Error> {
+        validate_clock_skew(validate_jwt_lifetime(lifetime)?, self.clock_skew)?;
+        self.jwt_lifetime = lifetime;
+        Ok(self)
+    }
+
+    pub fn with_clock_skew(mut self, skew: Duration) -> Result<Self, Error> {
+        validate_clock_skew(self.jwt_lifetime, skew)?;
+        self.clock_skew = skew;
+        Ok(self)
+    }
+
+    pub fn with_scope_in_body(mut self, scope_in_body: bool) -> Self {
+        self.scope_in_body = scope_in_body;
+        self
+    }
 }
 
 #[async_trait]
 impl TokenSource for OAuth2ServiceAccountTokenSource {
     async fn token(&self) -> Result<Token, Error> {
-        let iat = chrono::Utc::now();
-        let exp = iat + chrono::Duration::hours(1);
+        let now = self.clock.now();
+        let (iat, exp) = jwt_times(self.clock.as_ref(), self.jwt_lifetime, self.clock_skew);
 
         let request_token = Claims {
             iss: self.email.as_ref(),
             sub: self.delegation_email.as_deref(),
             scope: Some(self.scopes.as_ref()),
-            aud: self.token_url.as_ref(),
+            aud: Some(self.token_url.as_ref()),
             exp: exp.timestamp(),
             iat: iat.timestamp(),
+            target_audience: None,
+        }
+        .token(&self.pk, self.algorithm, &self.pk_id)?;
+
+        let mut params = vec![
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", request_token.as_str()),
+        ];
+        if self.scope_in_body && !self.scopes.is_empty() {
+            params.push(("scope", self.scopes.as_str()));
         }
-        .token(&self.pk, &self.pk_id)?;
+        let body = form_body(&params);
 
-        let body = hyper::Body::from(format!(
-            "grant_type=urn:ietf:params:oauth:grant-type:jwt-bearer&assertion={}",
-            request_token.as_str()
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.token_url.as_str())
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .body(hyper::Body::from(body.clone()))?)
+        })
+        .await?;
+        let it: InternalToken = response.deserialize().await?;
+
+        Ok(it.to_token(now))
+    }
+}
+
+#[derive(Deserialize)]
+struct IdTokenResponse {
+    id_token: String,
+}
+
+// Exchanges the JWT with the target_audience claim for the ID token signed by Google.
+// https://cloud.google.com/run/docs/authenticating/service-to-service
+pub struct ServiceAccountIdTokenSource {
+    email: String,
+    pk: jwt::EncodingKey,
+    algorithm: jwt::Algorithm,
+    pk_id: String,
+    audience: String,
+    token_url: String,
+    retry_policy: RetryPolicy,
+    jwt_lifetime: Duration,
+    clock_skew: Duration,
+    clock: Arc<dyn Clock>,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl ServiceAccountIdTokenSource {
+    pub fn new(cred: &credentials::CredentialsFile, audience: &str) -> Result<ServiceAccountIdTokenSource, Error> {
+        cred.ensure_type(credentials::SERVICE_ACCOUNT_KEY)?;
+        let (pk, algorithm) = cred.try_to_private_key()?;
+        Ok(ServiceAccountIdTokenSource {
+            email: cred.required_str("client_email", &cred.client_email)?.to_string(),
+            pk,
+            algorithm,
+            pk_id: cred.private_key_id.clone().unwrap_or_default(),
+            audience: audience.to_string(),
+            token_url: match &cred.token_uri {
+                None => TOKEN_URL.to_string(),
+                Some(s) => s.to_string(),
+            },
+            retry_policy: RetryPolicy::default(),
+            jwt_lifetime: MAX_JWT_LIFETIME,
+            clock_skew: Duration::ZERO,
+            clock: system_clock(),
+            client: default_http_client(),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_jwt_lifetime(mut self, lifetime: Duration) -> Result<Self, Error> {
+        validate_clock_skew(validate_jwt_lifetime(lifetime)?, self.clock_skew)?;
+        self.jwt_lifetime = lifetime;
+        Ok(self)
+    }
+
+    pub fn with_clock_skew(mut self, skew: Duration) -> Result<Self, Error> {
+        validate_clock_skew(self.jwt_lifetime, skew)?;
+        self.clock_skew = skew;
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl TokenSource for ServiceAccountIdTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        let (iat, exp) = jwt_times(self.clock.as_ref(), self.jwt_lifetime, self.clock_skew);
+
+        let request_token = Claims {
+            iss: self.email.as_ref(),
+            sub: Some(self.email.as_ref()),
+            scope: None,
+            aud: Some(self.token_url.as_ref()),
+            exp: exp.timestamp(),
+            iat: iat.timestamp(),
+            target_audience: Some(self.audience.as_ref()),
+        }
+        .token(&self.pk, self.algorithm, &self.pk_id)?;
+
+        let body = form_body(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &request_token),
+        ]);
+        let response = request_with_retry(self.client.as_ref(), &self.retry_policy, || {
+            Ok(Request::builder()
+                .method(Method::POST)
+                .uri(self.token_url.as_str())
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .body(hyper::Body::from(body.clone()))?)
+        })
+        .await?;
+        let response: IdTokenResponse = response.deserialize().await?;
+        Ok(Token {
+            expiry: jwt_expiry(&response.id_token),
+            id_token: Some(response.id_token.clone()),
+            access_token: response.id_token,
+            token_type: "Bearer".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::FakeClock;
+    use crate::credentials::CredentialsFile;
+    use crate::error::Error;
+    use crate::mock_server::{self, claims};
+    use crate::token_source::service_account_token_source::{
+        jwt_refresh_margin, OAuth2ServiceAccountTokenSource, ServiceAccountIdTokenSource, ServiceAccountTokenSource,
+    };
+    use crate::token_source::TokenSource;
+    use chrono::TimeZone;
+    use hyper::{Body, Response};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_self_signed_jwt_with_scopes() {
+        let cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        let scopes = "https://www.googleapis.com/auth/cloud-platform https://www.googleapis.com/auth/spanner.data";
+        let ts = ServiceAccountTokenSource::new_with_scopes(&cred, scopes).unwrap();
+        let token = ts.token().await.unwrap();
+        let claims = claims(&token.access_token);
+        assert_eq!(scopes, claims["scope"]);
+        assert!(claims.get("aud").is_none());
+        assert_eq!(claims["exp"], token.expiry.unwrap().timestamp());
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_jwt_with_clock() {
+        let cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        let now = chrono::Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let ts = ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/")
+            .unwrap()
+            .with_clock(Arc::new(FakeClock::new(now)));
+        let token = ts.token().await.unwrap();
+        let claims = claims(&token.access_token);
+        assert_eq!(1_700_000_000, claims["iat"]);
+        assert_eq!(1_700_003_600, claims["exp"]);
+        assert_eq!(now + chrono::Duration::hours(1), token.expiry.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_jwt_lifetime_and_clock_skew() {
+        let cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        let now = chrono::Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let ts = ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/")
+            .unwrap()
+            .with_clock(Arc::new(FakeClock::new(now)))
+            .with_jwt_lifetime(Duration::from_secs(600))
+            .unwrap()
+            .with_clock_skew(Duration::from_secs(30))
+            .unwrap();
+        let token = ts.token().await.unwrap();
+        let claims = claims(&token.access_token);
+        assert_eq!(1_699_999_970, claims["iat"]);
+        assert_eq!(1_700_000_570, claims["exp"]);
+        // the cache refreshes by the exp of the JWT.
+        assert_eq!(claims["exp"], token.expiry.unwrap().timestamp());
+
+        for lifetime in [Duration::ZERO, Duration::from_secs(3601), Duration::from_millis(1500)] {
+            assert!(matches!(
+                ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/")
+                    .unwrap()
+                    .with_jwt_lifetime(lifetime),
+                Err(Error::InvalidJwtLifetime(_))
+            ));
+        }
+
+        // the JWT must not be expired when it is signed.
+        assert!(matches!(
+            OAuth2ServiceAccountTokenSource::new(&cred, "scope", None)
+                .unwrap()
+                .with_jwt_lifetime(Duration::from_secs(60))
+                .unwrap()
+                .with_clock_skew(Duration::from_secs(60)),
+            Err(Error::InvalidJwtClockSkew { .. })
+        ));
+        assert!(OAuth2ServiceAccountTokenSource::new(&cred, "scope", None)
+            .unwrap()
+            .with_jwt_lifetime(Duration::from_secs(60))
+            .unwrap()
+            .with_clock_skew(Duration::from_secs(59))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_jwt_refresh_margin() {
+        let (margin, jitter) = (chrono::Duration::seconds(225), chrono::Duration::seconds(30));
+        let refresh_margin = |lifetime, skew| {
+            jwt_refresh_margin(Duration::from_secs(lifetime), Duration::from_secs(skew), margin, jitter)
+        };
+        assert_eq!((margin, jitter), refresh_margin(3600, 0));
+        assert_eq!((margin, jitter), refresh_margin(900, 0));
+        assert_eq!(
+            (chrono::Duration::seconds(15), chrono::Duration::seconds(2)),
+            refresh_margin(60, 0)
+        );
+        assert_eq!(
+            (chrono::Duration::seconds(50), chrono::Duration::milliseconds(6666)),
+            refresh_margin(600, 400)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_short_self_signed_jwt_is_cached() {
+        let cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        let clock = Arc::new(FakeClock::new(chrono::Utc::now()));
+        // the JWT living shorter than the default margin is reused until a quarter of its lifetime is left.
+        let ts = ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/")
+            .unwrap()
+            .with_jwt_lifetime(Duration::from_secs(60))
+            .unwrap()
+            .with_clock(clock.clone())
+            .cached()
+            .with_clock(clock.clone());
+        let token = ts.token().await.unwrap();
+        clock.advance(chrono::Duration::seconds(30));
+        assert_eq!(token.access_token, ts.token().await.unwrap().access_token);
+        clock.advance(chrono::Duration::seconds(10));
+        assert_ne!(token.access_token, ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_jwt_with_custom_refresh_margin() {
+        let cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        let clock = Arc::new(FakeClock::new(chrono::Utc::now()));
+        let ts = ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/")
+            .unwrap()
+            .with_refresh_margin(chrono::Duration::minutes(10), chrono::Duration::minutes(1))
+            .with_clock(clock.clone())
+            .cached()
+            .with_clock(clock.clone());
+        let token = ts.token().await.unwrap();
+        clock.advance(chrono::Duration::seconds(2900));
+        assert_eq!(token.access_token, ts.token().await.unwrap().access_token);
+        clock.advance(chrono::Duration::seconds(90));
+        assert_ne!(token.access_token, ts.token().await.unwrap().access_token);
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_jwt_with_ec_key() {
+        let cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account_ec.json")).unwrap();
+        let ts = ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/").unwrap();
+        let token = ts.token().await.unwrap();
+
+        let header = jwt::decode_header(&token.access_token).unwrap();
+        assert_eq!(jwt::Algorithm::ES256, header.alg);
+        assert_eq!(cred.private_key_id, header.kid);
+
+        let key = jwt::DecodingKey::from_ec_pem(include_bytes!("../../testdata/ec_public_key.pem")).unwrap();
+        let validation = jwt::Validation::new(jwt::Algorithm::ES256);
+        assert!(jwt::decode::<json::Value>(&token.access_token, &key, &validation).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_required_fields() {
+        let mut cred = CredentialsFile::new_from_file("testdata/service_account.json")
+            .await
+            .unwrap();
+        cred.private_key_id = None;
+        let error = ServiceAccountTokenSource::new(&cred, "https://spanner.googleapis.com/")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            &error,
+            Error::MissingCredentialField {
+                field: "private_key_id",
+                ..
+            }
+        ));
+        assert_eq!(
+            "private_key_id is required for service_account credentials in testdata/service_account.json",
+            error.to_string()
+        );
+        assert!(OAuth2ServiceAccountTokenSource::new(&cred, "scope", None).is_ok());
+
+        cred.client_email = Some("".to_string());
+        assert!(matches!(
+            OAuth2ServiceAccountTokenSource::new(&cred, "scope", None),
+            Err(Error::MissingCredentialField {
+                field: "client_email",
+                ..
+            })
         ));
+        assert!(matches!(
+            ServiceAccountIdTokenSource::new(&cred, "https://example.run.app"),
+            Err(Error::MissingCredentialField {
+                field: "client_email",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oauth2_form_body() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let bodies = received.clone();
+        let host = mock_server::serve(move |req, body| {
+            assert_eq!("application/x-www-form-urlencoded", req.headers["Content-Type"]);
+            bodies.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+            Response::new(Body::from(r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#))
+        })
+        .await;
 
-        let request = Request::builder()
-            .method(Method::POST)
-            .uri(self.token_url.as_str())
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .body(body)?;
+        let mut cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        cred.token_uri = Some(format!("http://{}/token", host));
+        let scopes = "https://www.googleapis.com/auth/cloud-platform https://www.googleapis.com/auth/spanner.data";
+        let now = chrono::Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let ts = OAuth2ServiceAccountTokenSource::new(&cred, scopes, None)
+            .unwrap()
+            .with_clock(Arc::new(FakeClock::new(now)));
+        assert_eq!("abc", ts.token().await.unwrap().access_token);
+        let ts = ts.with_scope_in_body(true);
+        ts.token().await.unwrap();
 
-        let it: InternalToken = self.client.request(request).await?.deserialize().await?;
+        let received = received.lock().unwrap();
+        let assertion = received[0]
+            .strip_prefix("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion=")
+            .unwrap();
+        // the JWT is made of the URL safe characters, so it is sent as it is.
+        assert!(assertion
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'));
+        assert_eq!(scopes, claims(assertion)["scope"]);
+        assert_eq!(
+            format!(
+                "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}\
+                &scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform%20\
+                https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fspanner.data",
+                assertion
+            ),
+            received[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_id_token() {
+        let host = mock_server::serve(|_, body| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let assertion = body
+                .strip_prefix("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion=")
+                .unwrap();
+            let claims = claims(assertion);
+            assert_eq!("https://example.run.app", claims["target_audience"]);
+            assert_eq!("test-sa@test-project.iam.gserviceaccount.com", claims["iss"]);
+
+            let id_token = format!(
+                "header.{}.signature",
+                base64::encode_config(r#"{"exp":1893456000}"#, base64::URL_SAFE_NO_PAD)
+            );
+            Response::new(Body::from(json::json!({ "id_token": id_token }).to_string()))
+        })
+        .await;
 
-        return Ok(it.to_token(iat));
+        let mut cred = CredentialsFile::new_from_str(include_str!("../../testdata/service_account.json")).unwrap();
+        cred.token_uri = Some(format!("http://{}/token", host));
+        let ts = ServiceAccountIdTokenSource::new(&cred, "https://example.run.app").unwrap();
+        let token = ts.token().await.unwrap();
+        assert!(token.access_token.starts_with("header."));
+        assert_eq!(1893456000, token.expiry.unwrap().timestamp());
     }
 }
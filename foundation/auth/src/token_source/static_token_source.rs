@@ -0,0 +1,85 @@
+use crate::error::Error;
+use crate::token::Token;
+use crate::token_source::TokenSource;
+use async_trait::async_trait;
+
+// StaticTokenSource always returns the same token, for example in the tests.
+// The token is never refreshed, so the requests fail after it expires.
+#[derive(Clone, Debug)]
+pub struct StaticTokenSource(pub Token);
+
+impl StaticTokenSource {
+    // Returns the Bearer token without expiry.
+    pub fn new(access_token: &str) -> StaticTokenSource {
+        StaticTokenSource(Token {
+            access_token: access_token.to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: None,
+            id_token: None,
+        })
+    }
+
+    // The token expires at the expiry, for example to test the refresh of the cache.
+    pub fn with_expiry(mut self, expiry: chrono::DateTime<chrono::Utc>) -> StaticTokenSource {
+        self.0.expiry = Some(expiry);
+        self
+    }
+}
+
+#[async_trait]
+impl TokenSource for StaticTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        Ok(self.0.clone())
+    }
+}
+
+// AnonymousTokenSource returns the empty token, with which the requests are sent without the Authorization header.
+// It is for the public resources and the emulators which don't require the authentication.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AnonymousTokenSource;
+
+#[async_trait]
+impl TokenSource for AnonymousTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        Ok(Token {
+            access_token: String::new(),
+            token_type: "Bearer".to_string(),
+            expiry: None,
+            id_token: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::token_source::static_token_source::{AnonymousTokenSource, StaticTokenSource};
+    use crate::token_source::{TokenSource, TokenSourceExt};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_token() {
+        let token = StaticTokenSource::new("static").token().await.unwrap();
+        assert_eq!("Bearer static", token.header_value());
+        assert!(token.expiry.is_none());
+
+        let token = AnonymousTokenSource.token().await.unwrap();
+        assert!(token.access_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ext() {
+        let credential = StaticTokenSource::new("static").with_quota_project("billing");
+        assert_eq!(Some("billing"), credential.quota_project_id.as_deref());
+        assert_eq!("static", credential.token_source.token().await.unwrap().access_token);
+
+        // the boxed and the shared token sources can be wrapped again and moved to another task.
+        let boxed: Box<dyn TokenSource> = Box::new(StaticTokenSource::new("static"));
+        let shared = boxed.cached().into_shared();
+        let handle = tokio::spawn({
+            let shared = shared.clone();
+            async move { shared.token().await.unwrap() }
+        });
+        assert_eq!("static", handle.await.unwrap().access_token);
+        assert_eq!("static", Arc::new(shared).cached().token().await.unwrap().access_token);
+    }
+}
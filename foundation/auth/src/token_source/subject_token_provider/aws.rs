@@ -0,0 +1,450 @@
+use crate::credentials::{CredentialSource, EXTERNAL_ACCOUNT_KEY};
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::subject_token_provider::SubjectTokenProvider;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hyper::http::{Method, Request};
+use hyper::{Body, Uri};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const AWS_ENVIRONMENT_V1: &str = "aws1";
+const AWS_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const AWS_REQUEST_TYPE: &str = "aws4_request";
+const AWS_SUBJECT_TOKEN_TYPE: &str = "urn:ietf:params:aws:token-type:aws4_request";
+const AWS_STS_SERVICE: &str = "sts";
+const AWS_IMDSV2_TOKEN_HEADER: &str = "X-aws-ec2-metadata-token";
+const AWS_IMDSV2_TTL_HEADER: &str = "X-aws-ec2-metadata-token-ttl-seconds";
+const AWS_IMDSV2_TTL: &str = "300";
+const GOOGLE_TARGET_RESOURCE_HEADER: &str = "x-goog-cloud-target-resource";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AwsSecurityCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AwsRequestHeader {
+    key: String,
+    value: String,
+}
+
+// The serialized form of the signed GetCallerIdentity request that Google STS expects.
+#[derive(Serialize)]
+struct AwsRequest {
+    url: String,
+    method: String,
+    headers: Vec<AwsRequestHeader>,
+}
+
+// AwsSubjectTokenProvider signs the AWS GetCallerIdentity request with the credentials of the AWS workload
+// and uses it as the subject token.
+// https://cloud.google.com/iam/docs/workload-identity-federation-with-other-clouds
+pub struct AwsSubjectTokenProvider {
+    region_url: Option<String>,
+    credentials_url: Option<String>,
+    regional_cred_verification_url: String,
+    imdsv2_session_token_url: Option<String>,
+    target_resource: String,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl AwsSubjectTokenProvider {
+    pub fn new(source: &CredentialSource, target_resource: &str) -> Result<Self, Error> {
+        let environment_id = source.environment_id.as_deref().unwrap_or_default();
+        if environment_id != AWS_ENVIRONMENT_V1 {
+            return Err(Error::UnsupportedAwsEnvironment(environment_id.to_string()));
+        }
+        let regional_cred_verification_url = match &source.regional_cred_verification_url {
+            Some(url) => url.to_string(),
+            None => {
+                return Err(Error::MissingCredentialField {
+                    field: "credential_source.regional_cred_verification_url",
+                    credential_type: EXTERNAL_ACCOUNT_KEY.to_string(),
+                    path: None,
+                })
+            }
+        };
+        Ok(Self {
+            region_url: source.region_url.clone(),
+            credentials_url: source.url.clone(),
+            regional_cred_verification_url,
+            imdsv2_session_token_url: source.imdsv2_session_token_url.clone(),
+            target_resource: target_resource.to_string(),
+            client: default_http_client(),
+        })
+    }
+
+    // The client sends the requests of the region and the credentials to the metadata server of EC2.
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    async fn session_token(&self) -> Result<Option<String>, Error> {
+        let url = match &self.imdsv2_session_token_url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(url.as_str())
+            .header(AWS_IMDSV2_TTL_HEADER, AWS_IMDSV2_TTL)
+            .body(Body::empty())?;
+        Ok(Some(self.send(url, request).await?))
+    }
+
+    async fn metadata(&self, url: &str, session_token: &Option<String>) -> Result<String, Error> {
+        let mut builder = Request::builder().method(Method::GET).uri(url);
+        if let Some(session_token) = session_token {
+            builder = builder.header(AWS_IMDSV2_TOKEN_HEADER, session_token.as_str());
+        }
+        self.send(url, builder.body(Body::empty())?).await
+    }
+
+    async fn send(&self, url: &str, request: Request<Body>) -> Result<String, Error> {
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(Error::SubjectTokenResponseError {
+                url: url.to_string(),
+                status: response.status(),
+            });
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(String::from_utf8_lossy(&body).trim().to_string())
+    }
+
+    async fn region(&self, session_token: &Option<String>) -> Result<String, Error> {
+        let url = self.region_url.as_ref().ok_or(Error::MissingCredentialField {
+            field: "credential_source.region_url",
+            credential_type: EXTERNAL_ACCOUNT_KEY.to_string(),
+            path: None,
+        })?;
+        // the metadata server returns the availability zone such as us-east-2b.
+        let mut zone = self.metadata(url, session_token).await?;
+        zone.pop();
+        Ok(zone)
+    }
+
+    async fn security_credentials(&self, session_token: &Option<String>) -> Result<AwsSecurityCredentials, Error> {
+        let url = self.credentials_url.as_ref().ok_or(Error::MissingCredentialField {
+            field: "credential_source.url",
+            credential_type: EXTERNAL_ACCOUNT_KEY.to_string(),
+            path: None,
+        })?;
+        let role_name = self.metadata(url, session_token).await?;
+        let credentials = self.metadata(&format!("{}/{}", url, role_name), session_token).await?;
+        Ok(json::from_str(&credentials)?)
+    }
+}
+
+#[async_trait]
+impl SubjectTokenProvider for AwsSubjectTokenProvider {
+    fn subject_token_type(&self) -> Option<&str> {
+        Some(AWS_SUBJECT_TOKEN_TYPE)
+    }
+
+    async fn subject_token(&self) -> Result<String, Error> {
+        let region = env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION"));
+        let credentials = env_security_credentials();
+        // the session token is fetched only for the metadata server, so that the environment works outside EC2.
+        let session_token = match (&region, &credentials) {
+            (Some(_), Some(_)) => None,
+            _ => self.session_token().await?,
+        };
+        let region = match region {
+            Some(region) => region,
+            None => self.region(&session_token).await?,
+        };
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => self.security_credentials(&session_token).await?,
+        };
+
+        let url = self.regional_cred_verification_url.replace("{region}", &region);
+        let mut headers = vec![(GOOGLE_TARGET_RESOURCE_HEADER.to_string(), self.target_resource.to_string())];
+        if let Some(token) = &credentials.token {
+            headers.push(("X-Amz-Security-Token".to_string(), token.to_string()));
+        }
+        let headers = sign_request("POST", &url, headers, b"", &region, AWS_STS_SERVICE, &credentials, Utc::now())?;
+
+        let mut headers: Vec<AwsRequestHeader> = headers
+            .into_iter()
+            .map(|(key, value)| AwsRequestHeader { key, value })
+            .collect();
+        headers.sort_by(|a, b| a.key.cmp(&b.key).then(a.value.cmp(&b.value)));
+        let request = json::to_string(&AwsRequest {
+            url,
+            method: "POST".to_string(),
+            headers,
+        })?;
+        Ok(urlencoding::encode(&request).into_owned())
+    }
+}
+
+fn env(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.is_empty())
+}
+
+fn env_security_credentials() -> Option<AwsSecurityCredentials> {
+    Some(AwsSecurityCredentials {
+        access_key_id: env("AWS_ACCESS_KEY_ID")?,
+        secret_access_key: env("AWS_SECRET_ACCESS_KEY")?,
+        token: env("AWS_SESSION_TOKEN"),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+fn canonical_query(query: Option<&str>) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            let key = urlencoding::decode(kv.next().unwrap_or_default()).unwrap_or_default();
+            let value = urlencoding::decode(kv.next().unwrap_or_default()).unwrap_or_default();
+            (urlencoding::encode(&key).into_owned(), urlencoding::encode(&value).into_owned())
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// Signs the request with AWS Signature Version 4 and returns the headers including Authorization.
+// https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+#[allow(clippy::too_many_arguments)]
+fn sign_request(
+    method: &str,
+    url: &str,
+    mut headers: Vec<(String, String)>,
+    payload: &[u8],
+    region: &str,
+    service: &str,
+    credentials: &AwsSecurityCredentials,
+    now: DateTime<Utc>,
+) -> Result<Vec<(String, String)>, Error> {
+    let uri: Uri = url.parse().map_err(hyper::http::Error::from)?;
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let host = uri.authority().map(|a| a.as_str()).unwrap_or_default().to_string();
+    headers.push(("Host".to_string(), host));
+    headers.push(("X-Amz-Date".to_string(), amz_date.to_string()));
+
+    let mut canonical_headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_string()))
+        .collect();
+    canonical_headers.sort();
+    let signed_headers = canonical_headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let path = match uri.path() {
+        "" => "/",
+        path => path,
+    };
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        canonical_query(uri.query()),
+        canonical_headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v))
+            .collect::<String>(),
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, payload).as_ref())
+    );
+
+    let credential_scope = format!("{}/{}/{}/{}", date, region, service, AWS_REQUEST_TYPE);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        AWS_ALGORITHM,
+        amz_date,
+        credential_scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+
+    let key = hmac_sha256(format!("AWS4{}", credentials.secret_access_key).as_bytes(), &date);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    let key = hmac_sha256(&key, AWS_REQUEST_TYPE);
+    let signature = hex(&hmac_sha256(&key, &string_to_sign));
+
+    headers.push((
+        "Authorization".to_string(),
+        format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            AWS_ALGORITHM, credentials.access_key_id, credential_scope, signed_headers, signature
+        ),
+    ));
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::CredentialSource;
+    use crate::mock_server;
+    use crate::token_source::subject_token_provider::aws::{
+        sign_request, AwsSecurityCredentials, AwsSubjectTokenProvider,
+    };
+    use crate::token_source::subject_token_provider::SubjectTokenProvider;
+    use chrono::TimeZone;
+    use hyper::{Body, Response};
+    use serial_test::serial;
+
+    #[test]
+    fn test_sign_request() {
+        // get-vanilla of the AWS Signature Version 4 test suite.
+        let credentials = AwsSecurityCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            token: None,
+        };
+        let now = chrono::Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let headers = sign_request(
+            "GET",
+            "https://example.amazonaws.com",
+            vec![],
+            b"",
+            "us-east-1",
+            "service",
+            &credentials,
+            now,
+        )
+        .unwrap();
+        let authorization = headers.iter().find(|(k, _)| k == "Authorization").unwrap();
+        assert_eq!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            authorization.1
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subject_token_from_metadata() {
+        for key in [
+            "AWS_REGION",
+            "AWS_DEFAULT_REGION",
+            "AWS_ACCESS_KEY_ID",
+            "AWS_SECRET_ACCESS_KEY",
+            "AWS_SESSION_TOKEN",
+        ] {
+            std::env::remove_var(key);
+        }
+        let host = mock_server::serve(|req, _| {
+            if req.uri.path() == "/latest/api/token" {
+                assert_eq!("PUT", req.method.as_str());
+                assert_eq!("300", req.headers.get("X-aws-ec2-metadata-token-ttl-seconds").unwrap());
+                return Response::new(Body::from("session"));
+            }
+            assert_eq!("session", req.headers.get("X-aws-ec2-metadata-token").unwrap());
+            let body = match req.uri.path() {
+                "/latest/meta-data/placement/availability-zone" => "us-east-2b",
+                "/latest/meta-data/iam/security-credentials" => "role",
+                "/latest/meta-data/iam/security-credentials/role" => {
+                    r#"{"Code":"Success","AccessKeyId":"AKID","SecretAccessKey":"SECRET","Token":"SESSION_TOKEN"}"#
+                }
+                path => panic!("unexpected path {}", path),
+            };
+            Response::new(Body::from(body))
+        })
+        .await;
+        let source: CredentialSource = json::from_value(json::json!({
+            "environment_id": "aws1",
+            "region_url": format!("http://{}/latest/meta-data/placement/availability-zone", host),
+            "url": format!("http://{}/latest/meta-data/iam/security-credentials", host),
+            "regional_cred_verification_url": "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+            "imdsv2_session_token_url": format!("http://{}/latest/api/token", host),
+        }))
+        .unwrap();
+        let audience = "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/aws";
+        let provider = AwsSubjectTokenProvider::new(&source, audience).unwrap();
+        let token = provider.subject_token().await.unwrap();
+
+        let request: json::Value = json::from_str(&urlencoding::decode(&token).unwrap()).unwrap();
+        assert_eq!(
+            "https://sts.us-east-2.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+            request["url"]
+        );
+        assert_eq!("POST", request["method"]);
+        let headers = request["headers"].as_array().unwrap();
+        let keys: Vec<&str> = headers.iter().map(|h| h["key"].as_str().unwrap()).collect();
+        assert_eq!(
+            vec![
+                "Authorization",
+                "Host",
+                "X-Amz-Date",
+                "X-Amz-Security-Token",
+                "x-goog-cloud-target-resource"
+            ],
+            keys
+        );
+        let authorization = headers[0]["value"].as_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+        assert!(authorization.contains("/us-east-2/sts/aws4_request, SignedHeaders=host;x-amz-date;x-amz-security-token;x-goog-cloud-target-resource, Signature="));
+        assert_eq!("sts.us-east-2.amazonaws.com", headers[1]["value"]);
+        assert_eq!("SESSION_TOKEN", headers[3]["value"]);
+        assert_eq!(audience, headers[4]["value"]);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subject_token_from_env() {
+        std::env::set_var("AWS_REGION", "ap-northeast-1");
+        std::env::set_var("AWS_ACCESS_KEY_ID", "AKID");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "SECRET");
+        std::env::remove_var("AWS_SESSION_TOKEN");
+        // the metadata server is not called, though the config has its urls as gcloud generates with --enable-imdsv2.
+        let source: CredentialSource = json::from_value(json::json!({
+            "environment_id": "aws1",
+            "region_url": "http://127.0.0.1:9/latest/meta-data/placement/availability-zone",
+            "url": "http://127.0.0.1:9/latest/meta-data/iam/security-credentials",
+            "regional_cred_verification_url": "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+            "imdsv2_session_token_url": "http://127.0.0.1:9/latest/api/token",
+        }))
+        .unwrap();
+        let provider = AwsSubjectTokenProvider::new(&source, "audience").unwrap();
+        let token = provider.subject_token().await.unwrap();
+        for key in ["AWS_REGION", "AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY"] {
+            std::env::remove_var(key);
+        }
+
+        let request: json::Value = json::from_str(&urlencoding::decode(&token).unwrap()).unwrap();
+        assert_eq!(4, request["headers"].as_array().unwrap().len());
+        assert!(request["url"]
+            .as_str()
+            .unwrap()
+            .contains("sts.ap-northeast-1.amazonaws.com"));
+    }
+
+    #[test]
+    fn test_unsupported_environment() {
+        let source: CredentialSource = json::from_value(json::json!({
+            "environment_id": "aws2",
+            "regional_cred_verification_url": "https://sts.{region}.amazonaws.com",
+        }))
+        .unwrap();
+        assert!(AwsSubjectTokenProvider::new(&source, "audience").is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::credentials::ExecutableConfig;
+use crate::error::Error;
+use crate::token_source::subject_token_provider::SubjectTokenProvider;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+pub const ALLOW_EXECUTABLES_ENV: &str = "GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+const SUPPORTED_VERSION: i64 = 1;
+
+const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+const SAML2_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:saml2";
+
+// The output of the executable.
+// https://cloud.google.com/iam/docs/workload-identity-federation-with-other-providers#create_a_credential_configuration
+#[derive(Deserialize)]
+struct ExecutableResponse {
+    version: Option<i64>,
+    success: Option<bool>,
+    token_type: Option<String>,
+    expiration_time: Option<i64>,
+    id_token: Option<String>,
+    saml_response: Option<String>,
+    code: Option<String>,
+    message: Option<String>,
+}
+
+// Runs the local executable which prints the subject token, for the setups where the token can't be
+// read from the file or the url. Running the executables must be allowed by GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES=1.
+// With the output_file, the executable is run only after the token written to the file expires.
+pub struct ExecutableSubjectTokenProvider {
+    command: String,
+    timeout: Duration,
+    output_file: Option<String>,
+    envs: Vec<(&'static str, String)>,
+}
+
+impl ExecutableSubjectTokenProvider {
+    // impersonation_url is the service_account_impersonation_url of the credentials, if any.
+    pub fn new(
+        config: &ExecutableConfig,
+        audience: &str,
+        subject_token_type: &str,
+        impersonation_url: Option<&str>,
+    ) -> Result<Self, Error> {
+        if config.command.trim().is_empty() {
+            return Err(Error::InvalidExecutableConfig("command is empty".to_string()));
+        }
+        let timeout = match config.timeout_millis {
+            None => DEFAULT_TIMEOUT,
+            Some(millis) => Duration::from_millis(millis),
+        };
+        if !(MIN_TIMEOUT..=MAX_TIMEOUT).contains(&timeout) {
+            return Err(Error::InvalidExecutableConfig(format!(
+                "timeout_millis must be between {} and {}",
+                MIN_TIMEOUT.as_millis(),
+                MAX_TIMEOUT.as_millis()
+            )));
+        }
+
+        let mut envs = vec![
+            ("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", audience.to_string()),
+            ("GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE", subject_token_type.to_string()),
+            ("GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE", "0".to_string()),
+        ];
+        if let Some(email) = impersonation_url.and_then(impersonated_email) {
+            envs.push(("GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL", email.to_string()));
+        }
+        if let Some(output_file) = &config.output_file {
+            envs.push(("GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE", output_file.to_string()));
+        }
+        Ok(Self {
+            command: config.command.to_string(),
+            timeout,
+            output_file: config.output_file.clone(),
+            envs,
+        })
+    }
+
+    // Returns the unexpired token written by the previous run, or None to run the executable.
+    async fn cached_token(&self) -> Result<Option<String>, Error> {
+        let output_file = match &self.output_file {
+            Some(output_file) => output_file,
+            None => return Ok(None),
+        };
+        let content = match tokio::fs::read(output_file).await {
+            Ok(content) if !content.is_empty() => content,
+            _ => return Ok(None),
+        };
+        let response = match json::from_slice::<ExecutableResponse>(&content) {
+            Ok(response) => response,
+            Err(_) => return Ok(None),
+        };
+        match response.expiration_time {
+            Some(exp) if exp > chrono::Utc::now().timestamp() => self.parse(response).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // The executable is killed when it times out or the caller gives up, in which case the future is dropped.
+    async fn run(&self) -> Result<Vec<u8>, Error> {
+        let mut args = self.command.split_whitespace();
+        let program = args.next().unwrap_or_default();
+        let child = Command::new(program)
+            .args(args)
+            .envs(self.envs.iter().map(|(k, v)| (*k, v.as_str())))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        // the output is read until the pipe is closed, which the process started by the executable might keep open,
+        // so the timeout covers the reading as well.
+        let output = match tokio::time::timeout(self.timeout, child.wait_with_output()).await {
+            Ok(output) => output?,
+            Err(_) => return Err(Error::ExecutableTimeout(self.command.to_string())),
+        };
+        if !output.status.success() {
+            return Err(Error::ExecutableFailed {
+                command: self.command.to_string(),
+                code: output.status.code(),
+            });
+        }
+        Ok(output.stdout)
+    }
+
+    fn parse(&self, response: ExecutableResponse) -> Result<String, Error> {
+        let invalid = |message: &str| Err(Error::InvalidExecutableResponse(message.to_string()));
+        match response.version {
+            Some(SUPPORTED_VERSION) => {}
+            Some(version) => return invalid(&format!("unsupported version {}", version)),
+            None => return invalid("version is missing"),
+        }
+        match response.success {
+            Some(true) => {}
+            Some(false) => {
+                return Err(Error::ExecutableResponseError {
+                    code: response.code.unwrap_or_default(),
+                    message: response.message.unwrap_or_default(),
+                })
+            }
+            None => return invalid("success is missing"),
+        }
+        match response.expiration_time {
+            Some(exp) if exp <= chrono::Utc::now().timestamp() => return invalid("the token is expired"),
+            None if self.output_file.is_some() => return invalid("expiration_time is required with the output_file"),
+            _ => {}
+        }
+        let token = match response.token_type.as_deref() {
+            Some(ID_TOKEN_TYPE) | Some(JWT_TOKEN_TYPE) => response.id_token,
+            Some(SAML2_TOKEN_TYPE) => response.saml_response,
+            Some(other) => return invalid(&format!("unsupported token_type {}", other)),
+            None => return invalid("token_type is missing"),
+        };
+        match token {
+            Some(token) if !token.is_empty() => Ok(token),
+            _ => invalid("the token is missing"),
+        }
+    }
+}
+
+// Extracts the email from https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/<email>:generateAccessToken.
+fn impersonated_email(url: &str) -> Option<&str> {
+    let (_, rest) = url.rsplit_once("/serviceAccounts/")?;
+    Some(rest.split(':').next().unwrap_or(rest))
+}
+
+#[async_trait]
+impl SubjectTokenProvider for ExecutableSubjectTokenProvider {
+    async fn subject_token(&self) -> Result<String, Error> {
+        if std::env::var(ALLOW_EXECUTABLES_ENV).as_deref() != Ok("1") {
+            return Err(Error::ExecutablesNotAllowed);
+        }
+        if let Some(token) = self.cached_token().await? {
+            return Ok(token);
+        }
+        let output = self.run().await?;
+        let response: ExecutableResponse =
+            json::from_slice(&output).map_err(|e| Error::InvalidExecutableResponse(e.to_string()))?;
+        self.parse(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::ExecutableConfig;
+    use crate::error::Error;
+    use crate::token_source::subject_token_provider::executable::{
+        impersonated_email, ExecutableSubjectTokenProvider, ALLOW_EXECUTABLES_ENV,
+    };
+    use crate::token_source::subject_token_provider::SubjectTokenProvider;
+    use serial_test::serial;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const AUDIENCE: &str = "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/p";
+    const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+
+    // Writes the shell script and returns the directory and the command running it.
+    fn script(name: &str, body: &str) -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("google-cloud-auth-test-executable-{}", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token.sh");
+        std::fs::write(&path, body).unwrap();
+        let command = format!("sh {}", path.to_str().unwrap());
+        (dir, command)
+    }
+
+    fn provider(command: &str, output_file: Option<&PathBuf>) -> ExecutableSubjectTokenProvider {
+        let config = ExecutableConfig {
+            command: command.to_string(),
+            timeout_millis: Some(5000),
+            output_file: output_file.map(|p| p.to_str().unwrap().to_string()),
+        };
+        let impersonation_url = "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken";
+        ExecutableSubjectTokenProvider::new(&config, AUDIENCE, ID_TOKEN_TYPE, Some(impersonation_url)).unwrap()
+    }
+
+    fn expiration() -> i64 {
+        chrono::Utc::now().timestamp() + 3600
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subject_token() {
+        // the token echoes the variables given to the executable.
+        let (dir, command) = script(
+            "success",
+            &format!(
+                r#"echo "{{\"version\":1,\"success\":true,\"token_type\":\"$GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE\",\"expiration_time\":{},\"id_token\":\"$GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE $GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL\"}}""#,
+                expiration()
+            ),
+        );
+        let ts = provider(&command, None);
+
+        std::env::remove_var(ALLOW_EXECUTABLES_ENV);
+        assert!(matches!(ts.subject_token().await, Err(Error::ExecutablesNotAllowed)));
+
+        std::env::set_var(ALLOW_EXECUTABLES_ENV, "1");
+        assert_eq!("0 sa@p.iam.gserviceaccount.com", ts.subject_token().await.unwrap());
+        std::env::remove_var(ALLOW_EXECUTABLES_ENV);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_errors() {
+        std::env::set_var(ALLOW_EXECUTABLES_ENV, "1");
+        let (dir, command) = script(
+            "errors",
+            r#"case "$1" in
+  exit) exit 3 ;;
+  error) echo '{"version":1,"success":false,"code":"401","message":"not logged in"}' ;;
+  malformed) echo 'token' ;;
+  expired) echo '{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:jwt","expiration_time":1,"id_token":"t"}' ;;
+  sleep) echo $$ > "$(dirname "$0")/pid"; sleep 5 ;;
+  background) echo '{}'; sleep 5 & ;;
+esac
+"#,
+        );
+        match provider(&format!("{} exit", command), None).subject_token().await {
+            Err(Error::ExecutableFailed { code, .. }) => assert_eq!(Some(3), code),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match provider(&format!("{} error", command), None).subject_token().await {
+            Err(Error::ExecutableResponseError { code, message }) => {
+                assert_eq!("401", code);
+                assert_eq!("not logged in", message);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(
+            provider(&format!("{} malformed", command), None).subject_token().await,
+            Err(Error::InvalidExecutableResponse(_))
+        ));
+        assert!(matches!(
+            provider(&format!("{} expired", command), None).subject_token().await,
+            Err(Error::InvalidExecutableResponse(_))
+        ));
+
+        let mut ts = provider(&format!("{} sleep", command), None);
+        ts.timeout = Duration::from_millis(100);
+        assert!(matches!(ts.subject_token().await, Err(Error::ExecutableTimeout(_))));
+
+        // the process which keeps the output open after the executable exits doesn't block the caller.
+        let mut ts = provider(&format!("{} background", command), None);
+        ts.timeout = Duration::from_millis(300);
+        let start = std::time::Instant::now();
+        assert!(matches!(ts.subject_token().await, Err(Error::ExecutableTimeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // the executable is killed when the caller gives up.
+        let ts = provider(&format!("{} sleep", command), None);
+        assert!(tokio::time::timeout(Duration::from_millis(300), ts.subject_token())
+            .await
+            .is_err());
+        let pid = std::fs::read_to_string(dir.join("pid")).unwrap();
+        let status = format!("/proc/{}/status", pid.trim());
+        // the killed process is gone or a zombie until it is reaped.
+        let alive = || std::fs::read_to_string(&status).is_ok_and(|s| !s.contains("State:\tZ"));
+        for _ in 0..50 {
+            if !alive() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!alive());
+        std::env::remove_var(ALLOW_EXECUTABLES_ENV);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_output_file() {
+        std::env::set_var(ALLOW_EXECUTABLES_ENV, "1");
+        let (dir, command) = script(
+            "output-file",
+            &format!(
+                r#"echo '{{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:saml2","expiration_time":{},"saml_response":"from-executable"}}'"#,
+                expiration()
+            ),
+        );
+        let output_file = dir.join("output.json");
+
+        // the executable is run without the output file.
+        let ts = provider(&command, Some(&output_file));
+        assert_eq!("from-executable", ts.subject_token().await.unwrap());
+
+        // the unexpired token in the output file is used instead of running the executable.
+        let cached = format!(
+            r#"{{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:jwt","expiration_time":{},"id_token":"from-file"}}"#,
+            expiration()
+        );
+        std::fs::write(&output_file, cached).unwrap();
+        assert_eq!("from-file", ts.subject_token().await.unwrap());
+
+        let expired = r#"{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:jwt","expiration_time":1,"id_token":"from-file"}"#;
+        std::fs::write(&output_file, expired).unwrap();
+        assert_eq!("from-executable", ts.subject_token().await.unwrap());
+        std::env::remove_var(ALLOW_EXECUTABLES_ENV);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config() {
+        let config = |command: &str, timeout_millis: Option<u64>| ExecutableConfig {
+            command: command.to_string(),
+            timeout_millis,
+            output_file: None,
+        };
+        let new =
+            |config: ExecutableConfig| ExecutableSubjectTokenProvider::new(&config, AUDIENCE, ID_TOKEN_TYPE, None);
+        assert!(new(config("token.sh", None)).is_ok());
+        assert!(matches!(new(config(" ", None)), Err(Error::InvalidExecutableConfig(_))));
+        assert!(matches!(
+            new(config("token.sh", Some(1000))),
+            Err(Error::InvalidExecutableConfig(_))
+        ));
+        assert!(matches!(
+            new(config("token.sh", Some(300_000))),
+            Err(Error::InvalidExecutableConfig(_))
+        ));
+
+        assert_eq!(
+            Some("sa@p.iam.gserviceaccount.com"),
+            impersonated_email("https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken")
+        );
+        assert_eq!(None, impersonated_email("https://example.com"));
+    }
+}
@@ -0,0 +1,28 @@
+use crate::credentials::Format;
+use crate::error::Error;
+use crate::token_source::subject_token_provider::{parse_subject_token, SubjectTokenProvider};
+use async_trait::async_trait;
+use tokio::fs;
+
+// Reads the subject token from the file on every refresh, since the file is rotated by the platform.
+pub struct FileSubjectTokenProvider {
+    path: String,
+    format: Option<Format>,
+}
+
+impl FileSubjectTokenProvider {
+    pub fn new(path: &str, format: Option<Format>) -> Self {
+        Self {
+            path: path.to_string(),
+            format,
+        }
+    }
+}
+
+#[async_trait]
+impl SubjectTokenProvider for FileSubjectTokenProvider {
+    async fn subject_token(&self) -> Result<String, Error> {
+        let content = fs::read(&self.path).await?;
+        parse_subject_token(&content, &self.format)
+    }
+}
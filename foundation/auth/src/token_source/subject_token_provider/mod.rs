@@ -0,0 +1,138 @@
+pub mod aws;
+pub mod executable;
+pub mod file;
+pub mod url;
+
+use crate::credentials::{CredentialSource, CredentialSourceKind, CredentialsFile, Format};
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::subject_token_provider::aws::AwsSubjectTokenProvider;
+use crate::token_source::subject_token_provider::executable::ExecutableSubjectTokenProvider;
+use crate::token_source::subject_token_provider::file::FileSubjectTokenProvider;
+use crate::token_source::subject_token_provider::url::UrlSubjectTokenProvider;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+const FORMAT_TEXT: &str = "text";
+const FORMAT_JSON: &str = "json";
+
+// SubjectTokenProvider supplies the subject token exchanged at the STS endpoint by the external account.
+// Besides the built-in providers for the credential sources, the custom provider such as the one minting
+// the SPIFFE tokens in-process can be given by Config::with_subject_token_provider.
+#[async_trait]
+pub trait SubjectTokenProvider: Send + Sync {
+    async fn subject_token(&self) -> Result<String, Error>;
+
+    // Returns the subject_token_type of the token, which is used unless the credentials specify it.
+    fn subject_token_type(&self) -> Option<&str> {
+        None
+    }
+}
+
+// audience is the resource name of the workload identity pool provider.
+pub(crate) fn from_credential_source(
+    source: &CredentialSource,
+    audience: &str,
+    cred: &CredentialsFile,
+) -> Result<Box<dyn SubjectTokenProvider>, Error> {
+    if let Some(provider) = http_provider(source, audience, default_http_client())? {
+        return Ok(provider);
+    }
+    match source.kind() {
+        Some(CredentialSourceKind::FileSourced) => Ok(Box::new(FileSubjectTokenProvider::new(
+            source.file.as_deref().unwrap_or_default(),
+            source.format.clone(),
+        ))),
+        Some(CredentialSourceKind::Executable) => Ok(Box::new(ExecutableSubjectTokenProvider::new(
+            cred.required("credential_source.executable", &source.executable)?,
+            audience,
+            cred.required("subject_token_type", &cred.subject_token_type)?,
+            cred.service_account_impersonation_url.as_deref(),
+        )?)),
+        _ => Err(Error::UnsupportedCredentialSource),
+    }
+}
+
+// Returns the provider of the credential source which sends the HTTP requests with the client,
+// or None if the credential source is the file or the executable.
+pub(crate) fn http_provider(
+    source: &CredentialSource,
+    audience: &str,
+    client: Arc<dyn HttpClient>,
+) -> Result<Option<Box<dyn SubjectTokenProvider>>, Error> {
+    Ok(match source.kind() {
+        Some(CredentialSourceKind::Aws) => Some(Box::new(
+            AwsSubjectTokenProvider::new(source, audience)?.with_http_client(client),
+        )),
+        Some(CredentialSourceKind::UrlSourced) => Some(Box::new(
+            UrlSubjectTokenProvider::new(
+                source.url.as_deref().unwrap_or_default(),
+                source.headers.clone().unwrap_or_default(),
+                source.format.clone(),
+            )
+            .with_http_client(client),
+        )),
+        _ => None,
+    })
+}
+
+// Extracts the subject token from the raw content according to the format of the credential source.
+fn parse_subject_token(content: &[u8], format: &Option<Format>) -> Result<String, Error> {
+    let format = match format {
+        None => return Ok(String::from_utf8_lossy(content).trim().to_string()),
+        Some(format) => format,
+    };
+    match format.tp.as_str() {
+        FORMAT_TEXT => Ok(String::from_utf8_lossy(content).trim().to_string()),
+        FORMAT_JSON => {
+            let field_name = match &format.subject_token_field_name {
+                Some(field_name) => field_name,
+                None => return Err(Error::SubjectTokenFieldNameRequired),
+            };
+            let value: json::Value = json::from_slice(content)?;
+            match value.get(field_name).and_then(|v| v.as_str()) {
+                Some(token) => Ok(token.to_string()),
+                None => Err(Error::SubjectTokenFieldNotFound(field_name.to_string())),
+            }
+        }
+        other => Err(Error::UnsupportedSubjectTokenFormat(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::Format;
+    use crate::error::Error;
+    use crate::token_source::subject_token_provider::parse_subject_token;
+
+    fn format(tp: &str, field_name: Option<&str>) -> Option<Format> {
+        Some(Format {
+            tp: tp.to_string(),
+            subject_token_field_name: field_name.map(|s| s.to_string()),
+        })
+    }
+
+    #[test]
+    fn test_parse_text() {
+        assert_eq!("abc", parse_subject_token(b"abc\n", &None).unwrap());
+        assert_eq!("abc", parse_subject_token(b" abc ", &format("text", None)).unwrap());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let content = br#"{"id_token":"abc","other":1}"#;
+        assert_eq!("abc", parse_subject_token(content, &format("json", Some("id_token"))).unwrap());
+        match parse_subject_token(content, &format("json", Some("access_token"))) {
+            Err(Error::SubjectTokenFieldNotFound(name)) => assert_eq!("access_token", name),
+            _ => panic!("missing field must be reported"),
+        }
+        assert!(matches!(
+            parse_subject_token(content, &format("json", None)),
+            Err(Error::SubjectTokenFieldNameRequired)
+        ));
+        assert!(matches!(
+            parse_subject_token(content, &format("xml", None)),
+            Err(Error::UnsupportedSubjectTokenFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::credentials::Format;
+use crate::error::Error;
+use crate::http_client::{default_http_client, HttpClient};
+use crate::token_source::subject_token_provider::{parse_subject_token, SubjectTokenProvider};
+use async_trait::async_trait;
+use hyper::http::{Method, Request};
+use hyper::Body;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Fetches the subject token from the url on every refresh.
+pub struct UrlSubjectTokenProvider {
+    url: String,
+    headers: HashMap<String, String>,
+    format: Option<Format>,
+    timeout: Duration,
+
+    client: Arc<dyn HttpClient>,
+}
+
+impl UrlSubjectTokenProvider {
+    pub fn new(url: &str, headers: HashMap<String, String>, format: Option<Format>) -> Self {
+        Self {
+            url: url.to_string(),
+            headers,
+            format,
+            timeout: DEFAULT_TIMEOUT,
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = client;
+        self
+    }
+
+    async fn fetch(&self) -> Result<Vec<u8>, Error> {
+        let mut builder = Request::builder().method(Method::GET).uri(self.url.as_str());
+        for (key, value) in &self.headers {
+            builder = builder.header(key.as_str(), value.as_str());
+        }
+        let request = builder.body(Body::empty())?;
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(Error::SubjectTokenResponseError {
+                url: self.url.to_string(),
+                status: response.status(),
+            });
+        }
+        Ok(hyper::body::to_bytes(response.into_body()).await?.to_vec())
+    }
+}
+
+#[async_trait]
+impl SubjectTokenProvider for UrlSubjectTokenProvider {
+    async fn subject_token(&self) -> Result<String, Error> {
+        let content = match tokio::time::timeout(self.timeout, self.fetch()).await {
+            Ok(content) => content?,
+            Err(_) => return Err(Error::SubjectTokenTimeout(self.url.to_string())),
+        };
+        parse_subject_token(&content, &self.format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::Format;
+    use crate::error::Error;
+    use crate::mock_server;
+    use crate::token_source::subject_token_provider::url::UrlSubjectTokenProvider;
+    use crate::token_source::subject_token_provider::SubjectTokenProvider;
+    use hyper::{Body, Response, StatusCode};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn json_format() -> Option<Format> {
+        Some(Format {
+            tp: "json".to_string(),
+            subject_token_field_name: Some("access_token".to_string()),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_subject_token() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let host = mock_server::serve(move |req, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert_eq!("true", req.headers.get("Metadata").unwrap());
+            Response::new(Body::from(r#"{"access_token":"azure-token"}"#))
+        })
+        .await;
+
+        let mut headers = HashMap::new();
+        headers.insert("Metadata".to_string(), "true".to_string());
+        let provider = UrlSubjectTokenProvider::new(&format!("http://{}/token", host), headers, json_format());
+        assert_eq!("azure-token", provider.subject_token().await.unwrap());
+        assert_eq!("azure-token", provider.subject_token().await.unwrap());
+        // the subject token must not be cached.
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let host = mock_server::serve(|req, _| match req.uri.path() {
+            "/missing" => Response::new(Body::from(r#"{"id_token":"x"}"#)),
+            _ => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap(),
+        })
+        .await;
+
+        let provider = UrlSubjectTokenProvider::new(&format!("http://{}/missing", host), HashMap::new(), json_format());
+        assert!(matches!(
+            provider.subject_token().await,
+            Err(Error::SubjectTokenFieldNotFound(_))
+        ));
+
+        let provider = UrlSubjectTokenProvider::new(&format!("http://{}/denied", host), HashMap::new(), json_format());
+        match provider.subject_token().await {
+            Err(Error::SubjectTokenResponseError { status, .. }) => assert_eq!(StatusCode::FORBIDDEN, status),
+            _ => panic!("status must be reported"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // accepts connections but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let mut provider = UrlSubjectTokenProvider::new(&format!("http://{}/token", addr), HashMap::new(), None);
+        provider.timeout = Duration::from_millis(100);
+        assert!(matches!(provider.subject_token().await, Err(Error::SubjectTokenTimeout(_))));
+    }
+}
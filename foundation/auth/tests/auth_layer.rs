@@ -0,0 +1,203 @@
+use async_trait::async_trait;
+use google_cloud_auth::error::Error;
+use google_cloud_auth::middleware::{AuthLayer, BearerToken};
+use google_cloud_auth::testing::CountingTokenSource;
+use google_cloud_auth::token::Token;
+use google_cloud_auth::token_source::{TokenSource, TokenSourceExt};
+use google_cloud_auth::AnonymousTokenSource;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tower::{Layer, Service};
+
+// Returns the token with both the access token and the ID token, like the OAuth 2.0 flow of the user.
+struct IdTokenSource;
+
+#[async_trait]
+impl TokenSource for IdTokenSource {
+    async fn token(&self) -> Result<Token, Error> {
+        Ok(Token {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expiry: Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+            id_token: Some("identity".to_string()),
+        })
+    }
+}
+
+// Rejects the tokens in rejected with 401 and records the headers and the body of every request.
+async fn serve(rejected: &'static [&'static str], received: Arc<Mutex<Vec<(String, String, String)>>>) -> String {
+    let make_service = make_service_fn(move |_| {
+        let received = received.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let received = received.clone();
+                async move {
+                    let header = |name: &str| {
+                        req.headers()
+                            .get(name)
+                            .map(|v| v.to_str().unwrap().to_string())
+                            .unwrap_or_default()
+                    };
+                    let authorization = header("authorization");
+                    let user_project = header("x-goog-user-project");
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let body = String::from_utf8(body.to_vec()).unwrap();
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((authorization.clone(), user_project, body));
+                    let status = match rejected.iter().any(|t| authorization == format!("Bearer {}", t)) {
+                        true => StatusCode::UNAUTHORIZED,
+                        false => StatusCode::OK,
+                    };
+                    Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    format!("http://{}/storage/v1/b", addr)
+}
+
+fn post(url: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .body(Body::from("payload"))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_auth_header() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let url = serve(&[], received.clone()).await;
+    let target = Arc::new(CountingTokenSource::new());
+    let ts = target.clone().cached();
+    let layer = AuthLayer::new(Arc::new(ts)).with_quota_project_id("billing").unwrap();
+    let mut client = layer.layer(Client::new());
+
+    for _ in 0..2 {
+        let response = client.call(post(&url)).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+    let received = received.lock().unwrap();
+    assert_eq!(2, received.len());
+    for (authorization, user_project, body) in received.iter() {
+        assert_eq!("Bearer token-1", authorization);
+        assert_eq!("billing", user_project);
+        assert_eq!("payload", body);
+    }
+    assert_eq!(1, target.calls());
+}
+
+#[tokio::test]
+async fn test_anonymous() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let url = serve(&[], received.clone()).await;
+    let mut client = AuthLayer::new(Arc::new(AnonymousTokenSource)).layer(Client::new());
+
+    let response = client.call(post(&url)).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let received = received.lock().unwrap();
+    assert_eq!("", received[0].0);
+    assert_eq!("payload", received[0].2);
+}
+
+#[tokio::test]
+async fn test_refresh_on_unauthorized() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let url = serve(&["token-1"], received.clone()).await;
+    let target = Arc::new(CountingTokenSource::new());
+    let ts = target.clone().cached();
+    let mut client = AuthLayer::new(Arc::new(ts)).layer(Client::new());
+
+    let response = client.call(post(&url)).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    {
+        let received = received.lock().unwrap();
+        let authorizations: Vec<&str> = received.iter().map(|r| r.0.as_str()).collect();
+        assert_eq!(vec!["Bearer token-1", "Bearer token-2"], authorizations);
+        // the body is sent again with the refreshed token.
+        assert!(received.iter().all(|r| r.2 == "payload" && r.1.is_empty()));
+    }
+
+    // the refreshed token is reused.
+    client.call(post(&url)).await.unwrap();
+    assert_eq!("Bearer token-2", received.lock().unwrap()[2].0);
+    assert_eq!(2, target.calls());
+}
+
+#[tokio::test]
+async fn test_retry_only_once() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let url = serve(&["token-1", "token-2"], received.clone()).await;
+    let ts = CountingTokenSource::new().cached();
+    let mut client = AuthLayer::new(Arc::new(ts)).layer(Client::new());
+
+    let response = client.call(post(&url)).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    assert_eq!(2, received.lock().unwrap().len());
+}
+
+#[tokio::test]
+async fn test_id_token() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let url = serve(&[], received.clone()).await;
+    let layer = AuthLayer::new(Arc::new(IdTokenSource));
+    let mut client = layer.clone().layer(Client::new());
+    client.call(post(&url)).await.unwrap();
+    let mut client = layer.with_bearer_token(BearerToken::IdToken).layer(Client::new());
+    client.call(post(&url)).await.unwrap();
+
+    // the token without the ID token is not sent.
+    let ts = CountingTokenSource::new();
+    let mut client = AuthLayer::new(Arc::new(ts))
+        .with_bearer_token(BearerToken::IdToken)
+        .layer(Client::new());
+    let error = client.call(post(&url)).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<Error>(), Some(Error::IdTokenNotFound)));
+
+    let received = received.lock().unwrap();
+    assert_eq!(2, received.len());
+    assert_eq!("Bearer access", received[0].0);
+    assert_eq!("Bearer identity", received[1].0);
+}
+
+#[derive(Clone)]
+struct Span(&'static str);
+
+#[tokio::test]
+async fn test_extensions() {
+    // the inner layer reads the extension, and rejects the first token.
+    let received = Arc::new(Mutex::new(vec![]));
+    let recorded = received.clone();
+    let inner = hyper::service::service_fn(move |req: Request<Body>| {
+        let span = req.extensions().get::<Span>().map(|span| span.0);
+        let authorization = req.headers()["authorization"].to_str().unwrap().to_string();
+        recorded.lock().unwrap().push((authorization.clone(), span));
+        let status = match authorization.as_str() {
+            "Bearer token-1" => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::OK,
+        };
+        async move { Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap()) }
+    });
+    let mut client = AuthLayer::new(Arc::new(CountingTokenSource::new().cached())).layer(inner);
+
+    let mut request = post("http://localhost/storage/v1/b");
+    request.extensions_mut().insert(Span("upload"));
+    let response = client.call(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    // the first attempt is the original request, and the retry is rebuilt without the extensions.
+    assert_eq!(
+        vec![
+            ("Bearer token-1".to_string(), Some("upload")),
+            ("Bearer token-2".to_string(), None)
+        ],
+        *received.lock().unwrap()
+    );
+}
@@ -1,16 +1,14 @@
 use google_cloud_auth::*;
 
 #[tokio::test]
+#[allow(clippy::bool_assert_comparison)]
 async fn test_create_token_source() -> Result<(), error::Error> {
     let audience = "https://spanner.googleapis.com/";
     let scopes = [
         "https://www.googleapis.com/auth/cloud-platform",
         "https://www.googleapis.com/auth/spanner.data",
     ];
-    let config = Config {
-        audience: Some(audience),
-        scopes: Some(&scopes),
-    };
+    let config = Config::default().with_audience(audience).with_scopes(&scopes);
     let ts = create_token_source(config).await?;
     let token = ts.token().await?;
     assert_eq!("Bearer", token.token_type);
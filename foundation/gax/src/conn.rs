@@ -1,54 +1,16 @@
 use std::sync::atomic::{AtomicI64, Ordering};
 
-use google_cloud_auth::token_source::TokenSource;
-use google_cloud_auth::{create_token_source, Config};
-use http::header::AUTHORIZATION;
-use http::{HeaderValue, Request};
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::Arc;
-use tonic::body::BoxBody;
+pub use google_cloud_auth::grpc::AsyncAuthInterceptor;
+use google_cloud_auth::{create_credential, Config};
 use tonic::transport::{Certificate, Channel as TonicChannel, ClientTlsConfig, Endpoint};
-use tonic::{Code, Status};
-use tower::filter::{AsyncFilter, AsyncFilterLayer, AsyncPredicate};
+use tower::filter::{AsyncFilter, AsyncFilterLayer};
 use tower::util::Either;
-use tower::{BoxError, ServiceBuilder};
+use tower::ServiceBuilder;
 
 const TLS_CERTS: &[u8] = include_bytes!("roots.pem");
 
 pub type Channel = Either<AsyncFilter<TonicChannel, AsyncAuthInterceptor>, TonicChannel>;
 
-#[derive(Clone)]
-pub struct AsyncAuthInterceptor {
-    token_source: Arc<dyn TokenSource>,
-}
-
-impl AsyncAuthInterceptor {
-    fn new(token_source: Arc<dyn TokenSource>) -> Self {
-        Self { token_source }
-    }
-}
-
-impl AsyncPredicate<Request<BoxBody>> for AsyncAuthInterceptor {
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Request, BoxError>> + Send>>;
-    type Request = Request<BoxBody>;
-
-    fn check(&mut self, request: Request<BoxBody>) -> Self::Future {
-        let ts = self.token_source.clone();
-        Box::pin(async move {
-            let token = ts
-                .token()
-                .await
-                .map_err(|e| Status::new(Code::Unauthenticated, format!("token error: {:?}", e)))?;
-            let token_header = HeaderValue::from_str(token.value().as_ref())
-                .map_err(|e| Status::new(Code::Unauthenticated, format!("token error: {:?}", e)))?;
-            let (mut parts, body) = request.into_parts();
-            parts.headers.insert(AUTHORIZATION, token_header);
-            Ok(Request::from_parts(parts, body))
-        })
-    }
-}
-
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -95,19 +57,18 @@ impl ConnectionManager {
             .domain_name(domain_name);
         let mut conns = Vec::with_capacity(pool_size);
 
-        let ts = create_token_source(Config {
-            audience: Some(audience),
-            scopes,
-            delegation_email: None
-        })
-        .await
-        .map(|e| Arc::from(e))?;
+        let mut config = Config::default().with_audience(audience);
+        if let Some(scopes) = scopes {
+            config = config.with_scopes(scopes);
+        }
+        let credential = create_credential(config).await?;
+        let interceptor = AsyncAuthInterceptor::from_credential(&credential)?;
 
         for _i_ in 0..pool_size {
             let endpoint = TonicChannel::from_static(audience).tls_config(tls_config.clone())?;
             let con = Self::connect(endpoint).await?;
             // use GCP token per call
-            let auth_layer = Some(AsyncFilterLayer::new(AsyncAuthInterceptor::new(Arc::clone(&ts))));
+            let auth_layer = Some(interceptor.clone().layer());
             let auth_con = ServiceBuilder::new().option_layer(auth_layer).service(con);
             conns.push(auth_con);
         }
@@ -1,6 +1,6 @@
 use hyper::client::HttpConnector;
 use hyper::http::{Method, Request};
-use hyper::Client;
+use hyper::{Body, Client};
 use std::time::Duration;
 
 use tokio::net::lookup_host;
@@ -11,6 +11,14 @@ pub const METADATA_HOST_ENV: &str = "GCE_METADATA_HOST";
 pub const METADATA_GOOGLE_HOST: &str = "metadata.google.internal:80";
 pub const METADATA_FLAVOR_KEY: &str = "Metadata-Flavor";
 pub const METADATA_GOOGLE: &str = "Google";
+// Overrides the IP address of the metadata server, which is probed by on_gce.
+pub const METADATA_IP_ENV: &str = "GCE_METADATA_IP";
+
+// The serverless environments, on which the metadata server is always available, set these variables.
+const SERVERLESS_ENVS: [&str; 4] = ["K_SERVICE", "GAE_APPLICATION", "GAE_ENV", "GAE_SERVICE"];
+
+// The detection gives up after this, so that it returns quickly outside of Google Cloud.
+const ON_GCE_TIMEOUT: Duration = Duration::from_millis(500);
 
 static ON_GCE: OnceCell<bool> = OnceCell::const_new();
 
@@ -28,47 +36,88 @@ pub enum Error {
     Http(#[from] hyper::http::Error),
 }
 
+// Returns true if the application runs on Google Cloud, where the metadata server is available.
+// The result is cached for the process.
+// 1. GCE_METADATA_HOST or the variables of the serverless environments such as K_SERVICE are set.
+// 2. The metadata server, whose IP address GCE_METADATA_IP overrides, responds with the Metadata-Flavor: Google header.
+// 3. metadata.google.internal is resolved to the IP address of the metadata server, also overridden by GCE_METADATA_IP.
+// 2 and 3 run at once within the timeout, and either of them is enough.
 pub async fn on_gce() -> bool {
-    return match ON_GCE.get_or_try_init(test_on_gce).await {
-        Ok(s) => *s,
-        Err(_err) => false,
-    };
+    *ON_GCE
+        .get_or_init(|| async { detect_on_gce(&metadata_ip(), METADATA_GOOGLE_HOST, ON_GCE_TIMEOUT).await })
+        .await
 }
 
-async fn test_on_gce() -> Result<bool, Error> {
-    // The user explicitly said they're on GCE, so trust them.
-    if std::env::var(METADATA_HOST_ENV).is_ok() {
-        return Ok(true);
-    }
-
-    let url = format!("http://{}", METADATA_IP);
-    let body = hyper::Body::empty();
-    let request = Request::builder().method(Method::GET).uri(&url).body(body)?;
-
-    let client = Client::builder().build(default_http_connector());
-    let response = client.request(request).await;
-
-    if response.is_ok() {
-        let on_gce = match response.unwrap().headers().get(METADATA_FLAVOR_KEY) {
-            None => false,
-            Some(s) => s == METADATA_GOOGLE,
-        };
+// Returns GCE_METADATA_IP if it is set, otherwise the IP address of the metadata server.
+pub fn metadata_ip() -> String {
+    std::env::var(METADATA_IP_ENV).unwrap_or_else(|_| METADATA_IP.to_string())
+}
 
-        if on_gce {
-            return Ok(true);
-        }
+// Same as on_gce without the cache, probing the metadata server at ip_host and resolving dns_host.
+pub async fn detect_on_gce(ip_host: &str, dns_host: &str, timeout: Duration) -> bool {
+    // The user explicitly said they're on GCE, so trust them.
+    if std::env::var(METADATA_HOST_ENV).is_ok() || SERVERLESS_ENVS.iter().any(|env| std::env::var(env).is_ok()) {
+        return true;
     }
+    tokio::time::timeout(timeout, probe(ip_host, dns_host))
+        .await
+        .unwrap_or(false)
+}
 
-    match lookup_host(METADATA_GOOGLE_HOST).await {
-        Ok(s) => {
-            for ip in s {
-                if ip.ip().to_string() == METADATA_IP {
-                    return Ok(true);
+// The metadata server is probed and the name is resolved at once, and the first positive answer is taken,
+// so that the unreachable IP address, which takes the whole timeout, doesn't prevent the resolution.
+async fn probe(ip_host: &str, dns_host: &str) -> bool {
+    let http = probe_metadata_flavor(ip_host);
+    let dns = resolves_to_metadata_ip(dns_host);
+    tokio::pin!(http, dns);
+    let (mut http_done, mut dns_done) = (false, false);
+    while !(http_done && dns_done) {
+        tokio::select! {
+            found = &mut http, if !http_done => {
+                if found {
+                    return true;
+                }
+                http_done = true;
+            }
+            found = &mut dns, if !dns_done => {
+                if found {
+                    return true;
                 }
+                dns_done = true;
             }
         }
-        Err(_e) => return Ok(false),
+    }
+    false
+}
+
+async fn probe_metadata_flavor(ip_host: &str) -> bool {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}", ip_host))
+        .header(METADATA_FLAVOR_KEY, METADATA_GOOGLE)
+        .body(Body::empty());
+    let request = match request {
+        Ok(request) => request,
+        Err(_) => return false,
     };
+    let client = Client::builder().build::<_, Body>(default_http_connector());
+    match client.request(request).await {
+        Ok(response) => {
+            response
+                .headers()
+                .get(METADATA_FLAVOR_KEY)
+                .map(|v| v == METADATA_GOOGLE)
+                == Some(true)
+        }
+        Err(_) => false,
+    }
+}
 
-    Ok(false)
+async fn resolves_to_metadata_ip(dns_host: &str) -> bool {
+    let ip = metadata_ip();
+    // The failure of the resolution means that it is not on GCE.
+    match lookup_host(dns_host).await {
+        Ok(mut addresses) => addresses.any(|address| address.ip().to_string() == ip),
+        Err(_) => false,
+    }
 }
@@ -1,6 +1,31 @@
-use google_cloud_metadata::on_gce;
+use google_cloud_metadata::{detect_on_gce, on_gce};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use serial_test::serial;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// Starts the local server answering with or without the Metadata-Flavor header and returns its host:port.
+async fn serve(metadata_flavor: bool) -> String {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+            let mut builder = Response::builder();
+            if metadata_flavor {
+                builder = builder.header("Metadata-Flavor", "Google");
+            }
+            Ok::<_, Infallible>(builder.body(Body::empty()).unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let host = server.local_addr().to_string();
+    tokio::spawn(server);
+    host
+}
 
 #[tokio::test]
+#[serial]
 async fn test_on_gce() {
     let result = on_gce().await;
     assert_eq!(false, result);
@@ -9,3 +34,63 @@ async fn test_on_gce() {
     assert_eq!(false, result);
     println!("executed second");
 }
+
+#[tokio::test]
+#[serial]
+async fn test_detect_on_gce() {
+    assert!(detect_on_gce(&serve(true).await, "metadata.invalid:80", TIMEOUT).await);
+
+    // the server without the header is not the metadata server.
+    assert!(!detect_on_gce(&serve(false).await, "metadata.invalid:80", TIMEOUT).await);
+
+    // nothing listens on the discard port and the name is not resolved.
+    let start = Instant::now();
+    assert!(!detect_on_gce("127.0.0.1:9", "metadata.invalid:80", TIMEOUT).await);
+    assert!(start.elapsed() < TIMEOUT + Duration::from_millis(100));
+
+    std::env::set_var("K_SERVICE", "my-service");
+    assert!(detect_on_gce("127.0.0.1:9", "metadata.invalid:80", TIMEOUT).await);
+    std::env::remove_var("K_SERVICE");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_detect_on_gce_timeout() {
+    // the server accepting the connection but never answering is given up after the timeout.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+    let start = Instant::now();
+    assert!(!detect_on_gce(&host, "metadata.invalid:80", Duration::from_millis(200)).await);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_detect_on_gce_by_dns() {
+    // the unreachable metadata server doesn't delay the resolution to the IP address of GCE_METADATA_IP.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+    std::env::set_var("GCE_METADATA_IP", "127.0.0.1");
+    let start = Instant::now();
+    let result = detect_on_gce(&host, "localhost:80", TIMEOUT).await;
+    std::env::remove_var("GCE_METADATA_IP");
+    assert!(result);
+    assert!(start.elapsed() < TIMEOUT, "{:?}", start.elapsed());
+}